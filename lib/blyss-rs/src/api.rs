@@ -1,5 +1,5 @@
 use bzip2_rs::DecoderReader;
-use std::{collections::HashMap, io::Read};
+use std::{collections::HashMap, io::Read, sync::Arc};
 
 use crate::error::Error;
 use base64::{engine::general_purpose, Engine as _};
@@ -7,10 +7,12 @@ use reqwest::multipart::{Form, Part};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use spiral_rs::{
-    client::Client,
-    key_value::{extract_result_impl, row_from_key, varint_decode},
+    client::{Client, OwnedClient},
+    error::Error as SpiralError,
+    key_value::{row_from_key, try_extract_result, try_varint_decode},
     params::Params,
-    util::params_from_json_obj,
+    util::try_params_from_json_obj,
+    wire::{decode_frame, encode_frame, FrameKind, WIRE_CONTENT_TYPE},
 };
 
 /// HTTP GET request to the given URL with the given API key.
@@ -36,6 +38,24 @@ pub(crate) async fn http_post_bytes(
     Ok(resp_body.to_vec())
 }
 
+/// HTTP POST request with a binary frame as the body to the given URL with the given API key,
+/// asking for a binary frame back.
+pub(crate) async fn http_post_frame(
+    url: &str,
+    api_key: &str,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let req = reqwest::Client::new()
+        .post(url)
+        .body(data)
+        .header("Content-Type", WIRE_CONTENT_TYPE)
+        .header("Accept", WIRE_CONTENT_TYPE)
+        .header("x-api-key", api_key);
+    let res = req.send().await?.error_for_status()?;
+    let resp_body = res.bytes().await?;
+    Ok(resp_body.to_vec())
+}
+
 /// HTTP POST request with string body to the given URL with the given API key.
 pub(crate) async fn http_post_string(
     url: &str,
@@ -115,16 +135,18 @@ fn deserialize_chunks(data: &[u8]) -> Vec<Vec<u8>> {
 }
 
 /// Split the given data into metadata and the rest of the data.
-fn split_metadata(data: &[u8]) -> (&[u8], &[u8]) {
-    let (value, bytes_used) = varint_decode(data);
+fn split_metadata(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let (value, bytes_used) = try_varint_decode(data)?;
     let metadata_len = value as usize;
     if metadata_len == 0 {
-        return (&[], data);
+        return Ok((&[], data));
     }
-    let metadata = &data[bytes_used..bytes_used + metadata_len];
+    let metadata = data
+        .get(bytes_used..bytes_used.saturating_add(metadata_len))
+        .ok_or(SpiralError::Truncated)?;
     let data = &data[bytes_used + metadata_len..];
 
-    (metadata, data)
+    Ok((metadata, data))
 }
 
 /// Return whether the given data is all zeros.
@@ -148,7 +170,8 @@ struct PrelimSetupBody {
 
 async fn perform_setup(url: &str, api_key: &str, setup_data: Vec<u8>) -> Result<String, Error> {
     if !is_blyss_url(url) {
-        let setup_resp = http_post_bytes(&format!("{}/setup", url), api_key, setup_data).await?;
+        let setup_frame = encode_frame(FrameKind::Setup, &[setup_data]);
+        let setup_resp = http_post_frame(&format!("{}/setup", url), api_key, setup_frame).await?;
         let setup_resp_str = String::from_utf8(setup_resp)?;
         let uuid = serde_json::from_str::<Value>(&setup_resp_str)?
             .get("uuid")
@@ -194,39 +217,51 @@ async fn private_read<'a>(
     api_key: &str,
     keys: &[String],
 ) -> Result<Vec<Vec<u8>>, Error> {
-    let queries: Vec<_> = keys
+    let idx_targets: Vec<_> = keys.iter().map(|key| row_from_key(params, key)).collect();
+    let queries: Vec<_> = client
+        .generate_queries(&idx_targets)
         .iter()
-        .map(|key| {
-            let idx_target = row_from_key(&params, key);
-            let query = client.generate_query(idx_target);
-            let query_data = query.serialize();
+        .map(|query| {
             let uuid_and_query_data: Vec<_> = (uuid.as_bytes().to_vec().into_iter())
-                .chain(query_data)
+                .chain(query.serialize())
                 .collect();
             uuid_and_query_data
         })
         .collect();
-    let full_query_data = serialize_chunks(&queries);
-
-    let resp_data_b64 =
-        http_post_bytes(&format!("{}/private-read", url), api_key, full_query_data).await?;
-    let resp_data = general_purpose::STANDARD.decode(resp_data_b64)?;
-    let resp_chunks = deserialize_chunks(&resp_data);
+    let resp_chunks = if is_blyss_url(url) {
+        let full_query_data = serialize_chunks(&queries);
+        let resp_data_b64 =
+            http_post_bytes(&format!("{}/private-read", url), api_key, full_query_data).await?;
+        let resp_data = general_purpose::STANDARD.decode(resp_data_b64)?;
+        deserialize_chunks(&resp_data)
+    } else {
+        let query_frame = encode_frame(FrameKind::Queries, &queries);
+        let resp_frame =
+            http_post_frame(&format!("{}/private-read", url), api_key, query_frame).await?;
+        decode_frame(&resp_frame, FrameKind::Responses)?
+            .into_iter()
+            .map(|chunk| chunk.to_vec())
+            .collect()
+    };
+    if resp_chunks.len() != keys.len() {
+        return Err(Error::Unknown);
+    }
 
+    let resp_refs: Vec<&[u8]> = resp_chunks.iter().map(|chunk| chunk.as_slice()).collect();
     let mut results = Vec::new();
-    for (i, chunk) in resp_chunks.iter().enumerate() {
-        let decrypted = client.decode_response(&chunk);
-        if is_all_zeros(&decrypted) {
+    for (i, decrypted) in client.try_decode_responses(&resp_refs)?.iter().enumerate() {
+        if is_all_zeros(decrypted) {
             results.push(vec![]);
             continue;
         }
-        let decompressed = decompress(&decrypted)?;
-        let result = extract_result_impl(&keys[i], &decompressed);
-        if let Ok(result) = result {
-            let (_metadata, data) = split_metadata(&result);
-            results.push(data.to_vec());
-        } else {
-            results.push(vec![]);
+        let decompressed = decompress(decrypted)?;
+        match try_extract_result(&keys[i], &decompressed) {
+            Ok(result) => {
+                let (_metadata, data) = split_metadata(&result)?;
+                results.push(data.to_vec());
+            }
+            Err(SpiralError::KeyNotFound) => results.push(vec![]),
+            Err(e) => return Err(e.into()),
         }
     }
 
@@ -239,8 +274,7 @@ pub struct ApiClient {
     pub url: String,
 
     api_key: String,
-    params: &'static Params,
-    client: Client<'static>,
+    client: OwnedClient,
     uuid: Option<String>,
 }
 
@@ -249,23 +283,51 @@ impl ApiClient {
     ///
     /// The URL should be the URL of the bucket, e.g. `https://beta.api.blyss.dev/global.abc123`.
     pub async fn new(url: &str, api_key: &str) -> Result<Self, Error> {
-        let metadata = get_meta(url, api_key).await?;
-        let params_value = serde_json::from_str::<Value>(&metadata)?
-            .get("pir_scheme")
-            .ok_or(Error::Unknown)?
-            .clone();
-        let params = params_from_json_obj(&params_value);
-        let boxed_params = Box::leak(Box::new(params)); // TODO: avoid this
+        let params = Self::fetch_params(url, api_key).await?;
 
         Ok(Self {
             url: url.to_string(),
             api_key: api_key.to_string(),
-            params: boxed_params,
-            client: Client::init(boxed_params),
+            client: OwnedClient::init(params),
             uuid: None,
         })
     }
 
+    /// Resume a session saved by `export_session()`, without repeating setup.
+    ///
+    /// # Arguments
+    /// - `state` - The saved session.
+    /// - `passphrase` - The passphrase the session was saved with, if any.
+    ///
+    /// # Errors
+    /// - `Error::SpiralError` - If the state is malformed, the passphrase is
+    ///   wrong, or the bucket's parameters have changed since it was saved.
+    pub async fn resume(
+        url: &str,
+        api_key: &str,
+        state: &[u8],
+        passphrase: Option<&str>,
+    ) -> Result<Self, Error> {
+        let params = Self::fetch_params(url, api_key).await?;
+        let (client, uuid) = OwnedClient::import_state(params, state, passphrase)?;
+
+        Ok(Self {
+            url: url.to_string(),
+            api_key: api_key.to_string(),
+            client,
+            uuid,
+        })
+    }
+
+    async fn fetch_params(url: &str, api_key: &str) -> Result<Arc<Params>, Error> {
+        let metadata = get_meta(url, api_key).await?;
+        let params_value = serde_json::from_str::<Value>(&metadata)?
+            .get("pir_scheme")
+            .ok_or(Error::Unknown)?
+            .clone();
+        Ok(Arc::new(try_params_from_json_obj(&params_value)?))
+    }
+
     /// Returns whether the client has been set up for private reads.
     fn has_set_up(&self) -> bool {
         self.uuid.is_some()
@@ -273,8 +335,7 @@ impl ApiClient {
 
     /// Prepare the client for private reads. This must be called before calling private_read().
     pub async fn setup(&mut self) -> Result<(), Error> {
-        let setup = self.client.generate_keys();
-        let setup_data = setup.serialize();
+        let setup_data = self.client.generate_keys();
 
         let uuid = perform_setup(&self.url, &self.api_key, setup_data).await?;
 
@@ -283,6 +344,19 @@ impl ApiClient {
         Ok(())
     }
 
+    /// Save the client's secret keys and session, so that `resume()` can pick
+    /// up private reads later. With a passphrase, the saved state is
+    /// encrypted; without one, it must be stored as carefully as a key.
+    ///
+    /// # Errors
+    /// - `Error::NeedSetup` - If setup() has not been called.
+    pub fn export_session(&self, passphrase: Option<&str>) -> Result<Vec<u8>, Error> {
+        match &self.uuid {
+            Some(uuid) => Ok(self.client.export_state(Some(uuid), passphrase)),
+            None => Err(Error::NeedSetup),
+        }
+    }
+
     /// Privately read the given keys from the bucket.
     /// Must call setup() before calling this.
     ///
@@ -300,9 +374,10 @@ impl ApiClient {
             return Err(Error::NeedSetup);
         }
 
+        let client = self.client.client();
         private_read(
-            &self.client,
-            &self.params,
+            &client,
+            self.client.params(),
             self.uuid.as_ref().unwrap(),
             &self.url,
             &self.api_key,
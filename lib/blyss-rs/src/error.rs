@@ -21,6 +21,12 @@ pub enum Error {
     /// An error making HTTP requests.
     #[error("HTTP error: {0}")]
     HTTPError(#[from] reqwest::Error),
+    /// An error decoding a binary frame from the server.
+    #[error("Wire error: {0}")]
+    WireError(#[from] spiral_rs::wire::WireError),
+    /// An error parsing a response from the server.
+    #[error("Spiral error: {0}")]
+    SpiralError(#[from] spiral_rs::error::Error),
     /// A wrapped io::Error.
     #[error("IO error: {0}")]
     IOError(#[from] std::io::Error),
@@ -0,0 +1,94 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use spiral_rs::util::try_params_from_json_obj;
+use spiral_server::bucket::*;
+use spiral_server::db::import::read_kv_pairs;
+use uuid::Uuid;
+
+const BUCKETS_DIRNAME: &str = "buckets";
+const PREPROCESSED_DB_FILENAME: &str = "db.preprocessed";
+const DEFAULT_BUCKET_NAME: &str = "default";
+
+fn usage() -> ! {
+    eprintln!("usage: spiral-preprocess <params.json> <input> <out_dir> [bucket_name]");
+    eprintln!();
+    eprintln!("<input> is a .jsonl file of {{\"key\": ..., \"value\": <base64>}} lines, a");
+    eprintln!(".csv file of key,value lines, or a directory of files named by key.");
+    std::process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 && args.len() != 5 {
+        usage();
+    }
+    let params_json = fs::read_to_string(&args[1])
+        .unwrap_or_else(|e| fail(format!("could not read {}: {}", args[1], e)));
+    let input = Path::new(&args[2]);
+    let out_dir = Path::new(&args[3]);
+    let name = args
+        .get(4)
+        .map_or(DEFAULT_BUCKET_NAME, |name| name.as_str());
+
+    let scheme: serde_json::Value = serde_json::from_str(&params_json)
+        .unwrap_or_else(|e| fail(format!("bad params file: {}", e)));
+    let params =
+        Arc::new(try_params_from_json_obj(&scheme).unwrap_or_else(|e| fail(e.to_string())));
+
+    let now = Instant::now();
+    let kv_pairs =
+        read_kv_pairs(input).unwrap_or_else(|e| fail(format!("{}: {}", input.display(), e)));
+    println!(
+        "Read {} KV pairs ({} ms)",
+        kv_pairs.len(),
+        now.elapsed().as_millis()
+    );
+
+    let bucket_dir = out_dir
+        .join(BUCKETS_DIRNAME)
+        .join(Uuid::new_v4().to_string());
+    let metadata = BucketMetadata {
+        name: name.to_owned(),
+        parameters: BucketParameters {
+            max_item_size: params.db_item_size,
+            ..Default::default()
+        },
+        open_access: true,
+    };
+    let now = Instant::now();
+    let bucket = Bucket::build(
+        params,
+        &params_json,
+        metadata,
+        kv_pairs,
+        bucket_dir.clone(),
+        &BucketConfig::default(),
+    )
+    .unwrap_or_else(|e| fail(format!("could not build bucket: {}", e)));
+    println!(
+        "Wrote bucket {} to {} ({} ms)",
+        name,
+        bucket_dir.display(),
+        now.elapsed().as_millis()
+    );
+
+    let now = Instant::now();
+    let db_path = out_dir.join(PREPROCESSED_DB_FILENAME);
+    bucket
+        .write_preprocessed_db(&db_path)
+        .unwrap_or_else(|e| fail(format!("could not write {}: {}", db_path.display(), e)));
+    println!(
+        "Wrote preprocessed database to {} ({} ms)",
+        db_path.display(),
+        now.elapsed().as_millis()
+    );
+}
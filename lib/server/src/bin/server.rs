@@ -1,39 +1,361 @@
 use actix_web::HttpServer;
-use serde::Serialize;
-use spiral_rs::client::*;
+use serde::{Deserialize, Serialize};
 use spiral_rs::params::*;
 use spiral_rs::util::*;
-use spiral_server::db::loading::*;
-use spiral_server::db::sparse_db::SparseDb;
+use spiral_rs::wire::*;
+use spiral_server::bucket::*;
+use spiral_server::db::dense_db::{DbAdvice, DenseDbStorage};
 use spiral_server::db::write::unwrap_kv_pairs;
-use spiral_server::db::write::update_database;
-use spiral_server::error::Error;
-use spiral_server::server::*;
+use spiral_server::error::{Error, ErrorResponse};
+use spiral_server::session::SessionConfig;
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::sync::RwLock;
-use std::time::Instant;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{get, post, routes, web, App, HttpRequest, HttpResponse};
 use uuid::Uuid;
 
-use actix_web::error::PayloadError;
-use actix_web::{get, post, web, App};
-
 struct ServerState {
-    params: &'static Params,
-    db: RwLock<SparseDb>,
-    rows: RwLock<Vec<Vec<u8>>>,
-    pub_params: RwLock<HashMap<String, PublicParameters<'static>>>,
+    /// The PIR scheme of buckets created without one.
+    params: Arc<Params>,
     params_json: String,
-    version: RwLock<u64>,
+    /// Every scheme in use, keyed by its JSON, so buckets share them.
+    schemes: Mutex<HashMap<String, Arc<Params>>>,
+    /// The most schemes `/create` may bring `schemes` up to.
+    max_schemes: usize,
+    data_dir: Option<PathBuf>,
+    config: BucketConfig,
+    /// The bucket the unscoped routes are for.
+    default_bucket: String,
+    /// The hosted buckets, keyed by name.
+    buckets: RwLock<HashMap<String, Arc<Bucket>>>,
+    /// Held by `/create`, `/modify` and `/destroy` across their filesystem
+    /// work, so that the names they check are still free when `buckets` is
+    /// updated, without blocking lookups meanwhile.
+    bucket_changes: Mutex<()>,
+}
+
+const DATA_DIR_ENV_VAR: &str = "SPIRAL_DATA_DIR";
+const GROUP_COMMIT_MS_ENV_VAR: &str = "SPIRAL_WAL_GROUP_COMMIT_MS";
+const CHECKPOINT_INTERVAL_ENV_VAR: &str = "SPIRAL_CHECKPOINT_INTERVAL";
+const DEFAULT_CHECKPOINT_INTERVAL: usize = 1024;
+const SESSION_TTL_SECS_ENV_VAR: &str = "SPIRAL_SESSION_TTL_SECS";
+const MAX_SESSIONS_ENV_VAR: &str = "SPIRAL_MAX_SESSIONS";
+const SESSION_MEMORY_MB_ENV_VAR: &str = "SPIRAL_SESSION_MEMORY_MB";
+const DEFAULT_SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+const BUCKET_NAME_ENV_VAR: &str = "SPIRAL_BUCKET_NAME";
+const DEFAULT_BUCKET_NAME: &str = "default";
+const BUCKETS_DIRNAME: &str = "buckets";
+const STATIC_DB_ENV_VAR: &str = "SPIRAL_STATIC_DB";
+const STATIC_DB_MMAP_ENV_VAR: &str = "SPIRAL_STATIC_DB_MMAP";
+const STATIC_DB_MADVISE_ENV_VAR: &str = "SPIRAL_STATIC_DB_MADVISE";
+const SHARD_INSTANCES_ENV_VAR: &str = "SPIRAL_SHARD_INSTANCES";
+const SHARD_WORKERS_ENV_VAR: &str = "SPIRAL_SHARD_WORKERS";
+const READ_BATCH_MS_ENV_VAR: &str = "SPIRAL_READ_BATCH_MS";
+const MAX_SCHEMES_ENV_VAR: &str = "SPIRAL_MAX_SCHEMES";
+const DEFAULT_MAX_SCHEMES: usize = 16;
+const MAX_PAYLOAD_MB_ENV_VAR: &str = "SPIRAL_MAX_PAYLOAD_MB";
+const DEFAULT_MAX_PAYLOAD_MB: usize = 64;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .map(|val| {
+            val.parse()
+                .unwrap_or_else(|_| panic!("invalid value for {}", name))
+        })
+        .unwrap_or(default)
+}
+
+/// Reads session limits from the environment. A limit of 0 means unlimited.
+fn session_config_from_env() -> SessionConfig {
+    let ttl_secs: u64 = env_or(SESSION_TTL_SECS_ENV_VAR, DEFAULT_SESSION_TTL_SECS);
+    let max_sessions: usize = env_or(MAX_SESSIONS_ENV_VAR, 0);
+    let memory_mb: usize = env_or(SESSION_MEMORY_MB_ENV_VAR, 0);
+    SessionConfig {
+        ttl: (ttl_secs > 0).then(|| Duration::from_secs(ttl_secs)),
+        max_sessions: (max_sessions > 0).then_some(max_sessions),
+        max_bytes: (memory_mb > 0).then_some(memory_mb << 20),
+    }
+}
+
+/// Reads how to hold a static database from the environment.
+fn static_db_storage_from_env() -> DenseDbStorage {
+    if env_or(STATIC_DB_MMAP_ENV_VAR, false) {
+        DenseDbStorage::Mapped(env_or(STATIC_DB_MADVISE_ENV_VAR, DbAdvice::Normal))
+    } else {
+        DenseDbStorage::Memory
+    }
+}
+
+/// Reads the instances a shard worker holds, as `start..end`, from the
+/// environment.
+fn shard_instances_from_env() -> Option<Range<usize>> {
+    let val = env::var(SHARD_INSTANCES_ENV_VAR).ok()?;
+    let (start, end) = val
+        .split_once("..")
+        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+        .unwrap_or_else(|| panic!("invalid value for {}", SHARD_INSTANCES_ENV_VAR));
+    Some(start..end)
+}
+
+/// Sorts JSON object keys, so that equal schemes have equal strings.
+fn normalize_json(json: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(json)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| json.to_owned())
+}
+
+impl ServerState {
+    /// The name of the bucket a request is for: the one in its path, or,
+    /// for the unscoped routes, the default bucket.
+    fn bucket_name<'a>(&'a self, req: &'a HttpRequest) -> &'a str {
+        req.match_info()
+            .get("bucket")
+            .unwrap_or(&self.default_bucket)
+    }
+
+    fn bucket_for(&self, req: &HttpRequest) -> Result<Arc<Bucket>, Error> {
+        let buckets = self.buckets.read().unwrap();
+        buckets
+            .get(self.bucket_name(req))
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    /// The `Params` for a PIR scheme, built the first time the scheme is
+    /// used. An empty scheme is the server's default.
+    fn params_for(&self, params_json: &str, from_client: bool) -> Result<Arc<Params>, Error> {
+        if params_json.is_empty() {
+            return Ok(self.params.clone());
+        }
+        let mut schemes = self.schemes.lock().unwrap();
+        let key = normalize_json(params_json);
+        if let Some(params) = schemes.get(&key) {
+            return Ok(params.clone());
+        }
+        // schemes no bucket uses any more make room; clients may only add so
+        // many
+        schemes.retain(|_, params| Arc::strong_count(params) > 1);
+        if from_client && schemes.len() >= self.max_schemes {
+            return Err(Error::InvalidParams(format!(
+                "the server already holds {} schemes, the most it allows",
+                schemes.len()
+            )));
+        }
+        let scheme = serde_json::from_str(&key).map_err(|e| Error::InvalidParams(e.to_string()))?;
+        let params = Arc::new(params_from_scheme(&scheme)?);
+        schemes.insert(key, params.clone());
+        Ok(params)
+    }
+
+    /// A new directory for a bucket's files, if the server is durable.
+    fn new_bucket_dir(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|data_dir| {
+            data_dir
+                .join(BUCKETS_DIRNAME)
+                .join(Uuid::new_v4().to_string())
+        })
+    }
+
+    /// Creates a bucket, failing if one of the same name exists.
+    fn create_bucket(
+        &self,
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+    ) -> Result<serde_json::Value, Error> {
+        let _changes = self.bucket_changes.lock().unwrap();
+        let name = metadata.name.clone();
+        if self.buckets.read().unwrap().contains_key(&name) {
+            return Err(Error::AlreadyExists(name));
+        }
+        let bucket = Arc::new(Bucket::create(
+            params,
+            params_json,
+            metadata,
+            self.new_bucket_dir(),
+            &self.config,
+        )?);
+        println!("Created bucket {}", name);
+        let meta_json = bucket.meta_json();
+        self.buckets.write().unwrap().insert(name, bucket);
+        Ok(meta_json)
+    }
+
+    /// Modifies a bucket. The default bucket keeps its name, since the
+    /// unscoped routes, and the server after a restart, look it up by name.
+    fn modify_bucket(
+        &self,
+        name: &str,
+        modification: BucketModification,
+    ) -> Result<serde_json::Value, Error> {
+        let _changes = self.bucket_changes.lock().unwrap();
+        let bucket = self
+            .buckets
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(Error::NotFound)?;
+        if let Some(new_name) = &modification.name {
+            if new_name != name {
+                if name == self.default_bucket {
+                    return Err(Error::InvalidRequest(
+                        "the default bucket cannot be renamed".to_owned(),
+                    ));
+                }
+                if self.buckets.read().unwrap().contains_key(new_name) {
+                    return Err(Error::AlreadyExists(new_name.clone()));
+                }
+            }
+        }
+        let metadata = bucket.modify(modification)?;
+        if metadata.name != name {
+            let mut buckets = self.buckets.write().unwrap();
+            buckets.remove(name);
+            buckets.insert(metadata.name, bucket.clone());
+        }
+        Ok(bucket.meta_json())
+    }
+
+    /// Destroys a bucket, which stops being found before its files are
+    /// removed.
+    fn destroy_bucket(&self, name: &str) -> Result<(), Error> {
+        let _changes = self.bucket_changes.lock().unwrap();
+        let bucket = self
+            .buckets
+            .write()
+            .unwrap()
+            .remove(name)
+            .ok_or(Error::NotFound)?;
+        if let Err(e) = bucket.destroy() {
+            self.buckets
+                .write()
+                .unwrap()
+                .insert(name.to_owned(), bucket);
+            return Err(e);
+        }
+        println!("Destroyed bucket {}", name);
+        Ok(())
+    }
+
+    /// Opens every bucket stored in the data directory, first moving the
+    /// files of a single-bucket server into a bucket directory of their own.
+    fn open_buckets(&self, data_dir: &Path, metadata: BucketMetadata) -> Result<(), Error> {
+        let buckets_dir = data_dir.join(BUCKETS_DIRNAME);
+        fs::create_dir_all(&buckets_dir)?;
+        let legacy_dir = buckets_dir.join(Uuid::new_v4().to_string());
+        let mut buckets = self.buckets.write().unwrap();
+        if Bucket::move_files(data_dir, &legacy_dir)? {
+            println!("Moved existing bucket into {}", legacy_dir.display());
+            let bucket = Bucket::open_or_create(
+                self.params.clone(),
+                &self.params_json,
+                metadata,
+                legacy_dir.clone(),
+                &self.config,
+            )?;
+            buckets.insert(bucket.name(), Arc::new(bucket));
+        }
+
+        for entry in fs::read_dir(&buckets_dir)? {
+            let entry = entry?;
+            let dir = entry.path();
+            if dir == legacy_dir {
+                continue;
+            }
+            if !entry.file_type()?.is_dir() {
+                println!("Skipping {}, which is not a bucket", dir.display());
+                continue;
+            }
+            if Bucket::is_partial(&dir) {
+                // a bucket that was being created or destroyed
+                fs::remove_dir_all(&dir)?;
+                continue;
+            }
+            if !Bucket::exists(&dir) {
+                println!("Skipping {}, which has no bucket metadata", dir.display());
+                continue;
+            }
+            let params_json = Bucket::stored_params_json(&dir)?;
+            let params = self.params_for(&params_json, false)?;
+            let bucket = Bucket::open(params, &params_json, dir, &self.config)?;
+            buckets.insert(bucket.name(), Arc::new(bucket));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a request body is in the binary wire framing, rather than JSON and
+/// base64.
+fn is_wire_request(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(WIRE_CONTENT_TYPE))
+}
+
+/// Whether a request accepts a response in the binary wire framing.
+fn accepts_wire(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(WIRE_CONTENT_TYPE))
+}
+
+/// Waits until the logged mutation `seq` is durable.
+async fn wait_durable(bucket: Arc<Bucket>, seq: Option<u64>) -> Result<(), Error> {
+    if seq.is_some() {
+        web::block(move || bucket.sync(seq))
+            .await
+            .map_err(|_| Error::Unknown)??;
+    }
+    Ok(())
+}
+
+/// Gives error responses that don't come from an `Error`, such as those from
+/// failed extractors or unknown routes, the same JSON body.
+fn json_error_body<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes() == b"application/json");
+    if is_json {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+    let status = res.status();
+    let error = match res.response().error() {
+        Some(e) if !status.is_server_error() => e.to_string(),
+        _ => status.canonical_reason().unwrap_or("error").to_lowercase(),
+    };
+    let (req, _) = res.into_parts();
+    let res = HttpResponse::build(status).json(ErrorResponse { error });
+    Ok(ErrorHandlerResponse::Response(
+        ServiceResponse::new(req, res).map_into_right_body(),
+    ))
 }
 
+#[routes]
 #[post("/update-row")]
-async fn update_row(body: web::Bytes, data: web::Data<ServerState>) -> Result<String, Error> {
+#[post("/{bucket}/update-row")]
+async fn update_row(
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<ServerState>,
+) -> Result<String, Error> {
     let now = Instant::now();
 
-    let mut db_mut = data.db.write().unwrap();
-    let largest_update = update_many_items(&data.params, &body, &mut db_mut)?;
+    let bucket = data.bucket_for(&req)?;
+    let (largest_update, seq) = bucket.update_rows(&body)?;
+    wait_durable(bucket, seq).await?;
 
     Ok(format!(
         "{{\"status\":\"done updating\", \"loading_time_us\":{}, \"largest_update\":{}}}",
@@ -42,20 +364,20 @@ async fn update_row(body: web::Bytes, data: web::Data<ServerState>) -> Result<St
     ))
 }
 
+#[routes]
 #[post("/write")]
-async fn write(body: web::Bytes, data: web::Data<ServerState>) -> Result<String, Error> {
+#[post("/{bucket}/write")]
+async fn write(
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<ServerState>,
+) -> Result<String, Error> {
     let now = Instant::now();
-    let mut rows_mut = data.rows.write().unwrap();
-    let mut db_mut = data.db.write().unwrap();
 
-    let kv_pairs = unwrap_kv_pairs(&body);
-    let kv_pairs_slices: Vec<(&str, &[u8])> = kv_pairs
-        .iter()
-        .map(|(key, value)| (key.as_str(), value.as_slice()))
-        .collect();
-    update_database(data.params, &kv_pairs_slices, &mut rows_mut, &mut db_mut);
-    let mut version_mut = data.version.write().unwrap();
-    *version_mut += 1;
+    let bucket = data.bucket_for(&req)?;
+    let kv_pairs = unwrap_kv_pairs(&body)?;
+    let seq = bucket.write(kv_pairs)?;
+    wait_durable(bucket, seq).await?;
 
     Ok(format!(
         "{{\"status\":\"done updating\", \"loading_time_us\":{}}}",
@@ -68,117 +390,221 @@ pub struct UuidResponse {
     pub uuid: String,
 }
 
+#[routes]
 #[post("/setup")]
+#[post("/{bucket}/setup")]
 async fn setup(
-    body: String,
+    req: HttpRequest,
+    body: web::Bytes,
     data: web::Data<ServerState>,
 ) -> Result<String, actix_web::error::Error> {
-    // parse body as json str
-    let body_str = serde_json::from_str::<String>(&body).unwrap();
-    // decode body from base64
-    let client_pub_params = base64::decode(&body_str).unwrap();
-    let mut pub_params_map_mut = data.pub_params.write().unwrap();
-    assert_eq!(client_pub_params.len(), data.params.setup_bytes());
-    let pub_params = PublicParameters::deserialize(&data.params, &client_pub_params);
-
-    let uuid = Uuid::new_v4();
-    pub_params_map_mut.insert(uuid.to_string(), pub_params);
+    let bucket = data.bucket_for(&req)?;
+    let client_pub_params = if is_wire_request(&req) {
+        let chunks = decode_frame(&body, FrameKind::Setup).map_err(Error::from)?;
+        let [client_pub_params] = chunks[..] else {
+            return Err(Error::InvalidRequest("expected one chunk".to_owned()).into());
+        };
+        client_pub_params.to_vec()
+    } else {
+        // parse body as json str
+        let body_str = serde_json::from_slice::<String>(&body)
+            .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        // decode body from base64
+        base64::decode(&body_str)
+            .map_err(|_| Error::InvalidRequest("bad base64 public parameters".to_owned()))?
+    };
+    // a sharded bucket passes the parameters on to its workers
+    let uuid = web::block(move || bucket.setup(&client_pub_params))
+        .await
+        .map_err(|_| Error::Unknown)??;
 
     // return uuid as JSON string
-    let uuid_json = serde_json::to_string(&UuidResponse {
-        uuid: uuid.to_string(),
-    })
-    .unwrap();
+    let uuid_json = serde_json::to_string(&UuidResponse { uuid }).unwrap();
 
     Ok(uuid_json)
 }
 
-const UUID_V4_STR_BYTES: usize = 36;
+/// Reports whether the session `uuid` is still live, without extending it.
+/// Clients that get a 404 should call `/setup` again.
+#[routes]
+#[get("/check/{uuid}")]
+#[get("/{uuid}/check")]
+async fn check(
+    uuid: web::Path<String>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    let uuid = uuid.into_inner();
+    for bucket in data.buckets.read().unwrap().values() {
+        if bucket.has_session(&uuid)? {
+            return Ok(HttpResponse::Ok().json(UuidResponse { uuid }));
+        }
+    }
+    Err(Error::NotFound)
+}
 
-async fn private_read_impl(
-    body: &[u8],
+#[routes]
+#[post("/private-read")]
+#[post("/{bucket}/private-read")]
+async fn private_read(
+    req: HttpRequest,
+    body: web::Bytes,
     data: web::Data<ServerState>,
-) -> Result<Vec<u8>, actix_web::error::Error> {
-    let db = data.db.read().unwrap();
+) -> Result<HttpResponse, actix_web::error::Error> {
+    let bucket = data.bucket_for(&req)?;
+    let queries = if is_wire_request(&req) {
+        decode_frame(&body, FrameKind::Queries)
+            .map_err(Error::from)?
+            .into_iter()
+            .map(|query_bytes| query_bytes.to_vec())
+            .collect()
+    } else {
+        // parse body as list of json strings
+        let query_strs = serde_json::from_slice::<Vec<String>>(&body)
+            .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+        let mut queries = Vec::new();
+        for query_str in query_strs.iter() {
+            // decode each query from base64
+            let query_bytes = base64::decode(query_str)
+                .map_err(|_| Error::InvalidRequest("bad base64 query".to_owned()))?;
+            queries.push(query_bytes);
+        }
+        queries
+    };
+    let results = web::block(move || bucket.private_reads(queries))
+        .await
+        .map_err(|_| Error::Unknown)??;
+
+    if accepts_wire(&req) {
+        return Ok(HttpResponse::Ok()
+            .content_type(WIRE_CONTENT_TYPE)
+            .body(encode_frame(FrameKind::Responses, &results)));
+    }
 
-    let now = Instant::now();
-    let result = if data.params.expand_queries {
-        // Parse the UUID
-        let request_bytes = body;
-        assert_eq!(
-            request_bytes.len(),
-            UUID_V4_STR_BYTES + data.params.query_bytes()
-        );
-        let uuid_bytes = &request_bytes[..UUID_V4_STR_BYTES];
-        let query_bytes = &request_bytes[UUID_V4_STR_BYTES..];
-        let uuid = std::str::from_utf8(uuid_bytes).map_err(|_| PayloadError::EncodingCorrupted)?;
+    // return base64-encoded results as a JSON list
+    let out: Vec<_> = results.iter().map(base64::encode).collect();
+    let out_json = serde_json::to_string(&out).unwrap();
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(out_json))
+}
 
-        // Look up UUID and get public parameters
-        let pub_params_map = data.pub_params.read().unwrap();
-        let pub_params = pub_params_map.get(uuid).ok_or(Error::NotFound)?;
+#[routes]
+#[get("/meta")]
+#[get("/{bucket}/meta")]
+async fn meta(req: HttpRequest, data: web::Data<ServerState>) -> Result<HttpResponse, Error> {
+    let bucket = data.bucket_for(&req)?;
+    Ok(HttpResponse::Ok().json(bucket.meta_json()))
+}
 
-        let query = Query::deserialize(&data.params, query_bytes);
-        process_query(&data.params, pub_params, &query, &db)
-    } else {
-        // Here, we get the public parameters in the query
-        let request_bytes = body;
-        assert_eq!(
-            request_bytes.len(),
-            data.params.setup_bytes() + data.params.query_bytes()
-        );
-        let setup_bytes = &request_bytes[..data.params.setup_bytes()];
-        let query_bytes = &request_bytes[data.params.setup_bytes()..];
+#[get("/list-buckets")]
+async fn list_buckets(data: web::Data<ServerState>) -> HttpResponse {
+    let mut buckets: Vec<_> = data
+        .buckets
+        .read()
+        .unwrap()
+        .values()
+        .map(|bucket| bucket.meta_json())
+        .collect();
+    buckets.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    HttpResponse::Ok().json(serde_json::json!({ "buckets": buckets }))
+}
 
-        let pub_params_base = PublicParameters::deserialize(&data.params, setup_bytes);
-        let pub_params = &pub_params_base;
+#[derive(Deserialize)]
+struct CreateRequest {
+    #[serde(flatten)]
+    metadata: BucketMetadata,
+    /// The bucket's PIR scheme, in the format of the server's params file.
+    /// Defaults to the server's own.
+    pir_scheme: Option<serde_json::Value>,
+}
 
-        let query = Query::deserialize(&data.params, query_bytes);
-        process_query(&data.params, pub_params, &query, &db)
+/// Creates a bucket, failing with a 409 if one of the same name exists.
+#[post("/create")]
+async fn create(
+    request: web::Json<CreateRequest>,
+    data: web::Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    let request = request.into_inner();
+    let params_json = match &request.pir_scheme {
+        Some(scheme) => scheme.to_string(),
+        None => data.params_json.clone(),
     };
-    println!("Query processed. ({} ms)", now.elapsed().as_millis());
+    let params = data.params_for(&params_json, true)?;
 
-    Ok(result)
+    let state = data.clone();
+    let meta_json = web::block(move || state.create_bucket(params, &params_json, request.metadata))
+        .await
+        .map_err(|_| Error::Unknown)??;
+    Ok(HttpResponse::Ok().json(meta_json))
 }
 
-#[post("/private-read")]
-async fn private_read(
-    body: web::Bytes,
+#[routes]
+#[post("/modify")]
+#[post("/{bucket}/modify")]
+async fn modify(
+    req: HttpRequest,
+    modification: web::Json<BucketModification>,
     data: web::Data<ServerState>,
-) -> Result<String, actix_web::error::Error> {
-    // parse body as list of json strings
-    let query_strs = serde_json::from_slice::<Vec<String>>(&body).unwrap();
-
-    let mut out = Vec::new();
-    for query_str in query_strs.iter() {
-        // decode each query from base64
-        let query_bytes = base64::decode(query_str).unwrap();
-        let result = private_read_impl(&query_bytes, data.clone()).await?;
-        // store base64-encoded results in out
-        let result_str = base64::encode(&result);
-        out.push(result_str);
-    }
+) -> Result<HttpResponse, Error> {
+    let modification = modification.into_inner();
+    let name = data.bucket_name(&req).to_owned();
+    let state = data.clone();
+    let meta_json = web::block(move || state.modify_bucket(&name, modification))
+        .await
+        .map_err(|_| Error::Unknown)??;
+    Ok(HttpResponse::Ok().json(meta_json))
+}
 
-    let out_json = serde_json::to_string(&out).unwrap();
+#[routes]
+#[post("/destroy")]
+#[post("/{bucket}/destroy")]
+async fn destroy(req: HttpRequest, data: web::Data<ServerState>) -> Result<HttpResponse, Error> {
+    let name = data.bucket_name(&req).to_owned();
+    let state = data.clone();
+    web::block(move || state.destroy_bucket(&name))
+        .await
+        .map_err(|_| Error::Unknown)??;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "destroyed" })))
+}
 
-    Ok(out_json)
+#[routes]
+#[post("/clear")]
+#[post("/{bucket}/clear")]
+async fn clear(req: HttpRequest, data: web::Data<ServerState>) -> Result<HttpResponse, Error> {
+    let bucket = data.bucket_for(&req)?;
+    let seq = bucket.clear()?;
+    wait_durable(bucket, seq).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "cleared" })))
 }
 
-#[get("/meta")]
-async fn meta(data: web::Data<ServerState>) -> String {
-    let version = data.version.write().unwrap();
-
-    format!(
-        r#"{{
-            "id": 0,
-            "name": "",
-            "owner_id": 0,
-            "open_access": true,
-            "pir_scheme": {},
-            "global_version": {}
-        }}"#,
-        data.params_json, *version
-    )
-    .to_owned()
+/// Points clients at the bucket's Bloom filter, which they then download
+/// from `bloom-data`, as they would from a presigned URL.
+#[routes]
+#[get("/bloom")]
+#[get("/{bucket}/bloom")]
+async fn bloom(req: HttpRequest, data: web::Data<ServerState>) -> Result<HttpResponse, Error> {
+    let bucket = data.bucket_for(&req)?;
+    if bucket.bloom().is_none() {
+        return Err(Error::InvalidRequest(
+            "bucket does not keep a Bloom filter".to_owned(),
+        ));
+    }
+    let info = req.connection_info();
+    let url = format!("{}://{}{}-data", info.scheme(), info.host(), req.path());
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "url": url })))
+}
+
+#[routes]
+#[get("/bloom-data")]
+#[get("/{bucket}/bloom-data")]
+async fn bloom_data(req: HttpRequest, data: web::Data<ServerState>) -> Result<HttpResponse, Error> {
+    let bucket = data.bucket_for(&req)?;
+    let bloom_bytes = bucket.bloom().ok_or(Error::NotFound)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(bloom_bytes))
 }
 
 #[get("/")]
@@ -212,49 +638,177 @@ async fn main() -> std::io::Result<()> {
         let target_num_log2: usize = args[2].parse().unwrap();
         let item_size_bytes: usize = args[3].parse().unwrap();
 
-        params = get_params_from_store(target_num_log2, item_size_bytes);
+        let stored = match get_params_from_store(
+            1usize
+                .checked_shl(target_num_log2 as u32)
+                .unwrap_or(usize::MAX),
+            item_size_bytes,
+        ) {
+            Ok(stored) => stored,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "Starting with parameters for 2^{} x {} bytes (setup {} bytes, query {} bytes, response {} bytes)...",
+            stored.num_items.trailing_zeros(),
+            stored.item_size,
+            stored.setup_bytes,
+            stored.query_bytes,
+            stored.response_bytes
+        );
+        params_json = stored.params_json;
+        params = stored.params;
     } else if args.len() == 3 {
         // [port] [params.json]
         port = &args[1];
         let inp_params_fname = &args[2];
 
         params_json = fs::read_to_string(inp_params_fname).unwrap();
-        params = params_from_json(&params_json);
+        let scheme: serde_json::Value = serde_json::from_str(&params_json).unwrap();
+        params = match try_params_from_json_obj(&scheme) {
+            Ok(params) => params,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
     } else {
         // none
         params_json = cfg_expand.to_owned();
         params = params_from_json(cfg_expand);
     }
+    let params = Arc::new(params);
+
+    let data_dir = env::var_os(DATA_DIR_ENV_VAR).map(PathBuf::from);
+    let config = BucketConfig {
+        group_commit_window: Duration::from_millis(env_or(GROUP_COMMIT_MS_ENV_VAR, 0)),
+        checkpoint_interval: env_or(CHECKPOINT_INTERVAL_ENV_VAR, DEFAULT_CHECKPOINT_INTERVAL),
+        sessions: session_config_from_env(),
+        read_batch_window: Duration::from_millis(env_or(READ_BATCH_MS_ENV_VAR, 0)),
+        static_db_storage: static_db_storage_from_env(),
+        shard_instances: shard_instances_from_env(),
+    };
+    let metadata = BucketMetadata {
+        name: env_or(BUCKET_NAME_ENV_VAR, DEFAULT_BUCKET_NAME.to_owned()),
+        parameters: BucketParameters {
+            max_item_size: params.db_item_size,
+            ..Default::default()
+        },
+        open_access: true,
+    };
 
-    let db = SparseDb::new();
-    let mut rows = Vec::new();
-    for _ in 0..params.num_items() {
-        rows.push(Vec::new());
-    }
-
+    let mut schemes = HashMap::new();
+    schemes.insert(normalize_json(&params_json), params.clone());
     let server_state = ServerState {
-        params: Box::leak(Box::new(params)),
-        db: RwLock::new(db),
-        rows: RwLock::new(rows),
-        pub_params: RwLock::new(HashMap::new()),
+        params: params.clone(),
         params_json,
-        version: RwLock::new(0),
+        schemes: Mutex::new(schemes),
+        max_schemes: env_or(MAX_SCHEMES_ENV_VAR, DEFAULT_MAX_SCHEMES),
+        data_dir: data_dir.clone(),
+        config,
+        default_bucket: metadata.name.clone(),
+        buckets: RwLock::new(HashMap::new()),
+        bucket_changes: Mutex::new(()),
     };
+    if let Some(data_dir) = &data_dir {
+        server_state
+            .open_buckets(data_dir, metadata.clone())
+            .expect("could not load buckets");
+    }
+    let static_db = env::var_os(STATIC_DB_ENV_VAR);
+    let shard_workers = env::var(SHARD_WORKERS_ENV_VAR).ok();
+    if static_db.is_some() || shard_workers.is_some() {
+        // the default bucket serves a preprocessed database, read-only
+        let mut buckets = server_state.buckets.write().unwrap();
+        if buckets.contains_key(&metadata.name) {
+            eprintln!("bucket {} is both stored and static", metadata.name);
+            std::process::exit(1);
+        }
+        let bucket = match (static_db, shard_workers) {
+            (Some(static_db), None) => Bucket::open_static(
+                params.clone(),
+                &server_state.params_json,
+                metadata,
+                Path::new(&static_db),
+                &server_state.config,
+            ),
+            (None, Some(shard_workers)) => {
+                let urls: Vec<String> =
+                    shard_workers.split(',').map(|url| url.to_owned()).collect();
+                Bucket::open_coordinator(
+                    params.clone(),
+                    &server_state.params_json,
+                    metadata,
+                    &urls,
+                    &server_state.config,
+                )
+            }
+            _ => {
+                eprintln!(
+                    "{} and {} can't both be set",
+                    STATIC_DB_ENV_VAR, SHARD_WORKERS_ENV_VAR
+                );
+                std::process::exit(1);
+            }
+        }
+        .unwrap_or_else(|e| {
+            eprintln!("could not open static bucket: {}", e);
+            std::process::exit(1);
+        });
+        buckets.insert(bucket.name(), Arc::new(bucket));
+    } else if server_state.buckets.read().unwrap().is_empty() {
+        // a new server starts out with its default bucket
+        let bucket = Bucket::create(
+            params,
+            &server_state.params_json,
+            metadata,
+            server_state.new_bucket_dir(),
+            &server_state.config,
+        )
+        .expect("could not create bucket");
+        server_state
+            .buckets
+            .write()
+            .unwrap()
+            .insert(bucket.name(), Arc::new(bucket));
+    }
+    let mut names: Vec<_> = server_state
+        .buckets
+        .read()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    names.sort();
+    println!("Hosting buckets {}", names.join(", "));
     let state = web::Data::new(server_state);
 
+    let max_payload_bytes = env_or(MAX_PAYLOAD_MB_ENV_VAR, DEFAULT_MAX_PAYLOAD_MB) << 20;
+
     println!("Using {} threads", rayon::current_num_threads());
     println!("Listening on {}", port);
 
     HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
-            .app_data(web::PayloadConfig::new(1usize << 32))
+            .app_data(web::PayloadConfig::new(max_payload_bytes))
+            .wrap(ErrorHandlers::new().default_handler(json_error_body))
             .service(private_read)
             .service(index)
+            .service(list_buckets)
+            .service(create)
+            .service(check)
             .service(meta)
             .service(update_row)
             .service(setup)
             .service(write)
+            .service(modify)
+            .service(destroy)
+            .service(clear)
+            .service(bloom)
+            .service(bloom_data)
     })
     .bind(("localhost", port.parse().unwrap()))
     .unwrap()
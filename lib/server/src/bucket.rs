@@ -0,0 +1,1488 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use spiral_rs::client::{PublicParameters, Query};
+use spiral_rs::noise_estimate::NoiseEstimator;
+use spiral_rs::params::Params;
+use uuid::Uuid;
+
+use crate::db::bloom::BloomFilter;
+use crate::db::dense_db::{DenseDb, DenseDbStorage};
+use crate::db::loading::{check_many_items, update_many_items, write_preprocessed_db};
+use crate::db::snapshot::{read_snapshot, write_snapshot};
+use crate::db::sparse_db::SparseDb;
+use crate::db::wal::{Wal, WalRecord};
+use crate::db::write::{encode_rows, store_rows, EncodedRow};
+use crate::error::Error;
+use crate::server::{process_queries, process_queries_dense_shard};
+use crate::session::*;
+use crate::shard::Coordinator;
+
+const METADATA_FILENAME: &str = "bucket.json";
+const PARAMS_FILENAME: &str = "params.json";
+const SNAPSHOT_FILENAME: &str = "db.snapshot";
+const WAL_FILENAME: &str = "db.wal";
+const SESSIONS_DIRNAME: &str = "sessions";
+/// The extension of a bucket directory that is being created or destroyed.
+const PARTIAL_EXTENSION: &str = "partial";
+
+const BLOOM_K: u32 = 8;
+const BLOOM_BITS: u32 = 22;
+const MAX_NAME_LEN: usize = 128;
+const UUID_V4_STR_BYTES: usize = 36;
+
+const REQUIRED_SCHEME_FIELDS: [&str; 9] = [
+    "n",
+    "nu_1",
+    "nu_2",
+    "p",
+    "q2_bits",
+    "t_gsw",
+    "t_conv",
+    "t_exp_left",
+    "t_exp_right",
+];
+const MAX_SCHEME_N: u64 = 8;
+const MAX_SCHEME_DB_DIMS: u64 = 24;
+const MAX_SCHEME_T: u64 = 64;
+/// The highest estimated log2 probability that a response fails to decode
+/// that a scheme choosing its own `q1` may have.
+const MAX_SCHEME_LOG2_ERR: f64 = -40.0;
+
+/// What a bucket stores about its keys, besides the key hashes in its rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStoragePolicy {
+    #[default]
+    None,
+    /// A Bloom filter of every key ever written, served from `/bloom`.
+    Bloom,
+}
+
+/// The usage hints a bucket was created with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct BucketParameters {
+    pub max_item_size: usize,
+    pub key_storage_policy: KeyStoragePolicy,
+    pub version: u32,
+}
+
+impl Default for BucketParameters {
+    fn default() -> Self {
+        Self {
+            max_item_size: 1000,
+            key_storage_policy: KeyStoragePolicy::None,
+            version: 1,
+        }
+    }
+}
+
+/// A bucket's properties, as sent to `/create`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub parameters: BucketParameters,
+    #[serde(default)]
+    pub open_access: bool,
+}
+
+/// Changes to a bucket's properties, as sent to `/modify`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BucketModification {
+    pub name: Option<String>,
+    pub parameters: Option<BucketParameters>,
+    pub open_access: Option<bool>,
+}
+
+/// Settings shared by every bucket a server hosts.
+#[derive(Debug, Clone, Default)]
+pub struct BucketConfig {
+    pub group_commit_window: Duration,
+    pub checkpoint_interval: usize,
+    pub sessions: SessionConfig,
+    /// How long a private read waits for concurrent ones to share its pass
+    /// over the database. Reads that arrive while a pass is running are
+    /// batched into the next one regardless.
+    pub read_batch_window: Duration,
+    /// How static buckets hold their databases.
+    pub static_db_storage: DenseDbStorage,
+    /// The instances whose database slices static buckets hold, if they are
+    /// shard workers rather than holding them all.
+    pub shard_instances: Option<Range<usize>>,
+}
+
+/// Bucket names are 1 to 128 ASCII letters, digits, '-', '_' or '.'.
+pub fn is_valid_bucket_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn validate_metadata(params: &Params, metadata: &BucketMetadata) -> Result<(), Error> {
+    if !is_valid_bucket_name(&metadata.name) {
+        return Err(Error::InvalidName(metadata.name.clone()));
+    }
+    if metadata.parameters.max_item_size > params.db_item_size {
+        return Err(Error::InvalidLength(
+            metadata.parameters.max_item_size,
+            params.db_item_size,
+        ));
+    }
+    Ok(())
+}
+
+/// Builds `Params` from a PIR scheme in the JSON format of `params_from_json`,
+/// rejecting schemes that are incomplete, far outside any usable range, or
+/// over a ring `spiral_rs::params::check_ring` rejects. A scheme that sets
+/// `q1` must also meet `MAX_SCHEME_LOG2_ERR` by the noise estimator.
+pub fn params_from_scheme(scheme: &serde_json::Value) -> Result<Params, Error> {
+    let field = |name: &str| {
+        scheme[name]
+            .as_u64()
+            .ok_or_else(|| Error::InvalidParams(format!("missing or bad field {}", name)))
+    };
+    for name in REQUIRED_SCHEME_FIELDS {
+        field(name)?;
+    }
+    if !(1..=MAX_SCHEME_N).contains(&field("n")?) {
+        return Err(Error::InvalidParams("n out of range".to_owned()));
+    }
+    if field("nu_1")? + field("nu_2")? > MAX_SCHEME_DB_DIMS {
+        return Err(Error::InvalidParams("database too large".to_owned()));
+    }
+    let p = field("p")?;
+    if p < 2 || !p.is_power_of_two() {
+        return Err(Error::InvalidParams("p must be a power of two".to_owned()));
+    }
+    for name in ["t_gsw", "t_conv", "t_exp_left", "t_exp_right"] {
+        if !(1..=MAX_SCHEME_T).contains(&field(name)?) {
+            return Err(Error::InvalidParams(format!("{} out of range", name)));
+        }
+    }
+    let params = spiral_rs::util::try_params_from_json_obj(scheme)?;
+    if params.db_item_size > params.item_size() {
+        return Err(Error::InvalidParams(format!(
+            "db_item_size must be at most {}",
+            params.item_size()
+        )));
+    }
+    if scheme.get("q1").is_some() && params.estimate_log2_err_prob() > MAX_SCHEME_LOG2_ERR {
+        return Err(Error::InvalidParams(format!(
+            "q1 {} gives an estimated log2 error probability above {}",
+            params.q1, MAX_SCHEME_LOG2_ERR
+        )));
+    }
+    Ok(params)
+}
+
+/// Writes `data` to `path` durably, replacing any existing file atomically.
+fn write_file_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// The temporary name of the bucket directory `dir`, while it is created or
+/// destroyed.
+fn partial_dir(dir: &Path) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    dir.with_file_name(name)
+}
+
+fn new_bloom(metadata: &BucketMetadata) -> Option<BloomFilter> {
+    match metadata.parameters.key_storage_policy {
+        KeyStoragePolicy::None => None,
+        KeyStoragePolicy::Bloom => Some(BloomFilter::new(BLOOM_K, BLOOM_BITS)),
+    }
+}
+
+/// On-disk state of a bucket: its metadata, a snapshot of its database, a
+/// write-ahead log of the mutations made since the snapshot was taken, and
+/// its sessions.
+struct Storage {
+    dir: PathBuf,
+    wal: Wal,
+    checkpoint_interval: usize,
+    /// Held while a checkpoint is written, so that only one runs at a time.
+    checkpointing: Mutex<()>,
+}
+
+/// Mutable state of a bucket's database. Locks are taken in field order.
+struct Contents {
+    rows: RwLock<Vec<Vec<u8>>>,
+    db: RwLock<SparseDb>,
+    version: RwLock<u64>,
+    bloom: RwLock<Option<BloomFilter>>,
+}
+
+/// Private reads waiting to be answered together, as in `Bucket::private_reads`.
+#[derive(Default)]
+struct ReadBatch {
+    next_ticket: u64,
+    pending: Vec<(u64, Vec<Vec<u8>>)>,
+    answered: HashMap<u64, Result<Vec<Vec<u8>>, Error>>,
+    running: bool,
+}
+
+/// The public parameters a query was made under.
+enum QueryParams<'a> {
+    Session(Arc<StoredPublicParameters>),
+    Inline(PublicParameters<'a>),
+}
+
+impl QueryParams<'_> {
+    fn get(&self) -> Result<&PublicParameters<'_>, Error> {
+        match self {
+            QueryParams::Session(stored) => stored.get(),
+            QueryParams::Inline(pub_params) => Ok(pub_params),
+        }
+    }
+}
+
+/// A database served over PIR, with its clients' sessions.
+///
+/// With a directory, every mutation is logged and fsynced before it is
+/// acknowledged, and the bucket can be reopened after a restart.
+pub struct Bucket {
+    params: Arc<Params>,
+    params_json: String,
+    metadata: RwLock<BucketMetadata>,
+    contents: Contents,
+    sessions: Mutex<SessionStore<StoredPublicParameters>>,
+    storage: Option<Storage>,
+    /// The preprocessed database of a static bucket, which is read-only.
+    static_db: Option<DenseDb>,
+    /// The instances `static_db` holds.
+    instances: Range<usize>,
+    /// The workers holding a sharded bucket's instances, if this bucket
+    /// coordinates them.
+    coordinator: Option<Coordinator>,
+    destroyed: AtomicBool,
+    reads: Mutex<ReadBatch>,
+    reads_answered: Condvar,
+    read_batch_window: Duration,
+}
+
+/// A record whose checks have passed, with the work done to check it.
+enum CheckedRecord<'a> {
+    Write(&'a [(String, Vec<u8>)], Vec<EncodedRow>),
+    UpdateRows(&'a [u8]),
+    Clear,
+}
+
+/// Does the part of applying `record` that can fail on bad input, without
+/// changing anything, so that a mutation is only logged once it will apply.
+fn check_record<'a>(
+    params: &Params,
+    record: &'a WalRecord,
+    rows: &[Vec<u8>],
+) -> Result<CheckedRecord<'a>, Error> {
+    match record {
+        WalRecord::Write(kv_pairs) => {
+            let kv_pairs_slices: Vec<(&str, &[u8])> = kv_pairs
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice()))
+                .collect();
+            let encoded_rows = encode_rows(params, &kv_pairs_slices, rows)?;
+            Ok(CheckedRecord::Write(kv_pairs, encoded_rows))
+        }
+        WalRecord::UpdateRows(body) => {
+            check_many_items(params, body)?;
+            Ok(CheckedRecord::UpdateRows(body))
+        }
+        WalRecord::Clear => Ok(CheckedRecord::Clear),
+    }
+}
+
+fn apply_checked_record(
+    params: &Params,
+    record: CheckedRecord,
+    rows: &mut Vec<Vec<u8>>,
+    db: &mut SparseDb,
+    version: &mut u64,
+    bloom: &mut Option<BloomFilter>,
+) -> Result<usize, Error> {
+    match record {
+        CheckedRecord::Write(kv_pairs, encoded_rows) => {
+            store_rows(params, encoded_rows, rows, db)?;
+            if let Some(bloom) = bloom {
+                for (key, value) in kv_pairs {
+                    if !value.is_empty() {
+                        bloom.insert(key);
+                    }
+                }
+            }
+            *version += 1;
+            Ok(0)
+        }
+        CheckedRecord::UpdateRows(body) => Ok(update_many_items(params, body, db)? as usize),
+        CheckedRecord::Clear => {
+            *rows = vec![Vec::new(); params.num_items()];
+            *db = SparseDb::new();
+            if let Some(bloom) = bloom {
+                *bloom = BloomFilter::new(BLOOM_K, BLOOM_BITS);
+            }
+            *version += 1;
+            Ok(0)
+        }
+    }
+}
+
+fn apply_record(
+    params: &Params,
+    record: &WalRecord,
+    rows: &mut Vec<Vec<u8>>,
+    db: &mut SparseDb,
+    version: &mut u64,
+    bloom: &mut Option<BloomFilter>,
+) -> Result<usize, Error> {
+    let checked = check_record(params, record, rows)?;
+    apply_checked_record(params, checked, rows, db, version, bloom)
+}
+
+impl Bucket {
+    fn new(
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+        config: &BucketConfig,
+    ) -> Self {
+        let sessions = SessionStore::new(config.sessions.clone())
+            .on_evict(|_, stored: &StoredPublicParameters| stored.remove_file());
+        let bloom = new_bloom(&metadata);
+        let rows = vec![Vec::new(); params.num_items()];
+        let instances = 0..params.instances;
+        Self {
+            params,
+            params_json: params_json.to_owned(),
+            metadata: RwLock::new(metadata),
+            contents: Contents {
+                rows: RwLock::new(rows),
+                db: RwLock::new(SparseDb::new()),
+                version: RwLock::new(0),
+                bloom: RwLock::new(bloom),
+            },
+            sessions: Mutex::new(sessions),
+            storage: None,
+            static_db: None,
+            instances,
+            coordinator: None,
+            destroyed: AtomicBool::new(false),
+            reads: Mutex::new(ReadBatch::default()),
+            reads_answered: Condvar::new(),
+            read_batch_window: config.read_batch_window,
+        }
+    }
+
+    /// Creates an empty bucket, stored in `dir` if given.
+    pub fn create(
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+        dir: Option<PathBuf>,
+        config: &BucketConfig,
+    ) -> Result<Self, Error> {
+        validate_metadata(&params, &metadata)?;
+        let mut bucket = Self::new(params, params_json, metadata.clone(), config);
+        if let Some(dir) = dir {
+            // built under a temporary name, so that a crash part way through
+            // leaves a directory the next startup knows it may delete
+            let partial = partial_dir(&dir);
+            if partial.exists() {
+                fs::remove_dir_all(&partial)?;
+            }
+            fs::create_dir_all(partial.join(SESSIONS_DIRNAME))?;
+            write_file_atomic(&partial.join(PARAMS_FILENAME), params_json.as_bytes())?;
+            write_file_atomic(
+                &partial.join(METADATA_FILENAME),
+                &serde_json::to_vec(&metadata).unwrap(),
+            )?;
+            fs::rename(&partial, &dir)?;
+            let (wal, _) = Wal::open(&dir.join(WAL_FILENAME), 0, config.group_commit_window)?;
+            bucket.storage = Some(Storage {
+                dir,
+                wal,
+                checkpoint_interval: config.checkpoint_interval,
+                checkpointing: Mutex::new(()),
+            });
+        }
+        Ok(bucket)
+    }
+
+    /// Creates a bucket in `dir` holding `kv_pairs`, which are written
+    /// straight to a snapshot rather than through the write-ahead log, to
+    /// build databases offline.
+    pub fn build(
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+        kv_pairs: Vec<(String, Vec<u8>)>,
+        dir: PathBuf,
+        config: &BucketConfig,
+    ) -> Result<Self, Error> {
+        let bucket = Self::create(params, params_json, metadata, Some(dir), config)?;
+        {
+            let params = &bucket.params;
+            let contents = &bucket.contents;
+            let mut rows = contents.rows.write()?;
+            let mut db = contents.db.write()?;
+            let mut version = contents.version.write()?;
+            let mut bloom = contents.bloom.write()?;
+            apply_record(
+                params,
+                &WalRecord::Write(kv_pairs),
+                &mut rows,
+                &mut db,
+                &mut version,
+                &mut bloom,
+            )?;
+            let storage = bucket.storage.as_ref().unwrap();
+            write_snapshot(
+                &storage.dir.join(SNAPSHOT_FILENAME),
+                &db,
+                &rows,
+                *version,
+                storage.wal.last_seq()?,
+                bloom.as_ref(),
+            )?;
+        }
+        Ok(bucket)
+    }
+
+    /// Writes the bucket's database to `path` in the preprocessed format
+    /// static buckets serve.
+    pub fn write_preprocessed_db(&self, path: &Path) -> Result<(), Error> {
+        let db = self.contents.db.read()?;
+        let mut writer = std::io::BufWriter::with_capacity(1 << 24, File::create(path)?);
+        write_preprocessed_db(&self.params, &db, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+
+    /// Opens a static bucket, which answers reads from the preprocessed
+    /// database at `db_path`, in the format `load_preprocessed_db_from_file`
+    /// reads, and rejects writes. The database is loaded or mapped as
+    /// `config.static_db_storage` says. Its sessions are kept in memory only.
+    ///
+    /// With `config.shard_instances`, only the slices of those instances are
+    /// loaded, and reads are answered with their part of each response, for
+    /// a coordinator to put together.
+    pub fn open_static(
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+        db_path: &Path,
+        config: &BucketConfig,
+    ) -> Result<Self, Error> {
+        validate_metadata(&params, &metadata)?;
+        let now = Instant::now();
+        let mut bucket = Self::new(params, params_json, metadata, config);
+        if let Some(instances) = &config.shard_instances {
+            bucket.instances = instances.clone();
+        }
+        bucket.static_db = Some(DenseDb::open_instances(
+            &bucket.params,
+            db_path,
+            config.static_db_storage,
+            bucket.instances.clone(),
+        )?);
+        println!(
+            "[{}] Loaded static database {}, instances {}..{} ({} ms)",
+            bucket.name(),
+            db_path.display(),
+            bucket.instances.start,
+            bucket.instances.end,
+            now.elapsed().as_millis()
+        );
+        Ok(bucket)
+    }
+
+    /// Opens a read-only bucket whose reads are answered by the shard
+    /// workers at `worker_urls`, static buckets that together hold all of its
+    /// instances. Sessions are set up on every worker.
+    pub fn open_coordinator(
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+        worker_urls: &[String],
+        config: &BucketConfig,
+    ) -> Result<Self, Error> {
+        validate_metadata(&params, &metadata)?;
+        let coordinator =
+            Coordinator::connect(params.clone(), worker_urls, config.sessions.clone())?;
+        let mut bucket = Self::new(params, params_json, metadata, config);
+        for worker in coordinator.workers() {
+            println!(
+                "[{}] Instances {}..{} are on {}",
+                bucket.name(),
+                worker.instances.start,
+                worker.instances.end,
+                worker.url
+            );
+        }
+        bucket.coordinator = Some(coordinator);
+        Ok(bucket)
+    }
+
+    /// Whether the bucket serves a read-only, preprocessed database.
+    pub fn is_static(&self) -> bool {
+        self.static_db.is_some()
+    }
+
+    /// Whether the bucket rejects writes: it is static, or coordinates static
+    /// shard workers.
+    pub fn is_read_only(&self) -> bool {
+        self.is_static() || self.coordinator.is_some()
+    }
+
+    /// Whether `dir` holds a bucket.
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(METADATA_FILENAME).exists()
+    }
+
+    /// Whether `dir` is a bucket directory left part way through being
+    /// created or destroyed, which can be deleted.
+    pub fn is_partial(dir: &Path) -> bool {
+        dir.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION)
+    }
+
+    /// The PIR scheme the bucket stored in `dir` was created with, or an empty
+    /// string if it predates per-bucket schemes.
+    pub fn stored_params_json(dir: &Path) -> Result<String, Error> {
+        match fs::read_to_string(dir.join(PARAMS_FILENAME)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            result => Ok(result?),
+        }
+    }
+
+    /// Moves the files of a bucket stored directly in `from`, as servers that
+    /// hosted a single bucket did, into the new directory `to`. Returns
+    /// whether there were any.
+    pub fn move_files(from: &Path, to: &Path) -> Result<bool, Error> {
+        let filenames = [
+            METADATA_FILENAME,
+            PARAMS_FILENAME,
+            SNAPSHOT_FILENAME,
+            WAL_FILENAME,
+            SESSIONS_DIRNAME,
+        ];
+        let mut moved = false;
+        for filename in filenames {
+            let path = from.join(filename);
+            if path.exists() {
+                fs::create_dir_all(to)?;
+                fs::rename(path, to.join(filename))?;
+                moved = true;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Reopens the bucket stored in `dir`, first giving it `metadata` if it
+    /// has none. Any database already in `dir` is kept, so this also adopts
+    /// the files of servers that predate bucket metadata.
+    pub fn open_or_create(
+        params: Arc<Params>,
+        params_json: &str,
+        metadata: BucketMetadata,
+        dir: PathBuf,
+        config: &BucketConfig,
+    ) -> Result<Self, Error> {
+        if !Self::exists(&dir) {
+            validate_metadata(&params, &metadata)?;
+            fs::create_dir_all(&dir)?;
+            if !dir.join(PARAMS_FILENAME).exists() {
+                write_file_atomic(&dir.join(PARAMS_FILENAME), params_json.as_bytes())?;
+            }
+            write_file_atomic(
+                &dir.join(METADATA_FILENAME),
+                &serde_json::to_vec(&metadata).unwrap(),
+            )?;
+        }
+        Self::open(params, params_json, dir, config)
+    }
+
+    /// Reopens the bucket stored in `dir`, replaying its write-ahead log and
+    /// restoring its sessions.
+    pub fn open(
+        params: Arc<Params>,
+        params_json: &str,
+        dir: PathBuf,
+        config: &BucketConfig,
+    ) -> Result<Self, Error> {
+        let metadata_bytes = fs::read(dir.join(METADATA_FILENAME))?;
+        let metadata: BucketMetadata = serde_json::from_slice(&metadata_bytes)
+            .map_err(|e| Error::Corrupted(format!("bad bucket metadata: {}", e)))?;
+        let mut bucket = Self::new(params.clone(), params_json, metadata, config);
+        let name = bucket.metadata().name;
+
+        let now = Instant::now();
+        let mut wal_seq = 0;
+        if let Some(snapshot) = read_snapshot(&dir.join(SNAPSHOT_FILENAME), &params)? {
+            println!(
+                "[{}] Loaded snapshot at version {} ({} ms)",
+                name,
+                snapshot.version,
+                now.elapsed().as_millis()
+            );
+            let contents = &mut bucket.contents;
+            *contents.rows.get_mut()? = snapshot.rows;
+            *contents.db.get_mut()? = snapshot.db;
+            *contents.version.get_mut()? = snapshot.version;
+            let bloom = contents.bloom.get_mut()?;
+            if bloom.is_some() {
+                *bloom = snapshot.bloom.or_else(|| bloom.take());
+            }
+            wal_seq = snapshot.wal_seq;
+        }
+
+        let now = Instant::now();
+        let (wal, records) =
+            Wal::open(&dir.join(WAL_FILENAME), wal_seq, config.group_commit_window)?;
+        {
+            let contents = &mut bucket.contents;
+            let rows = contents.rows.get_mut()?;
+            let db = contents.db.get_mut()?;
+            let version = contents.version.get_mut()?;
+            let bloom = contents.bloom.get_mut()?;
+            for record in records.iter() {
+                if let Err(e) = apply_record(&params, record, rows, db, version, bloom) {
+                    // the original request failed the same way, so just move on
+                    println!("[{}] Replayed record failed: {}", name, e);
+                }
+            }
+        }
+        println!(
+            "[{}] Replayed {} write-ahead log records ({} ms)",
+            name,
+            records.len(),
+            now.elapsed().as_millis()
+        );
+
+        let now = Instant::now();
+        let sessions_dir = dir.join(SESSIONS_DIRNAME);
+        fs::create_dir_all(&sessions_dir)?;
+        let session_files = list_session_files(&sessions_dir, &params)?;
+        {
+            let sessions = bucket.sessions.get_mut()?;
+            if !session_files.is_empty() {
+                let size = public_parameters_bytes_for(&params);
+                for (uuid, path, last_used) in session_files {
+                    let age = last_used.elapsed().unwrap_or_default();
+                    let stored = StoredPublicParameters::on_disk(params.clone(), path);
+                    if config.sessions.ttl.is_some_and(|ttl| age > ttl) {
+                        stored.remove_file();
+                        continue;
+                    }
+                    let last_used = now.checked_sub(age).unwrap_or(now);
+                    // evicts the least recently used sessions if the limits
+                    // have shrunk
+                    sessions.insert_used_at(uuid, stored, size, last_used).ok();
+                }
+            }
+            println!(
+                "[{}] Restored {} sessions ({} ms)",
+                name,
+                sessions.len(),
+                now.elapsed().as_millis()
+            );
+        }
+
+        bucket.storage = Some(Storage {
+            dir,
+            wal,
+            checkpoint_interval: config.checkpoint_interval,
+            checkpointing: Mutex::new(()),
+        });
+        Ok(bucket)
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn metadata(&self) -> BucketMetadata {
+        self.metadata.read().unwrap().clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.metadata.read().unwrap().name.clone()
+    }
+
+    pub fn version(&self) -> u64 {
+        *self.contents.version.read().unwrap()
+    }
+
+    /// The bucket's properties, as returned by `/meta` and `/list-buckets`.
+    pub fn meta_json(&self) -> serde_json::Value {
+        let metadata = self.metadata();
+        let pir_scheme: serde_json::Value =
+            serde_json::from_str(&self.params_json).unwrap_or(serde_json::Value::Null);
+        let mut meta = serde_json::json!({
+            "id": 0,
+            "name": metadata.name,
+            "owner_id": 0,
+            "open_access": metadata.open_access,
+            "parameters": metadata.parameters,
+            "pir_scheme": pir_scheme,
+            "global_version": self.version(),
+        });
+        if self.is_static() {
+            // what a coordinator reads to place this bucket as a shard worker
+            meta["instances"] = serde_json::json!([self.instances.start, self.instances.end]);
+        }
+        meta
+    }
+
+    /// Applies `modification` to the bucket's properties, returning the result.
+    pub fn modify(&self, modification: BucketModification) -> Result<BucketMetadata, Error> {
+        let mut metadata_mut = self.metadata.write().unwrap();
+        let mut metadata = metadata_mut.clone();
+        if let Some(name) = modification.name {
+            metadata.name = name;
+        }
+        if let Some(parameters) = modification.parameters {
+            metadata.parameters = parameters;
+        }
+        if let Some(open_access) = modification.open_access {
+            metadata.open_access = open_access;
+        }
+        validate_metadata(&self.params, &metadata)?;
+
+        if metadata.parameters.key_storage_policy != metadata_mut.parameters.key_storage_policy {
+            *self.contents.bloom.write().unwrap() = new_bloom(&metadata);
+        }
+        if let Some(storage) = &self.storage {
+            write_file_atomic(
+                &storage.dir.join(METADATA_FILENAME),
+                &serde_json::to_vec(&metadata).unwrap(),
+            )?;
+        }
+        *metadata_mut = metadata.clone();
+        Ok(metadata)
+    }
+
+    /// The serialized Bloom filter of the bucket's keys, if it keeps one.
+    pub fn bloom(&self) -> Option<Vec<u8>> {
+        self.contents
+            .bloom
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|bloom| bloom.to_bytes())
+    }
+
+    /// Appends a mutation to the write-ahead log, if the bucket is stored.
+    /// Must be called while holding the database write lock.
+    fn log(&self, record: &WalRecord) -> Result<Option<u64>, Error> {
+        match &self.storage {
+            Some(storage) => Ok(Some(storage.wal.append(record)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Snapshots the database and empties the write-ahead log, once enough
+    /// records have accumulated. Only the contents' read locks are held, so
+    /// reads go on meanwhile, while mutations, which append to the log, wait.
+    /// A failure is only logged: the mutations are still in the log, which
+    /// grows until a later checkpoint succeeds.
+    fn checkpoint_if_needed(&self) {
+        let Some(storage) = &self.storage else {
+            return;
+        };
+        let Ok(_checkpointing) = storage.checkpointing.try_lock() else {
+            return;
+        };
+        let contents = &self.contents;
+        let rows = contents.rows.read().unwrap();
+        let db = contents.db.read().unwrap();
+        let version = contents.version.read().unwrap();
+        let bloom = contents.bloom.read().unwrap();
+        if let Err(e) = self.checkpoint(storage, &rows, &db, *version, bloom.as_ref()) {
+            println!("[{}] Checkpoint failed: {}", self.name(), e);
+        }
+    }
+
+    fn checkpoint(
+        &self,
+        storage: &Storage,
+        rows: &[Vec<u8>],
+        db: &SparseDb,
+        version: u64,
+        bloom: Option<&BloomFilter>,
+    ) -> Result<(), Error> {
+        if self.destroyed.load(Ordering::SeqCst)
+            || storage.wal.records_since_checkpoint()? < storage.checkpoint_interval
+        {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let wal_seq = storage.wal.last_seq()?;
+        let snapshot_path = storage.dir.join(SNAPSHOT_FILENAME);
+        write_snapshot(&snapshot_path, db, rows, version, wal_seq, bloom)?;
+        storage.wal.truncate()?;
+        println!("Checkpoint written. ({} ms)", now.elapsed().as_millis());
+        Ok(())
+    }
+
+    /// Checks, logs and applies a mutation. Returns the value from applying it
+    /// and the log sequence number to pass to `sync` before acknowledging it.
+    /// A mutation that fails its checks is not logged, and one that waited
+    /// on the locks while the bucket was destroyed fails with `NotFound`.
+    fn mutate(&self, record: WalRecord) -> Result<(usize, Option<u64>), Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let contents = &self.contents;
+        let (result, seq) = {
+            let mut rows = contents.rows.write().unwrap();
+            let mut db = contents.db.write().unwrap();
+            let mut version = contents.version.write().unwrap();
+            let mut bloom = contents.bloom.write().unwrap();
+            if self.destroyed.load(Ordering::SeqCst) {
+                return Err(Error::NotFound);
+            }
+            let checked = check_record(&self.params, &record, &rows)?;
+            let seq = self.log(&record)?;
+            let result = apply_checked_record(
+                &self.params,
+                checked,
+                &mut rows,
+                &mut db,
+                &mut version,
+                &mut bloom,
+            )?;
+            (result, seq)
+        };
+        self.checkpoint_if_needed();
+        Ok((result, seq))
+    }
+
+    /// Writes KV pairs; an empty value deletes the key.
+    pub fn write(&self, kv_pairs: Vec<(String, Vec<u8>)>) -> Result<Option<u64>, Error> {
+        Ok(self.mutate(WalRecord::Write(kv_pairs))?.1)
+    }
+
+    /// Writes raw, encoded rows, returning the size of the largest one.
+    pub fn update_rows(&self, body: &[u8]) -> Result<(usize, Option<u64>), Error> {
+        self.mutate(WalRecord::UpdateRows(body.to_vec()))
+    }
+
+    /// Deletes every item, keeping the bucket's properties and sessions.
+    pub fn clear(&self) -> Result<Option<u64>, Error> {
+        Ok(self.mutate(WalRecord::Clear)?.1)
+    }
+
+    /// Blocks until the logged mutation `seq` is durable.
+    pub fn sync(&self, seq: Option<u64>) -> Result<(), Error> {
+        match (&self.storage, seq) {
+            (Some(storage), Some(seq)) => storage.wal.sync(seq),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes the bucket's directory. Mutations that reach the bucket
+    /// afterwards fail with `NotFound`.
+    pub fn destroy(&self) -> Result<(), Error> {
+        let _rows = self.contents.rows.write().unwrap();
+        let _db = self.contents.db.write().unwrap();
+        self.destroyed.store(true, Ordering::SeqCst);
+        if let Some(storage) = &self.storage {
+            // renamed first, so that a crash part way through doesn't leave a
+            // bucket without some of its files
+            let partial = partial_dir(&storage.dir);
+            fs::rename(&storage.dir, &partial)?;
+            fs::remove_dir_all(&partial)?;
+        }
+        Ok(())
+    }
+
+    /// Stores a client's serialized public parameters, returning the UUID
+    /// that identifies them in later queries.
+    pub fn setup(&self, data: &[u8]) -> Result<String, Error> {
+        if let Some(coordinator) = &self.coordinator {
+            return coordinator.setup(data);
+        }
+        let stored = StoredPublicParameters::deserialize(self.params.clone(), data)?;
+        let size = public_parameters_bytes(stored.get()?);
+
+        let uuid = Uuid::new_v4().to_string();
+        let path =
+            match &self.storage {
+                Some(storage) if !self.destroyed.load(Ordering::SeqCst) => Some(
+                    write_session_file(&storage.dir.join(SESSIONS_DIRNAME), &uuid, data)?,
+                ),
+                _ => None,
+            };
+        let stored = stored.stored_at(path);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(uuid.clone(), stored, size)?;
+        Ok(uuid)
+    }
+
+    /// Whether the session `uuid` is live, without extending it.
+    pub fn has_session(&self, uuid: &str) -> Result<bool, Error> {
+        if let Some(coordinator) = &self.coordinator {
+            return coordinator.has_session(uuid);
+        }
+        Ok(self.sessions.lock()?.contains(uuid))
+    }
+
+    /// Answers a query: a session UUID followed by the query, or, for
+    /// parameters that don't expand queries, public parameters followed by
+    /// the query.
+    pub fn private_read(&self, request_bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .answer_reads(&[vec![request_bytes.to_vec()]])
+            .pop()
+            .unwrap()?
+            .pop()
+            .unwrap())
+    }
+
+    /// Answers the queries of one request, in the format of `private_read`,
+    /// failing if any of them is invalid. Concurrent calls are batched, so
+    /// that their queries share passes over the database: a call that finds
+    /// a batch running waits for it to finish and then joins the next one.
+    pub fn private_reads(&self, queries: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, Error> {
+        let mut reads = self.reads.lock()?;
+        let ticket = reads.next_ticket;
+        reads.next_ticket += 1;
+        reads.pending.push((ticket, queries));
+        loop {
+            if let Some(result) = reads.answered.remove(&ticket) {
+                return result;
+            }
+            if reads.running {
+                reads = self.reads_answered.wait(reads)?;
+                continue;
+            }
+
+            reads.running = true;
+            if !self.read_batch_window.is_zero() {
+                drop(reads);
+                thread::sleep(self.read_batch_window);
+                reads = self.reads.lock()?;
+            }
+            let (tickets, requests): (Vec<_>, Vec<_>) = reads.pending.drain(..).unzip();
+            drop(reads);
+
+            // a panic must not leave the batch running, or every later read
+            // would wait on it forever; its queries fail instead
+            let results = panic::catch_unwind(AssertUnwindSafe(|| self.answer_reads(&requests)))
+                .unwrap_or_else(|_| requests.iter().map(|_| Err(Error::Unknown)).collect());
+
+            reads = self.reads.lock()?;
+            reads.running = false;
+            reads.answered.extend(tickets.into_iter().zip(results));
+            self.reads_answered.notify_all();
+        }
+    }
+
+    fn parse_read(&self, request_bytes: &[u8]) -> Result<(QueryParams<'_>, Query<'_>), Error> {
+        let params = &*self.params;
+        if params.expand_queries {
+            // Parse the UUID
+            let expected_len = UUID_V4_STR_BYTES + params.query_bytes();
+            if request_bytes.len() != expected_len {
+                return Err(Error::InvalidLength(request_bytes.len(), expected_len));
+            }
+            let uuid_bytes = &request_bytes[..UUID_V4_STR_BYTES];
+            let query_bytes = &request_bytes[UUID_V4_STR_BYTES..];
+            let uuid = std::str::from_utf8(uuid_bytes).map_err(|_| Error::NotFound)?;
+
+            // Look up UUID and get public parameters
+            let session = self
+                .sessions
+                .lock()
+                .unwrap()
+                .get(uuid)
+                .ok_or(Error::NotFound)?;
+            session.get()?;
+            session.touch();
+
+            let query = Query::try_deserialize(params, query_bytes)?;
+            Ok((QueryParams::Session(session), query))
+        } else {
+            // Here, we get the public parameters in the query
+            let expected_len = params.setup_bytes() + params.query_bytes();
+            if request_bytes.len() != expected_len {
+                return Err(Error::InvalidLength(request_bytes.len(), expected_len));
+            }
+            let setup_bytes = &request_bytes[..params.setup_bytes()];
+            let query_bytes = &request_bytes[params.setup_bytes()..];
+
+            let pub_params = PublicParameters::try_deserialize(params, setup_bytes)?;
+
+            let query = Query::try_deserialize(params, query_bytes)?;
+            Ok((QueryParams::Inline(pub_params), query))
+        }
+    }
+
+    /// Answers the queries of several requests in as few passes as possible.
+    /// A request with an invalid query fails on its own.
+    fn answer_reads(&self, requests: &[Vec<Vec<u8>>]) -> Vec<Result<Vec<Vec<u8>>, Error>> {
+        if let Some(coordinator) = &self.coordinator {
+            // the workers batch concurrent requests themselves; the calls
+            // block on HTTP, so they get threads of their own, not rayon's
+            return thread::scope(|scope| {
+                let handles: Vec<_> = requests
+                    .iter()
+                    .map(|queries| scope.spawn(move || coordinator.private_reads(queries)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().map_err(|_| Error::Unknown)?)
+                    .collect()
+            });
+        }
+        let now = Instant::now();
+        let parsed: Vec<Result<Vec<_>, Error>> = requests
+            .iter()
+            .map(|queries| queries.iter().map(|q| self.parse_read(q)).collect())
+            .collect();
+
+        let mut batch = Vec::new();
+        for (query_params, query) in parsed.iter().flatten().flatten() {
+            batch.push((query_params.get().expect("loaded by parse_read"), query));
+        }
+        let mut responses = if batch.is_empty() {
+            Vec::new()
+        } else if let Some(static_db) = &self.static_db {
+            process_queries_dense_shard(
+                &self.params,
+                &batch,
+                static_db.as_slice(),
+                self.instances.clone(),
+            )
+        } else {
+            let db = self.contents.db.read().unwrap();
+            process_queries(&self.params, &batch, &db)
+        }
+        .into_iter();
+        if !batch.is_empty() {
+            println!(
+                "{} queries from {} requests processed. ({} ms)",
+                batch.len(),
+                requests.len(),
+                now.elapsed().as_millis()
+            );
+        }
+
+        parsed
+            .into_iter()
+            .map(|queries| Ok(responses.by_ref().take(queries?.len()).collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::RngCore;
+    use spiral_rs::arith::log2_ceil;
+    use spiral_rs::client::Client;
+
+    use crate::db::dense_db::DbAdvice;
+    use crate::db::loading::generate_random_db_and_get_item;
+    use crate::test_util::{get_params, get_small_params, temp_dir};
+
+    fn get_metadata(name: &str) -> BucketMetadata {
+        BucketMetadata {
+            name: name.to_owned(),
+            parameters: BucketParameters {
+                key_storage_policy: KeyStoragePolicy::Bloom,
+                ..Default::default()
+            },
+            open_access: false,
+        }
+    }
+
+    fn kv(key: &str, value: &[u8]) -> (String, Vec<u8>) {
+        (key.to_owned(), value.to_vec())
+    }
+
+    #[test]
+    fn bucket_reopen_is_correct() {
+        let params = get_params();
+        let dir = temp_dir();
+        let config = BucketConfig {
+            checkpoint_interval: 2,
+            ..Default::default()
+        };
+
+        let bucket = Bucket::create(
+            params.clone(),
+            "",
+            get_metadata("b"),
+            Some(dir.clone()),
+            &config,
+        )
+        .unwrap();
+        let seq = bucket.write(vec![kv("CA", b"California")]).unwrap();
+        bucket.sync(seq).unwrap();
+        let seq = bucket.clear().unwrap();
+        bucket.sync(seq).unwrap();
+        let seq = bucket
+            .write(vec![
+                kv("OR", b"Oregon"),
+                kv("WA", b"Washington"),
+                kv("ID", b"Id"),
+            ])
+            .unwrap();
+        bucket.sync(seq).unwrap();
+        let seq = bucket
+            .write(vec![kv("WA", b""), kv("ID", b""), kv("NV", b"")])
+            .unwrap();
+        bucket.sync(seq).unwrap();
+        bucket
+            .modify(BucketModification {
+                name: Some("c".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        let rows = bucket.contents.rows.read().unwrap().clone();
+        let bloom = bucket.bloom().unwrap();
+        drop(bucket);
+
+        let bucket = Bucket::open(params.clone(), "", dir.clone(), &config).unwrap();
+        assert_eq!(bucket.name(), "c");
+        assert_eq!(bucket.version(), 4);
+        assert_eq!(*bucket.contents.rows.read().unwrap(), rows);
+        assert_eq!(bucket.bloom().unwrap(), bloom);
+
+        let bloom = BloomFilter::from_bytes(&bloom).unwrap();
+        assert!(bloom.contains("OR"));
+        assert!(bloom.contains("WA"));
+        assert!(!bloom.contains("CA"));
+
+        bucket.destroy().unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn bucket_does_not_log_rejected_mutations() {
+        let params = get_params();
+        let dir = temp_dir();
+        let config = BucketConfig::default();
+
+        let bucket = Bucket::create(
+            params.clone(),
+            "",
+            get_metadata("b"),
+            Some(dir.clone()),
+            &config,
+        )
+        .unwrap();
+        let mut value = vec![0u8; 2 * params.db_item_size];
+        rand::thread_rng().fill_bytes(&mut value);
+        assert!(matches!(
+            bucket.write(vec![kv("CA", &value)]),
+            Err(Error::TooLarge(..))
+        ));
+        assert!(matches!(
+            bucket.update_rows(&[0, 0, 0, 9]),
+            Err(Error::InvalidLength(..))
+        ));
+        let wal = &bucket.storage.as_ref().unwrap().wal;
+        assert_eq!(wal.records_since_checkpoint().unwrap(), 0);
+        assert_eq!(bucket.version(), 0);
+
+        let seq = bucket.write(vec![kv("CA", b"California")]).unwrap();
+        assert_eq!(seq, Some(1));
+        bucket.sync(seq).unwrap();
+        drop(bucket);
+
+        let bucket = Bucket::open(params.clone(), "", dir.clone(), &config).unwrap();
+        assert_eq!(bucket.version(), 1);
+        bucket.destroy().unwrap();
+    }
+
+    #[test]
+    fn bucket_rejects_mutations_after_destroy() {
+        let params = get_params();
+        let dir = temp_dir();
+        let config = BucketConfig::default();
+
+        let bucket = Bucket::create(
+            params.clone(),
+            "",
+            get_metadata("b"),
+            Some(dir.clone()),
+            &config,
+        )
+        .unwrap();
+        bucket.destroy().unwrap();
+        assert!(matches!(
+            bucket.write(vec![kv("CA", b"California")]),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(bucket.clear(), Err(Error::NotFound)));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn bucket_rejects_bad_metadata() {
+        let params = get_params();
+        let config = BucketConfig::default();
+        assert!(Bucket::create(params.clone(), "", get_metadata("a/b"), None, &config).is_err());
+
+        let mut metadata = get_metadata("a");
+        metadata.parameters.max_item_size = params.db_item_size + 1;
+        assert!(Bucket::create(params.clone(), "", metadata, None, &config).is_err());
+    }
+
+    #[test]
+    fn bucket_rejects_bad_requests() {
+        let params = get_params();
+        let bucket = Bucket::create(
+            params.clone(),
+            "",
+            get_metadata("b"),
+            None,
+            &BucketConfig::default(),
+        )
+        .unwrap();
+        bucket.write(vec![kv("CA", b"California")]).unwrap();
+        let rows = bucket.contents.rows.read().unwrap().clone();
+
+        let mut state = 1u32;
+        let random: Vec<u8> = (0..40000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let result = bucket.write(vec![kv("OR", b"Oregon"), kv("big", &random)]);
+        assert!(matches!(result, Err(Error::TooLarge(..))));
+        assert!(matches!(
+            bucket.update_rows(&[0, 0, 0, 6, 0, 0, 0, 1, 1, 2, 0, 0]),
+            Err(Error::InvalidLength(..))
+        ));
+        assert!(matches!(
+            bucket.update_rows(&[0, 0, 0, 5, 255, 255, 255, 255, 1]),
+            Err(Error::InvalidRequest(_))
+        ));
+        assert_eq!(*bucket.contents.rows.read().unwrap(), rows);
+        assert_eq!(bucket.version(), 1);
+
+        assert!(matches!(
+            bucket.setup(&[0; 10]),
+            Err(Error::InvalidLength(..))
+        ));
+        assert!(matches!(
+            bucket.private_read(&[0; 10]),
+            Err(Error::InvalidLength(..))
+        ));
+        let unknown_uuid = vec![b'a'; UUID_V4_STR_BYTES + params.query_bytes()];
+        assert!(matches!(
+            bucket.private_read(&unknown_uuid),
+            Err(Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn concurrent_bad_reads_fail_alone() {
+        let params = get_params();
+        let config = BucketConfig {
+            read_batch_window: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let bucket =
+            Arc::new(Bucket::create(params.clone(), "", get_metadata("b"), None, &config).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let bucket = bucket.clone();
+                let params = params.clone();
+                thread::spawn(move || {
+                    let queries = if i % 2 == 0 {
+                        Vec::new()
+                    } else {
+                        vec![
+                            vec![b'a'; UUID_V4_STR_BYTES + params.query_bytes()],
+                            vec![0; i],
+                        ]
+                    };
+                    (i, bucket.private_reads(queries))
+                })
+            })
+            .collect();
+        for handle in handles {
+            let (i, result) = handle.join().unwrap();
+            if i % 2 == 0 {
+                assert!(result.unwrap().is_empty());
+            } else {
+                assert!(matches!(result, Err(Error::NotFound)));
+            }
+        }
+        assert!(bucket.reads.lock().unwrap().answered.is_empty());
+    }
+
+    /// Reads item `idx` of `bucket` privately.
+    fn read_item(bucket: &Bucket, idx: usize) -> Vec<u8> {
+        let mut client = Client::init(bucket.params());
+        let uuid = bucket.setup(&client.generate_keys().serialize()).unwrap();
+        let mut request = uuid.into_bytes();
+        request.extend(client.generate_query(idx).serialize());
+        client.decode_response(&bucket.private_read(&request).unwrap())
+    }
+
+    #[test]
+    fn built_bucket_is_correct() {
+        let params = get_small_params();
+        let dir = temp_dir();
+        let kv_pairs = vec![kv("CA", b"California"), kv("OR", b"Oregon")];
+        let config = BucketConfig::default();
+
+        let bucket = Bucket::build(
+            params.clone(),
+            "",
+            get_metadata("b"),
+            kv_pairs.clone(),
+            dir.join("bucket"),
+            &config,
+        )
+        .unwrap();
+        bucket
+            .write_preprocessed_db(&dir.join("db.preprocessed"))
+            .unwrap();
+        let static_bucket = Bucket::open_static(
+            params.clone(),
+            "",
+            get_metadata("s"),
+            &dir.join("db.preprocessed"),
+            &config,
+        )
+        .unwrap();
+
+        let written = Bucket::create(params.clone(), "", get_metadata("w"), None, &config).unwrap();
+        written.write(kv_pairs).unwrap();
+        let rows = written.contents.rows.read().unwrap().clone();
+        drop(bucket);
+        let reopened = Bucket::open(params.clone(), "", dir.join("bucket"), &config).unwrap();
+        assert_eq!(*reopened.contents.rows.read().unwrap(), rows);
+        assert_eq!(reopened.version(), 1);
+
+        let idx = rows.iter().position(|row| !row.is_empty()).unwrap();
+        let item = read_item(&written, idx);
+        assert!(item.iter().any(|&x| x != 0));
+        assert_eq!(read_item(&reopened, idx), item);
+        assert_eq!(read_item(&static_bucket, idx), item);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn static_bucket_is_correct() {
+        let params = get_small_params();
+        let dir = temp_dir();
+        let db_path = dir.join("db.preprocessed");
+
+        let target_idx = 77;
+        let (corr_item, db) = generate_random_db_and_get_item(&params, target_idx);
+        let db_bytes: Vec<u8> = db.as_slice().iter().flat_map(|x| x.to_ne_bytes()).collect();
+        fs::write(&db_path, &db_bytes[8..]).unwrap();
+        let config = BucketConfig::default();
+        assert!(matches!(
+            Bucket::open_static(params.clone(), "", get_metadata("s"), &db_path, &config),
+            Err(Error::Corrupted(_))
+        ));
+        fs::write(&db_path, &db_bytes).unwrap();
+        let config = BucketConfig {
+            static_db_storage: DenseDbStorage::Mapped(DbAdvice::Sequential),
+            ..Default::default()
+        };
+        let bucket =
+            Bucket::open_static(params.clone(), "", get_metadata("s"), &db_path, &config).unwrap();
+        assert!(bucket.is_static());
+
+        let mut client = Client::init(&params);
+        let uuid = bucket.setup(&client.generate_keys().serialize()).unwrap();
+        let mut request = uuid.into_bytes();
+        request.extend(client.generate_query(target_idx).serialize());
+        let responses = bucket
+            .private_reads(vec![request.clone(), request])
+            .unwrap();
+        let p_bits = log2_ceil(params.pt_modulus) as usize;
+        let corr_result = corr_item.to_vec(p_bits, params.modp_words_per_chunk());
+        for response in responses {
+            assert_eq!(client.decode_response(&response), corr_result);
+        }
+
+        assert!(matches!(
+            bucket.write(vec![kv("CA", b"California")]),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(bucket.clear(), Err(Error::ReadOnly)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn params_from_scheme_rejects_bad_schemes() {
+        let scheme = serde_json::json!({
+            "n": 2, "nu_1": 9, "nu_2": 5, "p": 256, "q2_bits": 22,
+            "t_gsw": 7, "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5,
+        });
+        let params = params_from_scheme(&scheme).unwrap();
+        assert_eq!(params.num_items(), 1 << 14);
+
+        let mut scheme_moduli = scheme.clone();
+        scheme_moduli["moduli"] = serde_json::json!([1191937, 1196033, 1253377]);
+        assert!(params_from_scheme(&scheme_moduli).is_err());
+
+        let mut scheme_q1 = scheme.clone();
+        scheme_q1["q1"] = serde_json::json!(2048);
+        assert_eq!(params_from_scheme(&scheme_q1).unwrap().q1, 2048);
+
+        for (field, value) in [
+            ("n", serde_json::json!(0)),
+            ("nu_2", serde_json::json!(20)),
+            ("p", serde_json::json!(255)),
+            ("t_gsw", serde_json::json!("7")),
+            ("db_item_size", serde_json::json!(16384)),
+            ("poly_len", serde_json::json!(8192)),
+            ("moduli", serde_json::json!([268369921, 268369923])),
+            ("q1", serde_json::json!(1000)),
+            // a multiple of p, but too close to 2p to decode reliably
+            ("q1", serde_json::json!(768)),
+        ] {
+            let mut bad_scheme = scheme.clone();
+            bad_scheme[field] = value;
+            assert!(params_from_scheme(&bad_scheme).is_err());
+        }
+        let mut bad_scheme = scheme;
+        bad_scheme.as_object_mut().unwrap().remove("q2_bits");
+        assert!(params_from_scheme(&bad_scheme).is_err());
+    }
+}
@@ -1,7 +1,9 @@
-#[cfg(target_feature = "avx2")]
+#[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
+use std::sync::OnceLock;
 
 use spiral_rs::arith::*;
+use spiral_rs::kernels::{kernels, SimdLevel};
 use spiral_rs::params::*;
 use spiral_rs::poly::*;
 
@@ -10,130 +12,185 @@ use crate::db::sparse_db::*;
 pub const MAX_SUMMED: usize = 1 << 6;
 pub const PACKED_OFFSET_2: i32 = 32;
 
-#[cfg(target_feature = "avx2")]
-pub fn multiply_reg_by_sparse_database(
-    out: &mut Vec<PolyMatrixNTT>,
-    db: &SparseDb,
+/// Accumulates the product of one query ciphertext (`query`, two rows of
+/// packed 2-limb coefficients) and one packed database polynomial (`b_poly`)
+/// into `out`, laid out as `[c1_lo, c1_hi, c2_lo, c2_hi]` rows of `poly_len`.
+/// Nothing is reduced.
+pub type SparseKernel = fn(usize, &mut [u64], &[u64], &[u64]);
+
+/// The `SparseKernel` for `level`. Panics if this CPU cannot run `level`.
+pub fn sparse_kernel_for(level: SimdLevel) -> SparseKernel {
+    assert!(
+        level <= SimdLevel::supported(),
+        "{} kernels are not supported on this CPU",
+        level.name()
+    );
+    match level {
+        SimdLevel::Scalar => accumulate_packed_scalar,
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => accumulate_packed_avx2,
+        #[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+        SimdLevel::Avx512 => accumulate_packed_avx512,
+        // spiral-rs was built with AVX-512 but this crate was not.
+        #[cfg(all(target_arch = "x86_64", not(feature = "avx512")))]
+        SimdLevel::Avx512 => accumulate_packed_avx2,
+        #[allow(unreachable_patterns)]
+        _ => unreachable!(),
+    }
+}
+
+/// The `SparseKernel` for the level spiral-rs dispatches to.
+pub fn sparse_kernel() -> SparseKernel {
+    static KERNEL: OnceLock<SparseKernel> = OnceLock::new();
+    *KERNEL.get_or_init(|| sparse_kernel_for(kernels().level))
+}
+
+fn accumulate_packed_scalar(poly_len: usize, out: &mut [u64], query: &[u64], b_poly: &[u64]) {
+    let lo_mask = (1 << PACKED_OFFSET_2) - 1;
+
+    let (part_0, part_1) = out.split_at_mut(2 * poly_len);
+    let (out_0, out_1) = part_0.split_at_mut(poly_len);
+    let (out_2, out_3) = part_1.split_at_mut(poly_len);
+
+    for z in 0..poly_len {
+        let a1 = query[z];
+        let a2 = query[poly_len + z];
+        let b = b_poly[z];
+
+        let a1_lo = a1 & lo_mask;
+        let a1_hi = a1 >> PACKED_OFFSET_2;
+        let a2_lo = a2 & lo_mask;
+        let a2_hi = a2 >> PACKED_OFFSET_2;
+        let b_lo = b & lo_mask;
+        let b_hi = b >> PACKED_OFFSET_2;
+
+        out_0[z] += a1_lo * b_lo;
+        out_1[z] += a1_hi * b_hi;
+        out_2[z] += a2_lo * b_lo;
+        out_3[z] += a2_hi * b_hi;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn accumulate_packed_avx2(poly_len: usize, out: &mut [u64], query: &[u64], b_poly: &[u64]) {
+    // Only handed out by `sparse_kernel_for` when the CPU supports AVX2.
+    unsafe { accumulate_packed_avx2_impl(poly_len, out, query, b_poly) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn accumulate_packed_avx2_impl(
+    poly_len: usize,
+    out: &mut [u64],
     query: &[u64],
-    params: &Params,
-    dim0: usize,
-    num_per: usize,
-    db_idx: usize,
+    b_poly: &[u64],
 ) {
-    //    db:  [inst_trials, num_per, dim0, poly_len]
-    // query:  [dim0, ct_rows, poly_len]
+    assert!(out.len() >= 4 * poly_len && query.len() >= 2 * poly_len && b_poly.len() >= poly_len);
+    let out = out.as_mut_ptr();
+    let query = query.as_ptr();
+    let b_poly = b_poly.as_ptr();
+
+    for z in (0..poly_len).step_by(4) {
+        let a1 = _mm256_loadu_si256(query.add(z) as *const __m256i);
+        let a2 = _mm256_loadu_si256(query.add(poly_len + z) as *const __m256i);
+        let b = _mm256_loadu_si256(b_poly.add(z) as *const __m256i);
+
+        let a1_lo = a1;
+        let a1_hi = _mm256_srli_epi64(a1, PACKED_OFFSET_2);
+        let a2_lo = a2;
+        let a2_hi = _mm256_srli_epi64(a2, PACKED_OFFSET_2);
+        let b_lo = b;
+        let b_hi = _mm256_srli_epi64(b, PACKED_OFFSET_2);
+
+        let c1_lo_loc = out.add(z) as *mut __m256i;
+        let c1_hi_loc = out.add(poly_len + z) as *mut __m256i;
+        let c2_lo_loc = out.add(2 * poly_len + z) as *mut __m256i;
+        let c2_hi_loc = out.add(3 * poly_len + z) as *mut __m256i;
+
+        let mut c1_lo = _mm256_loadu_si256(c1_lo_loc);
+        let mut c1_hi = _mm256_loadu_si256(c1_hi_loc);
+        let mut c2_lo = _mm256_loadu_si256(c2_lo_loc);
+        let mut c2_hi = _mm256_loadu_si256(c2_hi_loc);
+
+        c1_lo = _mm256_add_epi64(c1_lo, _mm256_mul_epu32(a1_lo, b_lo));
+        c1_hi = _mm256_add_epi64(c1_hi, _mm256_mul_epu32(a1_hi, b_hi));
+        c2_lo = _mm256_add_epi64(c2_lo, _mm256_mul_epu32(a2_lo, b_lo));
+        c2_hi = _mm256_add_epi64(c2_hi, _mm256_mul_epu32(a2_hi, b_hi));
+
+        _mm256_storeu_si256(c1_lo_loc, c1_lo);
+        _mm256_storeu_si256(c1_hi_loc, c1_hi);
+        _mm256_storeu_si256(c2_lo_loc, c2_lo);
+        _mm256_storeu_si256(c2_hi_loc, c2_hi);
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+fn accumulate_packed_avx512(poly_len: usize, out: &mut [u64], query: &[u64], b_poly: &[u64]) {
+    // Only handed out by `sparse_kernel_for` when the CPU supports AVX-512F.
+    unsafe { accumulate_packed_avx512_impl(poly_len, out, query, b_poly) }
+}
 
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+#[allow(clippy::incompatible_msrv)] // the `avx512` feature needs Rust 1.89
+unsafe fn accumulate_packed_avx512_impl(
+    poly_len: usize,
+    out: &mut [u64],
+    query: &[u64],
+    b_poly: &[u64],
+) {
+    assert!(out.len() >= 4 * poly_len && query.len() >= 2 * poly_len && b_poly.len() >= poly_len);
+    let out = out.as_mut_ptr() as *mut i64;
+    let query = query.as_ptr() as *const i64;
+    let b_poly = b_poly.as_ptr() as *const i64;
+
+    for z in (0..poly_len).step_by(8) {
+        let a1 = _mm512_loadu_epi64(query.add(z));
+        let a2 = _mm512_loadu_epi64(query.add(poly_len + z));
+        let b = _mm512_loadu_epi64(b_poly.add(z));
+
+        let a1_hi = _mm512_srli_epi64(a1, PACKED_OFFSET_2 as u32);
+        let a2_hi = _mm512_srli_epi64(a2, PACKED_OFFSET_2 as u32);
+        let b_hi = _mm512_srli_epi64(b, PACKED_OFFSET_2 as u32);
+
+        let c1_lo_loc = out.add(z);
+        let c1_hi_loc = out.add(poly_len + z);
+        let c2_lo_loc = out.add(2 * poly_len + z);
+        let c2_hi_loc = out.add(3 * poly_len + z);
+
+        let c1_lo = _mm512_add_epi64(_mm512_loadu_epi64(c1_lo_loc), _mm512_mul_epu32(a1, b));
+        let c1_hi = _mm512_add_epi64(_mm512_loadu_epi64(c1_hi_loc), _mm512_mul_epu32(a1_hi, b_hi));
+        let c2_lo = _mm512_add_epi64(_mm512_loadu_epi64(c2_lo_loc), _mm512_mul_epu32(a2, b));
+        let c2_hi = _mm512_add_epi64(_mm512_loadu_epi64(c2_hi_loc), _mm512_mul_epu32(a2_hi, b_hi));
+
+        _mm512_storeu_epi64(c1_lo_loc, c1_lo);
+        _mm512_storeu_epi64(c1_hi_loc, c1_hi);
+        _mm512_storeu_epi64(c2_lo_loc, c2_lo);
+        _mm512_storeu_epi64(c2_hi_loc, c2_hi);
+    }
+}
+
+/// Reduces both rows of every output ciphertext, limb by limb.
+fn reduce_outputs(out: &mut [PolyMatrixNTT], params: &Params, num_per: usize) {
     let poly_len = params.poly_len;
     let crt_count = params.crt_count;
-    assert_eq!(crt_count, 2);
-
-    let mut adds = 0;
-
-    unsafe {
-        for j in 0..dim0 {
-            for i in 0..num_per {
-                let full_idx = db_idx * (dim0 * num_per) + j * num_per + i;
-                let result = db.get_idx(full_idx);
-                // if (j * num_per) + i == 14706 {
-                //     println!("sparsedb at 14706 (full_idx: {}) is {:?}", full_idx, result);
-                //     println!("moduli {:?} qmod {}", params.moduli, params.modulus);
-                // }
-                if result.is_none() {
-                    continue;
-                }
-                let real_idx = *result.unwrap();
-                // println!("doing full_idx: {}", full_idx);
-                // println!("at real_idx: {}", real_idx);
-
-                let b_poly = db.data[real_idx].as_slice();
-                // if (j * num_per) + i == 14706 {
-                //     println!(
-                //         "sparsedb at 14706 (full_idx: {}) contains data {} {} ...",
-                //         full_idx, b_poly[0], b_poly[1]
-                //     );
-                //     // println!(
-                //     //     "sparsedb at 14706 (full_idx: {}) contains data {:?}",
-                //     //     full_idx, b_poly
-                //     // );
-                // }
-                for z in (0..poly_len).step_by(4) {
-                    let v_a1 = query.get_unchecked((j * 2) * poly_len + z) as *const u64;
-                    let v_a2 = query.get_unchecked((j * 2 + 1) * poly_len + z) as *const u64;
-                    let v_b = b_poly.get_unchecked(z) as *const u64;
-
-                    let a1 = _mm256_load_si256(v_a1 as *const __m256i);
-                    let a2 = _mm256_load_si256(v_a2 as *const __m256i);
-                    let b = _mm256_load_si256(v_b as *const __m256i);
-
-                    let a1_lo = a1;
-                    let a1_hi = _mm256_srli_epi64(a1, PACKED_OFFSET_2);
-                    let a2_lo = a2;
-                    let a2_hi = _mm256_srli_epi64(a2, PACKED_OFFSET_2);
-                    let b_lo = b;
-                    let b_hi = _mm256_srli_epi64(b, PACKED_OFFSET_2);
-
-                    let c1_lo_loc = ((&mut out[i].data[z]) as *mut u64) as *mut __m256i;
-                    let c1_hi_loc = ((&mut out[i].data[poly_len + z]) as *mut u64) as *mut __m256i;
-                    let c2_lo_loc =
-                        ((&mut out[i].data[2 * poly_len + z]) as *mut u64) as *mut __m256i;
-                    let c2_hi_loc =
-                        ((&mut out[i].data[3 * poly_len + z]) as *mut u64) as *mut __m256i;
-
-                    let mut c1_lo = _mm256_load_si256(c1_lo_loc);
-                    let mut c1_hi = _mm256_load_si256(c1_hi_loc);
-                    let mut c2_lo = _mm256_load_si256(c2_lo_loc);
-                    let mut c2_hi = _mm256_load_si256(c2_hi_loc);
-
-                    c1_lo = _mm256_add_epi64(c1_lo, _mm256_mul_epu32(a1_lo, b_lo));
-                    c1_hi = _mm256_add_epi64(c1_hi, _mm256_mul_epu32(a1_hi, b_hi));
-                    c2_lo = _mm256_add_epi64(c2_lo, _mm256_mul_epu32(a2_lo, b_lo));
-                    c2_hi = _mm256_add_epi64(c2_hi, _mm256_mul_epu32(a2_hi, b_hi));
-
-                    _mm256_store_si256(c1_lo_loc, c1_lo);
-                    _mm256_store_si256(c1_hi_loc, c1_hi);
-                    _mm256_store_si256(c2_lo_loc, c2_lo);
-                    _mm256_store_si256(c2_hi_loc, c2_hi);
-
-                    adds += 1;
-
-                    if adds >= MAX_SUMMED {
-                        adds = 0;
-                        for z_in in 0..4 {
-                            out[i].data[z + z_in] =
-                                barrett_coeff_u64(params, out[i].data[z + z_in], 0);
-                            out[i].data[poly_len + z + z_in] =
-                                barrett_coeff_u64(params, out[i].data[poly_len + z + z_in], 1);
-                            out[i].data[2 * poly_len + z + z_in] =
-                                barrett_coeff_u64(params, out[i].data[2 * poly_len + z + z_in], 0);
-                            out[i].data[3 * poly_len + z + z_in] =
-                                barrett_coeff_u64(params, out[i].data[3 * poly_len + z + z_in], 1);
-                        }
-                        // *c1_lo_loc = barrett_coeff_u64(params, *c1_lo_loc, 0);
-                        // *c1_hi_loc = barrett_coeff_u64(params, *c1_hi_loc, 0);
-                        // *c2_lo_loc = barrett_coeff_u64(params, *c2_lo_loc, 1);
-                        // *c2_hi_loc = barrett_coeff_u64(params, *c2_hi_loc, 1);
-                    }
-                }
-            }
-        }
-
-        for i in 0..num_per {
-            for z in (0..poly_len).step_by(4) {
-                for z_in in 0..4 {
-                    out[i].data[z + z_in] = barrett_coeff_u64(params, out[i].data[z + z_in], 0);
-                    out[i].data[poly_len + z + z_in] =
-                        barrett_coeff_u64(params, out[i].data[poly_len + z + z_in], 1);
-                    out[i].data[2 * poly_len + z + z_in] =
-                        barrett_coeff_u64(params, out[i].data[2 * poly_len + z + z_in], 0);
-                    out[i].data[3 * poly_len + z + z_in] =
-                        barrett_coeff_u64(params, out[i].data[3 * poly_len + z + z_in], 1);
+    for out_i in out.iter_mut().take(num_per) {
+        for r in 0..2 {
+            for n in 0..crt_count {
+                let start = (r * crt_count + n) * poly_len;
+                for x in &mut out_i.data.as_mut_slice()[start..start + poly_len] {
+                    *x = barrett_coeff_u64(params, *x, n);
                 }
             }
         }
     }
 }
 
-#[cfg(not(target_feature = "avx2"))]
+/// How many `dim0` steps can be accumulated between reductions.
+fn max_summed(params: &Params) -> usize {
+    max_lazy_products(params).clamp(1, MAX_SUMMED)
+}
+
 pub fn multiply_reg_by_sparse_database(
     out: &mut Vec<PolyMatrixNTT>,
     db: &SparseDb,
@@ -142,84 +199,64 @@ pub fn multiply_reg_by_sparse_database(
     dim0: usize,
     num_per: usize,
     db_idx: usize,
+) {
+    multiply_reg_by_sparse_database_many(
+        std::slice::from_mut(out),
+        db,
+        &[query],
+        params,
+        dim0,
+        num_per,
+        db_idx,
+    );
+}
+
+/// `multiply_reg_by_sparse_database` for several queries at once: `out[q]`
+/// receives the product with `queries[q]`. Each database polynomial is read
+/// once for all of them, so the pass costs little more memory bandwidth than
+/// a single query.
+pub fn multiply_reg_by_sparse_database_many(
+    out: &mut [Vec<PolyMatrixNTT>],
+    db: &SparseDb,
+    queries: &[&[u64]],
+    params: &Params,
+    dim0: usize,
+    num_per: usize,
+    db_idx: usize,
 ) {
     //    db:  [inst_trials, num_per, dim0, poly_len]
     // query:  [dim0, ct_rows, poly_len]
+    assert_eq!(out.len(), queries.len());
+    assert_eq!(params.crt_count, 2);
 
+    let kernel = sparse_kernel();
     let poly_len = params.poly_len;
-    let crt_count = params.crt_count;
-    assert_eq!(crt_count, 2);
-
-    let lo_mask = (1 << PACKED_OFFSET_2) - 1;
+    let max_summed = max_summed(params);
 
     for j in 0..dim0 {
-        let mut adds = 0;
-
         for i in 0..num_per {
-            let (part_0, part_1) = out[i].data.as_mut_slice().split_at_mut(2 * poly_len);
-            let (out_0, out_1) = part_0.split_at_mut(poly_len);
-            let (out_2, out_3) = part_1.split_at_mut(poly_len);
-
             let full_idx = db_idx * (dim0 * num_per) + j * num_per + i;
-            let result = db.get_idx(full_idx);
-            if result.is_none() {
+            let Some(&real_idx) = db.get_idx(full_idx) else {
                 continue;
-            }
-            let real_idx = *result.unwrap();
-
+            };
             let b_poly = db.data[real_idx].as_slice();
-
-            for z in 0..poly_len {
-                let a1 = query[(j * 2) * poly_len + z];
-                let a2 = query[(j * 2 + 1) * poly_len + z];
-                let b = b_poly[z];
-
-                let a1_lo = (a1 & lo_mask) as u32;
-                let a1_hi = (a1 >> PACKED_OFFSET_2) as u32;
-                let a2_lo = (a2 & lo_mask) as u32;
-                let a2_hi = (a2 >> PACKED_OFFSET_2) as u32;
-                let b_lo = (b & lo_mask) as u32;
-                let b_hi = (b >> PACKED_OFFSET_2) as u32;
-
-                let c1_lo_loc = &mut out_0[z];
-                let c1_hi_loc = &mut out_1[z];
-                let c2_lo_loc = &mut out_2[z];
-                let c2_hi_loc = &mut out_3[z];
-
-                *c1_lo_loc += (a1_lo as u64) * (b_lo as u64);
-                *c1_hi_loc += (a1_hi as u64) * (b_hi as u64);
-                *c2_lo_loc += (a2_lo as u64) * (b_lo as u64);
-                *c2_hi_loc += (a2_hi as u64) * (b_hi as u64);
-
-                if adds >= MAX_SUMMED {
-                    *c1_lo_loc = barrett_coeff_u64(params, *c1_lo_loc, 0);
-                    *c1_hi_loc = barrett_coeff_u64(params, *c1_hi_loc, 1);
-                    *c2_lo_loc = barrett_coeff_u64(params, *c2_lo_loc, 0);
-                    *c2_hi_loc = barrett_coeff_u64(params, *c2_hi_loc, 1);
-                }
+            for (out_q, query) in out.iter_mut().zip(queries) {
+                let query_j = &query[(j * 2) * poly_len..(j * 2 + 2) * poly_len];
+                kernel(poly_len, out_q[i].data.as_mut_slice(), query_j, b_poly);
             }
+        }
 
-            adds += 1;
-
-            if adds >= MAX_SUMMED {
-                adds = 0;
+        if (j + 1) % max_summed == 0 {
+            for out_q in out.iter_mut() {
+                reduce_outputs(out_q, params, num_per);
             }
         }
     }
-
-    for i in 0..num_per {
-        for z in 0..poly_len {
-            out[i].data[z] = barrett_coeff_u64(params, out[i].data[z], 0);
-            out[i].data[poly_len + z] = barrett_coeff_u64(params, out[i].data[poly_len + z], 1);
-            out[i].data[2 * poly_len + z] =
-                barrett_coeff_u64(params, out[i].data[2 * poly_len + z], 0);
-            out[i].data[3 * poly_len + z] =
-                barrett_coeff_u64(params, out[i].data[3 * poly_len + z], 1);
-        }
+    for out_q in out.iter_mut() {
+        reduce_outputs(out_q, params, num_per);
     }
 }
 
-#[cfg(not(target_feature = "avx2"))]
 pub fn multiply_reg_by_database(
     out: &mut Vec<PolyMatrixNTT>,
     db: &[u64],
@@ -228,6 +265,8 @@ pub fn multiply_reg_by_database(
     dim0: usize,
     num_per: usize,
 ) {
+    assert_eq!(params.crt_count, 2);
+
     let ct_rows = 2;
     let ct_cols = 1;
     let pt_rows = 1;
@@ -288,6 +327,55 @@ pub fn multiply_reg_by_database(
     }
 }
 
+/// `multiply_reg_by_database` for several queries at once: `out[q]`
+/// receives the product with `queries[q]`. Each database word is read once
+/// for all of them, as in `multiply_reg_by_sparse_database_many`.
+pub fn multiply_reg_by_database_many(
+    out: &mut [Vec<PolyMatrixNTT>],
+    db: &[u64],
+    queries: &[&[u64]],
+    params: &Params,
+    dim0: usize,
+    num_per: usize,
+) {
+    //    db:  [poly_len, num_per, dim0]
+    // query:  [poly_len, dim0, ct_rows]
+    assert_eq!(out.len(), queries.len());
+    assert_eq!(params.crt_count, 2);
+
+    let poly_len = params.poly_len;
+    let (modulus_0, modulus_1) = (params.moduli[0] as u128, params.moduli[1] as u128);
+    // [row 0 limb 0, row 1 limb 0, row 0 limb 1, row 1 limb 1] per query
+    let mut sums = vec![[0u128; 4]; queries.len()];
+    for z in 0..poly_len {
+        let idx_a_base = z * (dim0 * 2);
+        for i in 0..num_per {
+            let idx_b_base = z * (num_per * dim0) + i * dim0;
+            sums.fill([0; 4]);
+            for jm in 0..dim0 {
+                let b = db[idx_b_base + jm];
+                let (b_lo, b_hi) = (b as u32 as u64, b >> 32);
+                for (sums_q, query) in sums.iter_mut().zip(queries) {
+                    let v_a0 = query[idx_a_base + jm * 2];
+                    let v_a1 = query[idx_a_base + jm * 2 + 1];
+                    sums_q[0] += ((v_a0 as u32 as u64) * b_lo) as u128;
+                    sums_q[1] += ((v_a1 as u32 as u64) * b_lo) as u128;
+                    sums_q[2] += ((v_a0 >> 32) * b_hi) as u128;
+                    sums_q[3] += ((v_a1 >> 32) * b_hi) as u128;
+                }
+            }
+
+            for (out_q, sums_q) in out.iter_mut().zip(&sums) {
+                let data = out_q[i].data.as_mut_slice();
+                data[z] = (sums_q[0] % modulus_0) as u64;
+                data[2 * poly_len + z] = (sums_q[1] % modulus_0) as u64;
+                data[poly_len + z] = (sums_q[2] % modulus_1) as u64;
+                data[3 * poly_len + z] = (sums_q[3] % modulus_1) as u64;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::time::Instant;
@@ -378,9 +466,7 @@ mod test {
         println!("Mul took {} us", start.elapsed().as_micros())
     }
 
-    #[test]
-    fn multiply_reg_by_sparse_database_is_correct() {
-        let cfg = r#"
+    const SPARSE_TEST_CFG: &str = r#"
             {'n': 4,
             'nu_1': 9,
             'nu_2': 5,
@@ -393,7 +479,84 @@ mod test {
             'instances': 1,
             'db_item_size': 32768 }
         "#;
-        let params = util::params_from_json(&cfg.replace("'", "\""));
+
+    #[test]
+    fn sparse_kernels_agree_with_scalar() {
+        let params = util::params_from_json(&SPARSE_TEST_CFG.replace("'", "\""));
+        let poly_len = params.poly_len;
+        let mut rng = util::get_seeded_rng();
+        let mut packed = |len: usize| -> Vec<u64> {
+            (0..len)
+                .map(|_| {
+                    let lo = rng.gen::<u64>() % params.moduli[0];
+                    let hi = rng.gen::<u64>() % params.moduli[1];
+                    lo | (hi << PACKED_OFFSET_2)
+                })
+                .collect()
+        };
+        // Offset by one word so the SIMD kernels see unaligned slices.
+        let query = packed(2 * poly_len + 1);
+        let b_poly = packed(poly_len + 1);
+
+        let mut expected = vec![0u64; 4 * poly_len + 1];
+        for _ in 0..3 {
+            sparse_kernel_for(SimdLevel::Scalar)(
+                poly_len,
+                &mut expected[1..],
+                &query[1..],
+                &b_poly[1..],
+            );
+        }
+
+        for level in SimdLevel::available() {
+            let kernel = sparse_kernel_for(level);
+            let mut out = vec![0u64; 4 * poly_len + 1];
+            for _ in 0..3 {
+                kernel(poly_len, &mut out[1..], &query[1..], &b_poly[1..]);
+            }
+            assert_eq!(out, expected, "{}", level.name());
+        }
+    }
+
+    #[test]
+    fn multiply_reg_by_database_many_matches_one_at_a_time() {
+        let params = util::params_from_json(
+            r#"{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 22, "t_gsw": 7,
+            "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5, "instances": 1,
+            "db_item_size": 4096}"#,
+        );
+        let mut rng = ChaCha20Rng::from_entropy();
+        let mut packed = |len: usize| -> Vec<u64> {
+            (0..len)
+                .map(|_| {
+                    let lo = rng.gen::<u64>() % params.moduli[0];
+                    let hi = rng.gen::<u64>() % params.moduli[1];
+                    lo | (hi << 32)
+                })
+                .collect()
+        };
+
+        let dim0 = 1 << params.db_dim_1;
+        let num_per = 1 << params.db_dim_2;
+        let db = packed(dim0 * num_per * params.poly_len);
+        let queries: Vec<Vec<u64>> = (0..3).map(|_| packed(dim0 * 2 * params.poly_len)).collect();
+        let query_slices: Vec<&[u64]> = queries.iter().map(|q| q.as_slice()).collect();
+        let zero = || vec![PolyMatrixNTT::zero(&params, 2, 1); num_per];
+
+        let mut out = vec![zero(); queries.len()];
+        multiply_reg_by_database_many(&mut out, &db, &query_slices, &params, dim0, num_per);
+        for (out_q, query) in out.iter().zip(&queries) {
+            let mut expected = zero();
+            multiply_reg_by_database(&mut expected, &db, query, &params, dim0, num_per);
+            for (a, b) in out_q.iter().zip(&expected) {
+                assert_eq!(a.as_slice(), b.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn multiply_reg_by_sparse_database_is_correct() {
+        let params = util::params_from_json(&SPARSE_TEST_CFG.replace("'", "\""));
 
         let mut seeded_rng = util::get_seeded_rng();
         let mut rng = ChaCha20Rng::from_entropy();
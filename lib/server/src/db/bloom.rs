@@ -0,0 +1,111 @@
+use sha1::{Digest, Sha1};
+
+use crate::error::Error;
+
+const HEADER_BYTES: usize = 8;
+const MAX_BITS: u32 = 32;
+
+/// A Bloom filter over bucket keys, in the format the clients download from
+/// `/bloom`: `[k u32][bits u32][2^bits bits of data]`, little-endian.
+///
+/// Key `key` sets bit `top_bits(sha1(i as u32 || key))` for each `i < k`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    k: u32,
+    bits: u32,
+    data: Vec<u8>,
+}
+
+fn top_be_bits(data: &[u8], bits: u32) -> usize {
+    let mut num = 0;
+    for i in 0..bits as usize {
+        if data[i / 8] & (1 << (7 - (i % 8))) != 0 {
+            num |= 1 << (bits as usize - 1 - i);
+        }
+    }
+    num
+}
+
+impl BloomFilter {
+    pub fn new(k: u32, bits: u32) -> Self {
+        assert!((3..=MAX_BITS).contains(&bits));
+        Self {
+            k,
+            bits,
+            data: vec![0u8; 1 << (bits - 3)],
+        }
+    }
+
+    fn bit_indices<'a>(&'a self, key: &'a str) -> impl Iterator<Item = usize> + 'a {
+        (0..self.k).map(move |i| {
+            let mut hasher = Sha1::new();
+            hasher.update(i.to_le_bytes());
+            hasher.update(key.as_bytes());
+            top_be_bits(&hasher.finalize(), self.bits)
+        })
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        let indices: Vec<_> = self.bit_indices(key).collect();
+        for idx in indices {
+            self.data[idx / 8] |= 1 << (7 - (idx % 8));
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.bit_indices(key)
+            .all(|idx| self.data[idx / 8] & (1 << (7 - (idx % 8))) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_BYTES + self.data.len());
+        out.extend(self.k.to_le_bytes());
+        out.extend(self.bits.to_le_bytes());
+        out.extend(&self.data);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_BYTES {
+            return Err(Error::InvalidLength(data.len(), HEADER_BYTES));
+        }
+        let k = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let bits = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        if !(3..=MAX_BITS).contains(&bits) {
+            return Err(Error::Corrupted(format!("bad bloom filter size {}", bits)));
+        }
+        let expected_len = HEADER_BYTES + (1 << (bits - 3));
+        if data.len() != expected_len {
+            return Err(Error::InvalidLength(data.len(), expected_len));
+        }
+        Ok(Self {
+            k,
+            bits,
+            data: data[HEADER_BYTES..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_is_correct() {
+        let mut filter = BloomFilter::new(8, 16);
+        for i in 0..100 {
+            filter.insert(&format!("key-{}", i));
+        }
+        for i in 0..100 {
+            assert!(filter.contains(&format!("key-{}", i)));
+        }
+        let false_positives = (0..1000)
+            .filter(|i| filter.contains(&format!("other-{}", i)))
+            .count();
+        assert!(false_positives < 10);
+
+        let decoded = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert_eq!(decoded, filter);
+        assert!(BloomFilter::from_bytes(&filter.to_bytes()[..100]).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::size_of;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+use memmap2::{Mmap, MmapOptions};
+use spiral_rs::params::Params;
+
+use super::aligned_memory::AlignedMemory64;
+use crate::error::Error;
+
+/// An `madvise` hint for a memory-mapped database. `Normal` is the kernel's
+/// default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbAdvice {
+    Normal,
+    /// Read ahead aggressively, since each query scans the whole database.
+    Sequential,
+    /// Don't read ahead.
+    Random,
+    /// Start paging the whole database in now.
+    WillNeed,
+}
+
+impl FromStr for DbAdvice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(DbAdvice::Normal),
+            "sequential" => Ok(DbAdvice::Sequential),
+            "random" => Ok(DbAdvice::Random),
+            "willneed" => Ok(DbAdvice::WillNeed),
+            _ => Err(Error::InvalidParams(format!("unknown madvise hint {}", s))),
+        }
+    }
+}
+
+/// How a static bucket holds its preprocessed database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DenseDbStorage {
+    /// Read the file into memory when the bucket is opened.
+    #[default]
+    Memory,
+    /// Map the file, so that pages are read when first used and can be
+    /// evicted again; the database may then be larger than physical memory.
+    Mapped(DbAdvice),
+}
+
+/// A preprocessed database, in the layout `multiply_reg_by_database` reads.
+pub enum DenseDb {
+    Memory(AlignedMemory64),
+    Mapped(Mmap),
+}
+
+impl DenseDb {
+    /// The size, in words, of a preprocessed database for `params`.
+    pub fn size_words(params: &Params) -> usize {
+        params.num_items() * params.instances * params.n * params.n * params.poly_len
+    }
+
+    /// The size, in words, of each instance's slice of a preprocessed
+    /// database; the slices are stored in order.
+    pub fn instance_words(params: &Params) -> usize {
+        Self::size_words(params) / params.instances
+    }
+
+    /// Opens the preprocessed database at `path`, which must be exactly the
+    /// size `params` gives.
+    pub fn open(params: &Params, path: &Path, storage: DenseDbStorage) -> Result<Self, Error> {
+        Self::open_instances(params, path, storage, 0..params.instances)
+    }
+
+    /// Opens only the slices of `instances` of the preprocessed database at
+    /// `path`, for a worker serving part of a sharded bucket.
+    pub fn open_instances(
+        params: &Params,
+        path: &Path,
+        storage: DenseDbStorage,
+        instances: Range<usize>,
+    ) -> Result<Self, Error> {
+        if instances.is_empty() || instances.end > params.instances {
+            return Err(Error::InvalidParams(format!(
+                "instances {}..{} are not within the {} of the scheme",
+                instances.start, instances.end, params.instances
+            )));
+        }
+        let mut file = File::open(path)?;
+        let expected_len = Self::size_words(params) * size_of::<u64>();
+        let len = file.metadata()?.len() as usize;
+        if len != expected_len {
+            return Err(Error::Corrupted(format!(
+                "preprocessed database {} is {} bytes, expected {}",
+                path.display(),
+                len,
+                expected_len
+            )));
+        }
+
+        let words = Self::instance_words(params) * instances.len();
+        let offset = Self::instance_words(params) * instances.start * size_of::<u64>();
+        match storage {
+            DenseDbStorage::Memory => {
+                let mut v = AlignedMemory64::new(words);
+                file.seek(SeekFrom::Start(offset as u64))?;
+                // Safety: any bytes are valid words, and u8 needs no alignment
+                let (_, bytes, _) = unsafe { v.as_mut_slice().align_to_mut::<u8>() };
+                file.read_exact(bytes)?;
+                Ok(DenseDb::Memory(v))
+            }
+            DenseDbStorage::Mapped(advice) => {
+                // Safety: the file must not be modified while it is mapped.
+                // Each instance's slice is a multiple of poly_len * 8 bytes,
+                // so a map starting at one stays aligned for the kernels.
+                let map = unsafe {
+                    MmapOptions::new()
+                        .offset(offset as u64)
+                        .len(words * size_of::<u64>())
+                        .map(&file)?
+                };
+                #[cfg(unix)]
+                map.advise(match advice {
+                    DbAdvice::Normal => memmap2::Advice::Normal,
+                    DbAdvice::Sequential => memmap2::Advice::Sequential,
+                    DbAdvice::Random => memmap2::Advice::Random,
+                    DbAdvice::WillNeed => memmap2::Advice::WillNeed,
+                })?;
+                #[cfg(not(unix))]
+                let _ = advice;
+                Ok(DenseDb::Mapped(map))
+            }
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u64] {
+        match self {
+            DenseDb::Memory(v) => v.as_slice(),
+            DenseDb::Mapped(map) => {
+                // maps are page-aligned, which is enough for the SIMD kernels
+                let (prefix, words, suffix) = unsafe { map.align_to::<u64>() };
+                assert!(prefix.is_empty() && suffix.is_empty());
+                words
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use spiral_rs::util;
+    use std::fs;
+
+    #[test]
+    fn mapped_db_matches_loaded_db() {
+        let params = util::params_from_json(
+            r#"{"n": 2, "nu_1": 3, "nu_2": 2, "p": 256, "q2_bits": 22, "t_gsw": 7,
+            "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5, "instances": 2,
+            "db_item_size": 8192}"#,
+        );
+        let path = temp_dir().join("db.preprocessed");
+        let data: Vec<u8> = (0..DenseDb::size_words(&params) as u64)
+            .flat_map(|x| (x * 0x9e37_79b9_7f4a_7c15).to_ne_bytes())
+            .collect();
+        fs::write(&path, &data[1..]).unwrap();
+        assert!(matches!(
+            DenseDb::open(&params, &path, DenseDbStorage::Memory),
+            Err(Error::Corrupted(_))
+        ));
+        fs::write(&path, &data).unwrap();
+
+        let loaded = DenseDb::open(&params, &path, DenseDbStorage::Memory).unwrap();
+        for advice in [DbAdvice::Normal, DbAdvice::Sequential, DbAdvice::WillNeed] {
+            let mapped = DenseDb::open(&params, &path, DenseDbStorage::Mapped(advice)).unwrap();
+            assert_eq!(mapped.as_slice().as_ptr() as usize % 64, 0);
+            assert_eq!(mapped.as_slice(), loaded.as_slice());
+        }
+        let instance_words = DenseDb::instance_words(&params);
+        for storage in [
+            DenseDbStorage::Memory,
+            DenseDbStorage::Mapped(DbAdvice::Normal),
+        ] {
+            let shard = DenseDb::open_instances(&params, &path, storage, 1..2).unwrap();
+            assert_eq!(
+                shard.as_slice(),
+                &loaded.as_slice()[instance_words..2 * instance_words]
+            );
+        }
+        assert!(matches!(
+            DenseDb::open_instances(&params, &path, DenseDbStorage::Memory, 1..3),
+            Err(Error::InvalidParams(_))
+        ));
+        assert_eq!("random".parse::<DbAdvice>().unwrap(), DbAdvice::Random);
+        assert!("fast".parse::<DbAdvice>().is_err());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
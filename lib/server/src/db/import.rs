@@ -0,0 +1,142 @@
+use std::fs;
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// The formats `read_kv_pairs` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvFormat {
+    /// One `{"key": <string>, "value": <base64 string>}` object per line.
+    JsonLines,
+    /// One `key,value` pair per line, split at the first comma, with the
+    /// rest of the line as the value. There is no header or quoting.
+    Csv,
+    /// A directory of files, each holding the value of the key that is its
+    /// name. Subdirectories are skipped.
+    Directory,
+}
+
+impl KvFormat {
+    /// A directory, a `.csv` file, or a `.jsonl` or `.json` file.
+    pub fn detect(path: &Path) -> Result<Self, Error> {
+        if path.is_dir() {
+            return Ok(KvFormat::Directory);
+        }
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => Ok(KvFormat::Csv),
+            Some("jsonl") | Some("json") => Ok(KvFormat::JsonLines),
+            _ => Err(Error::InvalidRequest(format!(
+                "can't tell the format of {}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonKv {
+    key: String,
+    value: String,
+}
+
+/// Reads KV pairs from `path`, in the format `KvFormat::detect` gives.
+/// Blank lines are skipped.
+pub fn read_kv_pairs(path: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let mut kv_pairs = Vec::new();
+    match KvFormat::detect(path)? {
+        KvFormat::Directory => {
+            let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+                let key = entry.file_name().into_string().map_err(|name| {
+                    Error::InvalidRequest(format!("file name {:?} is not UTF-8", name))
+                })?;
+                kv_pairs.push((key, fs::read(entry.path())?));
+            }
+        }
+        format => {
+            let data = fs::read_to_string(path)?;
+            for (line_no, line) in data.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let bad_line = |reason: String| {
+                    Error::InvalidRequest(format!("line {}: {}", line_no + 1, reason))
+                };
+                if format == KvFormat::Csv {
+                    let (key, value) = line
+                        .split_once(',')
+                        .ok_or_else(|| bad_line("missing comma".to_owned()))?;
+                    kv_pairs.push((key.to_owned(), value.as_bytes().to_vec()));
+                } else {
+                    let kv: JsonKv =
+                        serde_json::from_str(line).map_err(|e| bad_line(e.to_string()))?;
+                    let value = general_purpose::STANDARD
+                        .decode(&kv.value)
+                        .map_err(|_| bad_line(format!("bad base64 value for key {}", kv.key)))?;
+                    kv_pairs.push((kv.key, value));
+                }
+            }
+        }
+    }
+    Ok(kv_pairs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn read_kv_pairs_is_correct() {
+        let dir = temp_dir();
+        let values_dir = dir.join("values");
+        fs::create_dir_all(values_dir.join("nested")).unwrap();
+        fs::write(values_dir.join("OR"), b"Oregon").unwrap();
+        fs::write(values_dir.join("CA"), b"California").unwrap();
+        fs::write(dir.join("kv.csv"), "CA,California\n\nOR,Oregon, US\n").unwrap();
+        fs::write(
+            dir.join("kv.jsonl"),
+            "{\"key\": \"CA\", \"value\": \"Q2FsaWZvcm5pYQ==\"}\n",
+        )
+        .unwrap();
+
+        let kv = |key: &str, value: &[u8]| (key.to_owned(), value.to_vec());
+        assert_eq!(
+            read_kv_pairs(&values_dir).unwrap(),
+            vec![kv("CA", b"California"), kv("OR", b"Oregon")]
+        );
+        assert_eq!(
+            read_kv_pairs(&dir.join("kv.csv")).unwrap(),
+            vec![kv("CA", b"California"), kv("OR", b"Oregon, US")]
+        );
+        assert_eq!(
+            read_kv_pairs(&dir.join("kv.jsonl")).unwrap(),
+            vec![kv("CA", b"California")]
+        );
+
+        fs::write(dir.join("bad.csv"), "CA California\n").unwrap();
+        assert!(matches!(
+            read_kv_pairs(&dir.join("bad.csv")),
+            Err(Error::InvalidRequest(_))
+        ));
+        fs::write(
+            dir.join("bad.jsonl"),
+            "{\"key\": \"CA\", \"value\": \"!\"}\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            read_kv_pairs(&dir.join("bad.jsonl")),
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(read_kv_pairs(&dir.join("kv.txt")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
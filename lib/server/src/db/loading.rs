@@ -5,6 +5,8 @@ use std::io::BufReader;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::io::Write;
+use std::mem::size_of;
 use std::time::Instant;
 
 use rand::thread_rng;
@@ -275,6 +277,45 @@ pub fn load_preprocessed_db_from_file(params: &Params, file: &mut File) -> Align
     v
 }
 
+/// Writes `db` in the layout `load_preprocessed_db_from_file` reads, one
+/// instance and trial at a time. Items missing from `db` are zero.
+pub fn write_preprocessed_db<W: Write>(
+    params: &Params,
+    db: &SparseDb,
+    out: &mut W,
+) -> Result<(), Error> {
+    let trials = params.n * params.n;
+    let dim0 = 1 << params.db_dim_1;
+    let num_per = 1 << params.db_dim_2;
+    let num_items = dim0 * num_per;
+    let poly_len = params.poly_len;
+
+    let mut slice = vec![0u8; num_items * poly_len * size_of::<u64>()];
+    for inst_trial in 0..(params.instances * trials) {
+        let items: Vec<Option<&[u64]>> = (0..num_items)
+            .map(|i| {
+                db.get_idx(inst_trial * num_items + i)
+                    .map(|&vec_idx| db.data[vec_idx].as_slice())
+            })
+            .collect();
+        // [z, ii, j], as in `generate_random_db_and_get_item`
+        slice
+            .par_chunks_exact_mut(num_items * size_of::<u64>())
+            .enumerate()
+            .for_each(|(z, out_z)| {
+                for (i, item) in items.iter().enumerate() {
+                    let ii = i % num_per;
+                    let j = i / num_per;
+                    let word = item.map_or(0, |item| item[z]);
+                    let offs = (ii * dim0 + j) * size_of::<u64>();
+                    out_z[offs..offs + size_of::<u64>()].copy_from_slice(&word.to_ne_bytes());
+                }
+            });
+        out.write_all(&slice)?;
+    }
+    Ok(())
+}
+
 pub fn convert_pt_to_poly<'a>(params: &'a Params, data: &[u8]) -> PolyMatrixNTT<'a> {
     let logp = f64::ceil(f64::log2(params.pt_modulus as f64)) as usize;
     let modp_words_per_chunk = params.poly_len; //params.modp_words_per_chunk();
@@ -298,17 +339,39 @@ pub fn convert_pt_to_poly<'a>(params: &'a Params, data: &[u8]) -> PolyMatrixNTT<
     item.ntt()
 }
 
-pub fn update_item(params: &Params, body: &[u8], db: &mut SparseDb) -> Result<u64, Error> {
+fn check_db_idx(params: &Params, db_idx: usize) -> Result<(), Error> {
+    if db_idx >= params.num_items() {
+        return Err(Error::InvalidRequest(format!(
+            "bad db idx {} (expected less than {})",
+            db_idx,
+            params.num_items()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks an item update, `[db_idx u32 BE][data]`, without applying it.
+fn check_item_update(params: &Params, body: &[u8]) -> Result<(), Error> {
     let instances = params.instances;
     let trials = params.n * params.n;
 
     let pt_data_len = params.bytes_per_chunk();
     let max_update_len = 4 + instances * trials * pt_data_len;
 
+    if body.len() < 4 {
+        return Err(Error::InvalidLength(body.len(), 4));
+    }
     if body.len() > max_update_len {
-        return Err(Error::InvalidLength(body.len(), max_update_len));
+        return Err(Error::TooLarge(body.len(), max_update_len));
     }
 
+    let db_idx = u32::from_be_bytes(body[..4].try_into().unwrap()) as usize;
+    check_db_idx(params, db_idx)
+}
+
+pub fn update_item(params: &Params, body: &[u8], db: &mut SparseDb) -> Result<u64, Error> {
+    check_item_update(params, body)?;
+
     let db_idx = u32::from_be_bytes(body[..4].try_into().unwrap()) as usize;
 
     update_item_raw(params, db_idx, &body[4..], db)
@@ -324,21 +387,18 @@ pub fn update_item_raw(
     let trials = params.n * params.n;
     let pt_data_len = params.bytes_per_chunk();
 
-    let mut new_bucket = vec![0u8; instances * trials * pt_data_len];
+    check_db_idx(params, db_idx)?;
+    let max_data_len = instances * trials * pt_data_len;
+    if data.len() > max_data_len {
+        return Err(Error::TooLarge(data.len(), max_data_len));
+    }
+
+    let mut new_bucket = vec![0u8; max_data_len];
     new_bucket[..data.len()].copy_from_slice(&data);
     let inp = new_bucket.as_slice();
 
     assert_eq!(inp.len() % pt_data_len, 0);
 
-    if db_idx >= params.num_items() {
-        println!(
-            "bad db idx {} (expected less than {})",
-            db_idx,
-            params.num_items()
-        );
-        return Err(Error::Unknown);
-    }
-
     // set item to bytes
     let now = Instant::now();
     let results: Vec<_> = inp
@@ -358,20 +418,44 @@ pub fn update_item_raw(
     Ok(upsert_time as u64)
 }
 
-pub fn update_many_items(params: &Params, body: &[u8], db: &mut SparseDb) -> Result<u64, Error> {
+/// Applies a body of item updates, each prefixed by its length (u32 BE).
+/// Every update is checked before any is applied.
+/// Splits an `/update-row` body into its item updates, checking each, and
+/// returns them with the length of the largest.
+fn split_many_items<'a>(params: &Params, body: &'a [u8]) -> Result<(Vec<&'a [u8]>, u64), Error> {
     let mut offs = 0;
     let mut largest_update = 0;
+    let mut updates = Vec::new();
 
     while offs < body.len() {
-        let chunk_len = u32::from_be_bytes(body[offs..offs + 4].try_into().unwrap()) as usize;
-        let data = &body[offs + 4..offs + 4 + chunk_len];
+        let chunk_len_bytes = body
+            .get(offs..offs + 4)
+            .ok_or(Error::InvalidLength(body.len(), offs + 4))?;
+        let chunk_len = u32::from_be_bytes(chunk_len_bytes.try_into().unwrap()) as usize;
+        let data = body
+            .get(offs + 4..offs + 4 + chunk_len)
+            .ok_or(Error::InvalidLength(body.len(), offs + 4 + chunk_len))?;
         if data.len() > largest_update {
             largest_update = data.len();
         }
-        update_item(params, data, db)?;
+        check_item_update(params, data)?;
+        updates.push(data);
 
         offs += 4 + chunk_len;
     }
 
-    Ok(largest_update as u64)
+    Ok((updates, largest_update as u64))
+}
+
+/// Checks an `/update-row` body without applying it.
+pub fn check_many_items(params: &Params, body: &[u8]) -> Result<(), Error> {
+    split_many_items(params, body).map(|_| ())
+}
+
+pub fn update_many_items(params: &Params, body: &[u8], db: &mut SparseDb) -> Result<u64, Error> {
+    let (updates, largest_update) = split_many_items(params, body)?;
+    for data in updates {
+        update_item(params, data, db)?;
+    }
+    Ok(largest_update)
 }
@@ -0,0 +1,325 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use spiral_rs::params::Params;
+
+use crate::error::Error;
+
+use super::aligned_memory::AlignedMemory64;
+use super::bloom::BloomFilter;
+use super::sparse_db::SparseDb;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SPDB";
+const SNAPSHOT_FORMAT_VERSION: u32 = 3;
+const CHECKSUM_BYTES: usize = 32;
+
+/// The durable state of a database: the encoded `SparseDb`, the plaintext
+/// KV rows it was built from, the write version, the sequence number of
+/// the last write-ahead log record it includes, and the Bloom filter of its
+/// keys, if it keeps one.
+pub struct Snapshot {
+    pub db: SparseDb,
+    pub rows: Vec<Vec<u8>>,
+    pub version: u64,
+    pub wal_seq: u64,
+    pub bloom: Option<BloomFilter>,
+}
+
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn write_u64<W: Write>(w: &mut W, val: u64) -> std::io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_snapshot_body<W: Write>(
+    w: &mut W,
+    db: &SparseDb,
+    rows: &[Vec<u8>],
+    version: u64,
+    wal_seq: u64,
+    bloom: Option<&BloomFilter>,
+) -> std::io::Result<()> {
+    w.write_all(SNAPSHOT_MAGIC)?;
+    w.write_all(&SNAPSHOT_FORMAT_VERSION.to_le_bytes())?;
+    write_u64(w, version)?;
+    write_u64(w, wal_seq)?;
+
+    write_u64(w, rows.len() as u64)?;
+    for row in rows {
+        write_u64(w, row.len() as u64)?;
+        w.write_all(row)?;
+    }
+
+    write_u64(w, db.data.len() as u64)?;
+    for poly in db.data.iter() {
+        write_u64(w, poly.len() as u64)?;
+        for word in poly.as_slice() {
+            w.write_all(&word.to_le_bytes())?;
+        }
+    }
+
+    let mut map_entries: Vec<_> = db.db_idx_to_vec_idx.iter().collect();
+    map_entries.sort();
+    write_u64(w, map_entries.len() as u64)?;
+    for (db_idx, vec_idx) in map_entries {
+        write_u64(w, *db_idx as u64)?;
+        write_u64(w, *vec_idx as u64)?;
+    }
+
+    let bloom_bytes = bloom.map(|bloom| bloom.to_bytes()).unwrap_or_default();
+    write_u64(w, bloom_bytes.len() as u64)?;
+    w.write_all(&bloom_bytes)?;
+
+    Ok(())
+}
+
+/// Atomically writes a snapshot of `db`, `rows`, `version` and `bloom` to
+/// `path`, recording that it includes write-ahead log records up to `wal_seq`.
+///
+/// The snapshot is first written to a temporary file next to `path`, synced,
+/// and then renamed over `path`, so a crash never leaves a partial snapshot.
+pub fn write_snapshot(
+    path: &Path,
+    db: &SparseDb,
+    rows: &[Vec<u8>],
+    version: u64,
+    wal_seq: u64,
+    bloom: Option<&BloomFilter>,
+) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = HashingWriter {
+        inner: BufWriter::with_capacity(1 << 24, file),
+        hasher: Sha256::new(),
+    };
+    write_snapshot_body(&mut writer, db, rows, version, wal_seq, bloom)?;
+
+    let checksum = writer.hasher.finalize();
+    let mut inner = writer.inner;
+    inner.write_all(&checksum)?;
+    let file = inner.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_snapshot_body<R: Read>(
+    r: &mut R,
+    params: &Params,
+    file_len: usize,
+) -> Result<Snapshot, Error> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(Error::Corrupted("bad snapshot magic".to_owned()));
+    }
+    let mut format_version = [0u8; 4];
+    r.read_exact(&mut format_version)?;
+    let format_version = u32::from_le_bytes(format_version);
+    if format_version == 0 || format_version > SNAPSHOT_FORMAT_VERSION {
+        return Err(Error::Corrupted(format!(
+            "unsupported snapshot format version {}",
+            format_version
+        )));
+    }
+    let version = read_u64(r)?;
+    // version 1 snapshots predate the write-ahead log
+    let wal_seq = if format_version >= 2 { read_u64(r)? } else { 0 };
+
+    let num_rows = read_u64(r)? as usize;
+    if num_rows != params.num_items() {
+        return Err(Error::InvalidLength(num_rows, params.num_items()));
+    }
+    let mut rows = Vec::with_capacity(num_rows);
+    for _ in 0..num_rows {
+        let row_len = read_u64(r)? as usize;
+        if row_len > file_len {
+            return Err(Error::Corrupted(format!("bad row length {}", row_len)));
+        }
+        let mut row = vec![0u8; row_len];
+        r.read_exact(&mut row)?;
+        rows.push(row);
+    }
+
+    let num_polys = read_u64(r)? as usize;
+    let mut db = SparseDb::new();
+    let mut poly_bytes = vec![0u8; params.poly_len * 8];
+    for _ in 0..num_polys {
+        let poly_len = read_u64(r)? as usize;
+        if poly_len != params.poly_len {
+            return Err(Error::InvalidLength(poly_len, params.poly_len));
+        }
+        r.read_exact(&mut poly_bytes)?;
+        let mut poly = AlignedMemory64::new(poly_len);
+        for (word, bytes) in poly
+            .as_mut_slice()
+            .iter_mut()
+            .zip(poly_bytes.chunks_exact(8))
+        {
+            *word = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        db.data.push(poly);
+    }
+
+    let num_map_entries = read_u64(r)? as usize;
+    for _ in 0..num_map_entries {
+        let db_idx = read_u64(r)? as usize;
+        let vec_idx = read_u64(r)? as usize;
+        if vec_idx >= db.data.len() {
+            return Err(Error::Corrupted(format!(
+                "snapshot maps {} to missing polynomial {}",
+                db_idx, vec_idx
+            )));
+        }
+        db.db_idx_to_vec_idx.insert(db_idx, vec_idx);
+    }
+
+    // version 2 snapshots predate Bloom filters
+    let mut bloom = None;
+    if format_version >= 3 {
+        let bloom_len = read_u64(r)? as usize;
+        if bloom_len > file_len {
+            return Err(Error::Corrupted(format!(
+                "bad bloom filter length {}",
+                bloom_len
+            )));
+        }
+        if bloom_len > 0 {
+            let mut bloom_bytes = vec![0u8; bloom_len];
+            r.read_exact(&mut bloom_bytes)?;
+            bloom = Some(BloomFilter::from_bytes(&bloom_bytes)?);
+        }
+    }
+
+    Ok(Snapshot {
+        db,
+        rows,
+        version,
+        wal_seq,
+        bloom,
+    })
+}
+
+/// Reads a snapshot written by `write_snapshot`, verifying its checksum and
+/// that it matches `params`. Returns `Ok(None)` if no snapshot exists.
+pub fn read_snapshot(path: &Path, params: &Params) -> Result<Option<Snapshot>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let file_len = file.metadata()?.len() as usize;
+    let mut reader = HashingReader {
+        inner: BufReader::with_capacity(1 << 24, file),
+        hasher: Sha256::new(),
+    };
+    let snapshot = read_snapshot_body(&mut reader, params, file_len)?;
+
+    let computed = reader.hasher.finalize();
+    let mut inner = reader.inner;
+    let mut stored = [0u8; CHECKSUM_BYTES];
+    inner.read_exact(&mut stored)?;
+    if computed.as_slice() != stored {
+        return Err(Error::Corrupted("snapshot checksum mismatch".to_owned()));
+    }
+    if inner.read(&mut [0u8; 1])? != 0 {
+        return Err(Error::Corrupted("trailing data after snapshot".to_owned()));
+    }
+
+    Ok(Some(snapshot))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::write::update_database;
+    use crate::test_util::{get_params, temp_dir};
+
+    #[test]
+    fn snapshot_roundtrip_is_correct() {
+        let params = get_params();
+        let mut db = SparseDb::new();
+        let mut rows = vec![Vec::new(); params.num_items()];
+        let kv_pairs: Vec<(&str, &[u8])> = vec![("CA", b"California"), ("OR", b"Oregon")];
+        update_database(&params, &kv_pairs, &mut rows, &mut db).unwrap();
+        let mut bloom = BloomFilter::new(4, 10);
+        bloom.insert("CA");
+
+        let path = temp_dir().join("db.snapshot");
+        write_snapshot(&path, &db, &rows, 7, 12, Some(&bloom)).unwrap();
+        let snapshot = read_snapshot(&path, &params).unwrap().unwrap();
+
+        assert_eq!(snapshot.version, 7);
+        assert_eq!(snapshot.wal_seq, 12);
+        assert_eq!(snapshot.bloom, Some(bloom));
+        assert_eq!(snapshot.rows, rows);
+        assert_eq!(snapshot.db.db_idx_to_vec_idx, db.db_idx_to_vec_idx);
+        assert_eq!(snapshot.db.data.len(), db.data.len());
+        for (a, b) in snapshot.db.data.iter().zip(db.data.iter()) {
+            assert_eq!(a.as_slice(), b.as_slice());
+        }
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn snapshot_detects_corruption() {
+        let params = get_params();
+        let db = SparseDb::new();
+        let mut rows = vec![Vec::new(); params.num_items()];
+        rows[3] = vec![1, 2, 3];
+
+        let path = temp_dir().join("db.snapshot");
+        assert!(read_snapshot(&path, &params).unwrap().is_none());
+
+        write_snapshot(&path, &db, &rows, 1, 0, None).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        let len = data.len();
+        data[len - 1] ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(read_snapshot(&path, &params).is_err());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+const RECORD_HEADER_BYTES: usize = 4 + 8 + 1;
+const RECORD_CHECKSUM_BYTES: usize = 8;
+const MAX_PAYLOAD_BYTES: usize = u32::MAX as usize;
+
+const RECORD_KIND_WRITE: u8 = 1;
+const RECORD_KIND_UPDATE_ROWS: u8 = 2;
+const RECORD_KIND_CLEAR: u8 = 3;
+
+/// A mutation of the database, as logged to the write-ahead log.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalRecord {
+    /// KV pairs passed to `update_database` (a `/write`).
+    Write(Vec<(String, Vec<u8>)>),
+    /// A raw body passed to `update_many_items` (an `/update-row`).
+    UpdateRows(Vec<u8>),
+    /// Removal of every item (a `/clear`).
+    Clear,
+}
+
+fn checksum(seq: u64, kind: u8, payload: &[u8]) -> [u8; RECORD_CHECKSUM_BYTES] {
+    let mut hasher = Sha256::new();
+    hasher.update(seq.to_le_bytes());
+    hasher.update([kind]);
+    hasher.update(payload);
+    hasher.finalize()[..RECORD_CHECKSUM_BYTES]
+        .try_into()
+        .unwrap()
+}
+
+fn read_u32(data: &[u8], offs: &mut usize) -> Option<usize> {
+    let bytes = data.get(*offs..*offs + 4)?;
+    *offs += 4;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+fn read_bytes<'a>(data: &'a [u8], offs: &mut usize) -> Option<&'a [u8]> {
+    let len = read_u32(data, offs)?;
+    let bytes = data.get(*offs..*offs + len)?;
+    *offs += len;
+    Some(bytes)
+}
+
+impl WalRecord {
+    fn kind(&self) -> u8 {
+        match self {
+            WalRecord::Write(_) => RECORD_KIND_WRITE,
+            WalRecord::UpdateRows(_) => RECORD_KIND_UPDATE_ROWS,
+            WalRecord::Clear => RECORD_KIND_CLEAR,
+        }
+    }
+
+    fn encode_payload(&self) -> Vec<u8> {
+        match self {
+            WalRecord::Write(kv_pairs) => {
+                let mut payload = Vec::new();
+                payload.extend((kv_pairs.len() as u32).to_le_bytes());
+                for (key, value) in kv_pairs {
+                    payload.extend((key.len() as u32).to_le_bytes());
+                    payload.extend(key.as_bytes());
+                    payload.extend((value.len() as u32).to_le_bytes());
+                    payload.extend(value);
+                }
+                payload
+            }
+            WalRecord::UpdateRows(body) => body.clone(),
+            WalRecord::Clear => Vec::new(),
+        }
+    }
+
+    fn decode_payload(kind: u8, payload: &[u8]) -> Option<Self> {
+        match kind {
+            RECORD_KIND_WRITE => {
+                let mut offs = 0;
+                let num_pairs = read_u32(payload, &mut offs)?;
+                let mut kv_pairs = Vec::new();
+                for _ in 0..num_pairs {
+                    let key = std::str::from_utf8(read_bytes(payload, &mut offs)?).ok()?;
+                    let value = read_bytes(payload, &mut offs)?;
+                    kv_pairs.push((key.to_owned(), value.to_vec()));
+                }
+                if offs != payload.len() {
+                    return None;
+                }
+                Some(WalRecord::Write(kv_pairs))
+            }
+            RECORD_KIND_UPDATE_ROWS => Some(WalRecord::UpdateRows(payload.to_vec())),
+            RECORD_KIND_CLEAR if payload.is_empty() => Some(WalRecord::Clear),
+            _ => None,
+        }
+    }
+
+    /// Encodes the record as `[len u32][seq u64][kind u8][payload][checksum]`.
+    fn encode(&self, seq: u64, payload: &[u8]) -> Vec<u8> {
+        let kind = self.kind();
+        let mut out =
+            Vec::with_capacity(RECORD_HEADER_BYTES + payload.len() + RECORD_CHECKSUM_BYTES);
+        out.extend((payload.len() as u32).to_le_bytes());
+        out.extend(seq.to_le_bytes());
+        out.push(kind);
+        out.extend(payload);
+        out.extend(checksum(seq, kind, payload));
+        out
+    }
+
+    /// Reads one record, returning `None` at the end of the log or at a torn
+    /// or corrupted record.
+    fn decode_from<R: Read>(r: &mut R) -> Option<(u64, Self, usize)> {
+        let mut header = [0u8; RECORD_HEADER_BYTES];
+        r.read_exact(&mut header).ok()?;
+        let payload_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let seq = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let kind = header[12];
+
+        // read through take() so a garbage length can't force a huge allocation
+        let mut payload = Vec::new();
+        r.by_ref()
+            .take(payload_len as u64)
+            .read_to_end(&mut payload)
+            .ok()?;
+        if payload.len() != payload_len {
+            return None;
+        }
+        let mut stored_checksum = [0u8; RECORD_CHECKSUM_BYTES];
+        r.read_exact(&mut stored_checksum).ok()?;
+        if stored_checksum != checksum(seq, kind, &payload) {
+            return None;
+        }
+
+        let record = Self::decode_payload(kind, &payload)?;
+        let record_len = RECORD_HEADER_BYTES + payload_len + RECORD_CHECKSUM_BYTES;
+        Some((seq, record, record_len))
+    }
+}
+
+struct WalInner {
+    writer: BufWriter<File>,
+    next_seq: u64,
+    synced_seq: u64,
+    syncing: bool,
+    /// Set when a write or fsync fails. The file may then end in a partial
+    /// record, so nothing more is appended after it.
+    failed: bool,
+    records_since_checkpoint: usize,
+}
+
+impl WalInner {
+    fn check_failed(&self) -> Result<(), Error> {
+        if self.failed {
+            return Err(Error::IoError(io::Error::new(
+                io::ErrorKind::Other,
+                "the write-ahead log failed an earlier write",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// An append-only log of database mutations.
+///
+/// Records are appended with `append`, and are durable once `sync` returns
+/// for their sequence number. Concurrent callers of `sync` share a single
+/// fsync ("group commit"); a non-zero `group_commit_window` makes the syncing
+/// caller wait that long first, so more records are covered by each fsync.
+/// Once a write or fsync fails, every later call fails too, until the log is
+/// reopened and its torn tail discarded.
+pub struct Wal {
+    inner: Mutex<WalInner>,
+    synced: Condvar,
+    sync_file: File,
+    group_commit_window: Duration,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path`.
+    ///
+    /// Returns the log, positioned for appending, and the records with a
+    /// sequence number greater than `after_seq`, which must be replayed.
+    /// A torn record at the end of the log, left by a crash mid-append, is
+    /// discarded. A bad record followed by others means the log is corrupted,
+    /// and fails with `Error::Corrupted` rather than dropping them.
+    pub fn open(
+        path: &Path,
+        after_seq: u64,
+        group_commit_window: Duration,
+    ) -> Result<(Self, Vec<WalRecord>), Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        let mut last_seq = after_seq;
+        {
+            let mut reader = BufReader::with_capacity(1 << 24, &mut file);
+            while let Some((seq, record, record_len)) = WalRecord::decode_from(&mut reader) {
+                valid_len += record_len;
+                if seq > after_seq {
+                    records.push(record);
+                }
+                last_seq = u64::max(last_seq, seq);
+            }
+        }
+        let file_len = file.metadata()?.len();
+        if valid_len as u64 != file_len {
+            if !Self::is_torn_tail(&mut file, valid_len as u64, file_len)? {
+                return Err(Error::Corrupted(format!(
+                    "write-ahead log {} has a bad record at byte {}, followed by others",
+                    path.display(),
+                    valid_len
+                )));
+            }
+            println!("Discarding torn write-ahead log tail at byte {}", valid_len);
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+
+        let sync_file = file.try_clone()?;
+        let wal = Wal {
+            inner: Mutex::new(WalInner {
+                writer: BufWriter::new(file),
+                next_seq: last_seq + 1,
+                synced_seq: last_seq,
+                syncing: false,
+                failed: false,
+                records_since_checkpoint: records.len(),
+            }),
+            synced: Condvar::new(),
+            sync_file,
+            group_commit_window,
+        };
+        Ok((wal, records))
+    }
+
+    /// Whether the record that failed to decode at `offs` reaches the end of
+    /// the file, as one whose append was cut short does. A complete record
+    /// with a bad checksum counts, since its data may not have reached the
+    /// disk.
+    fn is_torn_tail(file: &mut File, offs: u64, file_len: u64) -> Result<bool, Error> {
+        let remaining = file_len - offs;
+        if remaining < RECORD_HEADER_BYTES as u64 {
+            return Ok(true);
+        }
+        let mut len_bytes = [0u8; 4];
+        file.seek(SeekFrom::Start(offs))?;
+        file.read_exact(&mut len_bytes)?;
+        let payload_len = u32::from_le_bytes(len_bytes) as u64;
+        let record_len = (RECORD_HEADER_BYTES + RECORD_CHECKSUM_BYTES) as u64 + payload_len;
+        Ok(record_len >= remaining)
+    }
+
+    /// Appends a record to the log, returning its sequence number.
+    /// The record is not durable until `sync` is called.
+    pub fn append(&self, record: &WalRecord) -> Result<u64, Error> {
+        let payload = record.encode_payload();
+        if payload.len() > MAX_PAYLOAD_BYTES {
+            return Err(Error::TooLarge(payload.len(), MAX_PAYLOAD_BYTES));
+        }
+
+        let mut inner = self.inner.lock()?;
+        inner.check_failed()?;
+        let seq = inner.next_seq;
+        if let Err(e) = inner.writer.write_all(&record.encode(seq, &payload)) {
+            inner.failed = true;
+            return Err(e.into());
+        }
+        inner.next_seq += 1;
+        inner.records_since_checkpoint += 1;
+        Ok(seq)
+    }
+
+    /// Blocks until the record with sequence number `seq` is durable.
+    pub fn sync(&self, seq: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock()?;
+        loop {
+            if inner.synced_seq >= seq {
+                return Ok(());
+            }
+            inner.check_failed()?;
+            if inner.syncing {
+                inner = self.synced.wait(inner)?;
+                continue;
+            }
+
+            inner.syncing = true;
+            if !self.group_commit_window.is_zero() {
+                drop(inner);
+                thread::sleep(self.group_commit_window);
+                inner = self.inner.lock()?;
+            }
+            let target_seq = inner.next_seq - 1;
+            let flushed = inner.writer.flush();
+            drop(inner);
+
+            let result = flushed.and_then(|_| self.sync_file.sync_data());
+
+            inner = self.inner.lock()?;
+            inner.syncing = false;
+            match result {
+                Ok(()) => inner.synced_seq = u64::max(inner.synced_seq, target_seq),
+                Err(_) => inner.failed = true,
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+
+    /// The sequence number of the last appended record.
+    pub fn last_seq(&self) -> Result<u64, Error> {
+        Ok(self.inner.lock()?.next_seq - 1)
+    }
+
+    /// The number of records appended since the log was last truncated.
+    pub fn records_since_checkpoint(&self) -> Result<usize, Error> {
+        Ok(self.inner.lock()?.records_since_checkpoint)
+    }
+
+    /// Empties the log, after all of its records have been captured in a
+    /// durable snapshot. The caller must prevent concurrent appends.
+    pub fn truncate(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock()?;
+        inner.check_failed()?;
+        let writer = &mut inner.writer;
+        let result = writer.flush().and_then(|_| {
+            let file = writer.get_mut();
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.sync_all()
+        });
+        if let Err(e) = result {
+            inner.failed = true;
+            return Err(e.into());
+        }
+        inner.synced_seq = inner.next_seq - 1;
+        inner.records_since_checkpoint = 0;
+        self.synced.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs;
+    use std::sync::Arc;
+
+    fn get_records() -> Vec<WalRecord> {
+        vec![
+            WalRecord::Write(vec![
+                ("CA".to_owned(), b"California".to_vec()),
+                ("OR".to_owned(), Vec::new()),
+            ]),
+            WalRecord::UpdateRows(vec![0, 0, 0, 4, 0, 0, 0, 1, 2, 3, 4, 5]),
+            WalRecord::Clear,
+            WalRecord::Write(Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn wal_replay_is_correct() {
+        let path = temp_dir().join("wal.log");
+        let records = get_records();
+
+        let (wal, replayed) = Wal::open(&path, 0, Duration::ZERO).unwrap();
+        assert!(replayed.is_empty());
+        for record in records.iter() {
+            let seq = wal.append(record).unwrap();
+            wal.sync(seq).unwrap();
+        }
+        assert_eq!(wal.last_seq().unwrap(), 4);
+        drop(wal);
+
+        let (wal, replayed) = Wal::open(&path, 0, Duration::ZERO).unwrap();
+        assert_eq!(replayed, records);
+        assert_eq!(wal.append(&records[0]).unwrap(), 5);
+        drop(wal);
+
+        let (_, replayed) = Wal::open(&path, 2, Duration::ZERO).unwrap();
+        assert_eq!(replayed.len(), 3);
+        assert_eq!(replayed[0], records[2]);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wal_discards_torn_tail() {
+        let path = temp_dir().join("wal.log");
+        let records = get_records();
+
+        let (wal, _) = Wal::open(&path, 0, Duration::ZERO).unwrap();
+        for record in records.iter() {
+            wal.append(record).unwrap();
+        }
+        wal.sync(wal.last_seq().unwrap()).unwrap();
+        drop(wal);
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let (wal, replayed) = Wal::open(&path, 0, Duration::ZERO).unwrap();
+        assert_eq!(replayed, records[..3].to_vec());
+        assert_eq!(wal.append(&records[3]).unwrap(), 4);
+        wal.sync(4).unwrap();
+        drop(wal);
+
+        let (_, replayed) = Wal::open(&path, 0, Duration::ZERO).unwrap();
+        assert_eq!(replayed, records);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wal_rejects_corruption_before_tail() {
+        let path = temp_dir().join("wal.log");
+        let records = get_records();
+
+        let (wal, _) = Wal::open(&path, 0, Duration::ZERO).unwrap();
+        for record in records.iter() {
+            wal.append(record).unwrap();
+        }
+        wal.sync(wal.last_seq().unwrap()).unwrap();
+        drop(wal);
+
+        // flip the last checksum byte of the first record
+        let mut data = fs::read(&path).unwrap();
+        let payload_len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        data[RECORD_HEADER_BYTES + payload_len + RECORD_CHECKSUM_BYTES - 1] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            Wal::open(&path, 0, Duration::ZERO),
+            Err(Error::Corrupted(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn wal_rejects_appends_after_a_failed_write() {
+        // every write to /dev/full fails with ENOSPC
+        let (wal, _) = Wal::open(Path::new("/dev/full"), 0, Duration::ZERO).unwrap();
+        let small = WalRecord::Clear;
+        let large = WalRecord::UpdateRows(vec![0; 1 << 16]);
+
+        let seq = wal.append(&small).unwrap();
+        assert!(matches!(wal.append(&large), Err(Error::IoError(_))));
+        assert!(matches!(wal.append(&small), Err(Error::IoError(_))));
+        assert!(matches!(wal.sync(seq), Err(Error::IoError(_))));
+        assert!(matches!(wal.truncate(), Err(Error::IoError(_))));
+        assert_eq!(wal.last_seq().unwrap(), seq);
+    }
+
+    #[test]
+    fn wal_group_commit_is_correct() {
+        let path = temp_dir().join("wal.log");
+        let (wal, _) = Wal::open(&path, 0, Duration::from_millis(5)).unwrap();
+        let wal = Arc::new(wal);
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let wal = wal.clone();
+                thread::spawn(move || {
+                    let record = WalRecord::UpdateRows(vec![i as u8; 16]);
+                    let seq = wal.append(&record).unwrap();
+                    wal.sync(seq).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(wal.records_since_checkpoint().unwrap(), 8);
+
+        wal.truncate().unwrap();
+        assert_eq!(wal.records_since_checkpoint().unwrap(), 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        drop(wal);
+
+        let (wal, replayed) = Wal::open(&path, 8, Duration::ZERO).unwrap();
+        assert!(replayed.is_empty());
+        assert_eq!(wal.append(&WalRecord::Write(Vec::new())).unwrap(), 9);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -6,6 +6,7 @@ use sha2::{Digest, Sha256};
 use spiral_rs::params::Params;
 
 use super::{loading::update_item_raw, sparse_db::SparseDb};
+use crate::error::Error;
 
 pub fn row_from_key(num_items: usize, key: &str) -> usize {
     let buckets_log2 = (num_items as f64).log2().ceil() as usize;
@@ -91,7 +92,8 @@ pub fn update_row(row: &mut Vec<u8>, key: &str, value: &[u8]) {
         }
 
         // read len
-        let (value_len, value_len_len) = varint_decode(&row[i..i + VARINT_MAX_BYTES]);
+        let varint_end = usize::min(i + VARINT_MAX_BYTES, row.len());
+        let (value_len, value_len_len) = varint_decode(&row[i..varint_end]);
         i += value_len_len;
 
         // read value
@@ -107,6 +109,11 @@ pub fn update_row(row: &mut Vec<u8>, key: &str, value: &[u8]) {
         assert!(found_end);
     }
 
+    if value.len() == 0 && !found_start {
+        // deleting a key that isn't present
+        return;
+    }
+
     let mut new_value = value.to_vec();
 
     if value.len() == 0 {
@@ -126,30 +133,42 @@ pub fn update_row(row: &mut Vec<u8>, key: &str, value: &[u8]) {
     }
 }
 
-pub fn unwrap_kv_pairs(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+pub fn unwrap_kv_pairs(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
     let mut kv_pairs = Vec::new();
 
-    // Parse the data as a JSON object
-    if let Ok(json_data) = serde_json::from_slice::<HashMap<String, String>>(data) {
-        for (key, base64_value) in json_data.iter() {
-            // Decode the Base64-encoded value
-            if let Ok(decoded_value) = base64::decode(base64_value) {
-                kv_pairs.push((key.clone(), decoded_value));
-            }
-        }
+    // Parse the data as a JSON object; a null value deletes the key
+    let json_data = serde_json::from_slice::<HashMap<String, Option<String>>>(data)
+        .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+    for (key, base64_value) in json_data.iter() {
+        let Some(base64_value) = base64_value else {
+            kv_pairs.push((key.clone(), Vec::new()));
+            continue;
+        };
+        // Decode the Base64-encoded value
+        let decoded_value = base64::decode(base64_value)
+            .map_err(|_| Error::InvalidRequest(format!("bad base64 value for key {}", key)))?;
+        kv_pairs.push((key.clone(), decoded_value));
     }
     // print KV pairs
     println!("kv_pairs: {:?}", kv_pairs);
 
-    kv_pairs
+    Ok(kv_pairs)
 }
 
-pub fn update_database(
+/// A row with new KV pairs written to it, and its compressed encoding.
+pub struct EncodedRow {
+    row_id: usize,
+    row_data: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+/// Writes KV pairs to copies of their rows and compresses those rows, without
+/// changing anything. Fails if any row no longer fits in an item.
+pub fn encode_rows(
     params: &Params,
     kv_pairs: &[(&str, &[u8])],
-    rows: &mut [Vec<u8>],
-    db: &mut SparseDb,
-) {
+    rows: &[Vec<u8>],
+) -> Result<Vec<EncodedRow>, Error> {
     let mut row_id_to_keys = HashMap::new();
     let mut keys_to_values = HashMap::new();
     for (k, v) in kv_pairs {
@@ -164,19 +183,55 @@ pub fn update_database(
     let mut row_ids_to_update = row_id_to_keys.keys().collect::<Vec<_>>();
     row_ids_to_update.sort();
 
+    let max_item_len = params.instances * params.n * params.n * params.bytes_per_chunk();
+    let mut updated_rows = Vec::new();
     for row_id in row_ids_to_update {
-        let row_data = &mut rows[*row_id];
+        let mut row_data = rows[*row_id].clone();
 
         let keys_to_update = row_id_to_keys.get(row_id).unwrap();
         for key in keys_to_update {
             let value = keys_to_values.get(key).unwrap();
-            update_row(row_data, *key, *value);
+            update_row(&mut row_data, *key, *value);
         }
 
         let mut compressor = BzEncoder::new(row_data.as_slice(), Compression::best());
         let mut compressed = Vec::new();
-        compressor.read_to_end(&mut compressed).unwrap();
+        compressor.read_to_end(&mut compressed)?;
+        if compressed.len() > max_item_len {
+            return Err(Error::TooLarge(compressed.len(), max_item_len));
+        }
+
+        updated_rows.push(EncodedRow {
+            row_id: *row_id,
+            row_data,
+            compressed,
+        });
+    }
+    Ok(updated_rows)
+}
 
-        update_item_raw(params, *row_id, &compressed, db).unwrap();
+/// Stores rows from `encode_rows` and re-encodes them into the database.
+pub fn store_rows(
+    params: &Params,
+    encoded_rows: Vec<EncodedRow>,
+    rows: &mut [Vec<u8>],
+    db: &mut SparseDb,
+) -> Result<(), Error> {
+    for row in encoded_rows {
+        update_item_raw(params, row.row_id, &row.compressed, db)?;
+        rows[row.row_id] = row.row_data;
     }
+    Ok(())
+}
+
+/// Writes KV pairs to their rows and re-encodes those rows into the database.
+/// If any row no longer fits in an item, nothing is changed.
+pub fn update_database(
+    params: &Params,
+    kv_pairs: &[(&str, &[u8])],
+    rows: &mut [Vec<u8>],
+    db: &mut SparseDb,
+) -> Result<(), Error> {
+    let encoded_rows = encode_rows(params, kv_pairs, rows)?;
+    store_rows(params, encoded_rows, rows, db)
 }
@@ -1,12 +1,22 @@
 use std::{fmt::Display, sync::PoisonError};
 
 use actix_http::body::BoxBody;
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use spiral_rs::{error::Error as SpiralError, wire::WireError};
 
 #[derive(Debug)]
 pub enum Error {
     InvalidLength(usize, usize),
     IoError(std::io::Error),
+    Corrupted(String),
+    InvalidName(String),
+    InvalidParams(String),
+    InvalidRequest(String),
+    TooLarge(usize, usize),
+    AlreadyExists(String),
+    ReadOnly,
+    Upstream(String),
     NotFound,
     Unknown,
 }
@@ -15,6 +25,14 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IoError(io_error) => write!(f, "{}", io_error),
+            Error::Corrupted(reason) => write!(f, "corrupted data: {}", reason),
+            Error::InvalidName(name) => write!(f, "invalid name: {}", name),
+            Error::InvalidParams(reason) => write!(f, "invalid parameters: {}", reason),
+            Error::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+            Error::TooLarge(got, limit) => write!(f, "too large: got {}, limit {}", got, limit),
+            Error::AlreadyExists(name) => write!(f, "already exists: {}", name),
+            Error::ReadOnly => write!(f, "bucket is read-only"),
+            Error::Upstream(reason) => write!(f, "shard worker failed: {}", reason),
             Error::NotFound => write!(f, "not found"),
             Error::Unknown => write!(f, "unknown err"),
             Error::InvalidLength(got, expected) => {
@@ -26,10 +44,63 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
-// TODO: add status_code() implementation to give better error info
 impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidLength(..)
+            | Error::InvalidName(_)
+            | Error::InvalidParams(_)
+            | Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::ReadOnly => StatusCode::FORBIDDEN,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::AlreadyExists(_) => StatusCode::CONFLICT,
+            Error::TooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Error::IoError(_) | Error::Corrupted(_) | Error::Unknown => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Responds with `{"error": <message>}`. Internal failures are only
+    /// described in the server's log.
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::with_body(self.status_code(), self.to_string()).map_into_boxed_body()
+        let status = self.status_code();
+        let message = if status.is_server_error() {
+            println!("Internal error: {}", self);
+            "internal error".to_owned()
+        } else {
+            self.to_string()
+        };
+        HttpResponse::build(status).json(ErrorResponse { error: message })
+    }
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(io_error: std::io::Error) -> Self {
+        Error::IoError(io_error)
+    }
+}
+
+impl From<WireError> for Error {
+    fn from(wire_error: WireError) -> Self {
+        Error::InvalidRequest(wire_error.to_string())
+    }
+}
+
+impl From<SpiralError> for Error {
+    fn from(spiral_error: SpiralError) -> Self {
+        match spiral_error {
+            SpiralError::InvalidLength(got, expected) => Error::InvalidLength(got, expected),
+            SpiralError::InvalidParams(reason) => Error::InvalidParams(reason),
+            _ => Error::InvalidRequest(spiral_error.to_string()),
+        }
     }
 }
 
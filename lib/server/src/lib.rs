@@ -1,5 +1,11 @@
+pub mod bucket;
 pub mod error;
 pub mod server;
+pub mod session;
+pub mod shard;
+
+#[cfg(test)]
+mod test_util;
 
 pub mod compute {
     pub mod dot_product;
@@ -10,7 +16,12 @@ pub mod compute {
 
 pub mod db {
     pub mod aligned_memory;
+    pub mod bloom;
+    pub mod dense_db;
+    pub mod import;
     pub mod loading;
+    pub mod snapshot;
     pub mod sparse_db;
+    pub mod wal;
     pub mod write;
 }
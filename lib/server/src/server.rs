@@ -12,7 +12,13 @@ use crate::compute::fold::*;
 use crate::compute::pack::*;
 use crate::compute::query_expansion::*;
 use crate::db::aligned_memory::*;
+use crate::db::dense_db::DenseDb;
 use crate::db::sparse_db::SparseDb;
+use std::ops::Range;
+
+/// The most queries `process_queries` multiplies against the database in one
+/// pass; each needs its own accumulators, so larger batches take several.
+pub const MAX_QUERIES_PER_PASS: usize = 16;
 
 pub fn process_query(
     params: &Params,
@@ -20,18 +26,67 @@ pub fn process_query(
     query: &Query,
     db: &SparseDb,
 ) -> Vec<u8> {
-    // println!("Processing query");
+    process_queries(params, &[(public_params, query)], db)
+        .pop()
+        .unwrap()
+}
 
-    let dim0 = 1 << params.db_dim_1;
-    let num_per = 1 << params.db_dim_2;
+/// A database in either of the layouts the first dimension is multiplied
+/// against.
+#[derive(Clone, Copy)]
+enum DbRef<'a> {
+    Sparse(&'a SparseDb),
+    /// A preprocessed database, laid out as `[instances, trials, poly_len,
+    /// num_per, dim0]` for `multiply_reg_by_database`.
+    Dense(&'a [u64]),
+}
 
-    let v_packing = public_params.v_packing.as_ref();
+/// The first-dimension vector and folding ciphertexts of a query.
+struct ExpandedQuery<'a> {
+    v_reg_reoriented: AlignedMemory64,
+    v_folding: Vec<PolyMatrixNTT<'a>>,
+    v_folding_neg: Vec<PolyMatrixNTT<'a>>,
+}
 
+/// Moves an expanded first dimension from the layout of the sparse kernel,
+/// `[dim0, ct_rows, poly_len]`, to that of the dense one, `[poly_len, dim0,
+/// ct_rows]`.
+fn to_dense_layout(params: &Params, v_reg: &[u64]) -> AlignedMemory64 {
+    let poly_len = params.poly_len;
+    let dim0 = 1 << params.db_dim_1;
+    let mut out = AlignedMemory64::new(v_reg.len());
+    let out_slice = out.as_mut_slice();
+    for j in 0..dim0 {
+        for r in 0..2 {
+            for z in 0..poly_len {
+                out_slice[z * (dim0 * 2) + j * 2 + r] =
+                    v_reg[j * (2 * poly_len) + r * poly_len + z];
+            }
+        }
+    }
+    out
+}
+
+fn expand<'a>(
+    params: &'a Params,
+    public_params: &PublicParameters<'a>,
+    query: &Query<'a>,
+    db: DbRef,
+) -> ExpandedQuery<'a> {
     let mut v_reg_reoriented;
     let v_folding;
     if params.expand_queries {
-        (v_reg_reoriented, v_folding) =
-            expand_query(params, public_params, query, Some(&db.db_idx_to_vec_idx));
+        match db {
+            DbRef::Sparse(db) => {
+                (v_reg_reoriented, v_folding) =
+                    expand_query(params, public_params, query, Some(&db.db_idx_to_vec_idx));
+            }
+            DbRef::Dense(_) => {
+                let v_reg;
+                (v_reg, v_folding) = expand_query(params, public_params, query, None);
+                v_reg_reoriented = to_dense_layout(params, v_reg.as_slice());
+            }
+        }
     } else {
         v_reg_reoriented = AlignedMemory64::new(query.v_buf.as_ref().unwrap().len());
         v_reg_reoriented
@@ -48,73 +103,175 @@ pub fn process_query(
     }
 
     let v_folding_neg = get_v_folding_neg(params, &v_folding);
+    ExpandedQuery {
+        v_reg_reoriented,
+        v_folding,
+        v_folding_neg,
+    }
+}
+
+/// Answers several queries, possibly from different clients, each with the
+/// public parameters it was made under. Up to `MAX_QUERIES_PER_PASS` queries
+/// share each pass over the database, which bounds throughput since the
+/// first dimension is limited by memory bandwidth. The responses are the
+/// same as `process_query` would give, in the order of `queries`.
+pub fn process_queries(
+    params: &Params,
+    queries: &[(&PublicParameters, &Query)],
+    db: &SparseDb,
+) -> Vec<Vec<u8>> {
+    queries
+        .chunks(MAX_QUERIES_PER_PASS)
+        .flat_map(|chunk| {
+            process_queries_in_one_pass(params, chunk, DbRef::Sparse(db), 0..params.instances)
+        })
+        .collect()
+}
+
+/// `process_queries` against a preprocessed database, as loaded by
+/// `load_preprocessed_db_from_file`. Needs two CRT moduli.
+pub fn process_queries_dense(
+    params: &Params,
+    queries: &[(&PublicParameters, &Query)],
+    db: &[u64],
+) -> Vec<Vec<u8>> {
+    process_queries_dense_shard(params, queries, db, 0..params.instances)
+}
+
+/// `process_queries_dense` for a worker holding only the database slices of
+/// `instances`, as loaded by `DenseDb::open_instances`. Each response is the
+/// part of the full one for those instances; concatenating the parts for
+/// consecutive ranges, in order, gives the full response.
+pub fn process_queries_dense_shard(
+    params: &Params,
+    queries: &[(&PublicParameters, &Query)],
+    db: &[u64],
+    instances: Range<usize>,
+) -> Vec<Vec<u8>> {
+    assert!(!instances.is_empty() && instances.end <= params.instances);
+    assert_eq!(db.len(), DenseDb::instance_words(params) * instances.len());
+    queries
+        .chunks(MAX_QUERIES_PER_PASS)
+        .flat_map(|chunk| {
+            process_queries_in_one_pass(params, chunk, DbRef::Dense(db), instances.clone())
+        })
+        .collect()
+}
+
+fn process_queries_in_one_pass(
+    params: &Params,
+    queries: &[(&PublicParameters, &Query)],
+    db: DbRef,
+    instances: Range<usize>,
+) -> Vec<Vec<u8>> {
+    let dim0 = 1 << params.db_dim_1;
+    let num_per = 1 << params.db_dim_2;
+
+    let expanded: Vec<ExpandedQuery> = queries
+        .par_iter()
+        .map(|(public_params, query)| expand(params, public_params, query, db))
+        .collect();
+    let v_reg_slices: Vec<&[u64]> = expanded
+        .iter()
+        .map(|e| e.v_reg_reoriented.as_slice())
+        .collect();
 
+    // v_cts[instance_trial - first_trial][q]; a dense db starts at first_trial
     let trials = params.n * params.n;
-    let v_cts: Vec<PolyMatrixRaw> = (0..(params.instances * trials))
+    let first_trial = instances.start * trials;
+    let v_cts: Vec<Vec<PolyMatrixRaw>> = (first_trial..(instances.end * trials))
         .into_par_iter()
         .map(|instance_trial| {
-            let instance = instance_trial / trials;
-            let trial = instance_trial % trials;
-
-            let mut intermediate = Vec::with_capacity(num_per);
-            let mut intermediate_raw = Vec::with_capacity(num_per);
-            for _ in 0..num_per {
-                intermediate.push(PolyMatrixNTT::zero(params, 2, 1));
-                intermediate_raw.push(PolyMatrixRaw::zero(params, 2, 1));
+            let mut intermediate = vec![Vec::with_capacity(num_per); queries.len()];
+            for out in intermediate.iter_mut() {
+                for _ in 0..num_per {
+                    out.push(PolyMatrixNTT::zero(params, 2, 1));
+                }
             }
 
-            // let now = Instant::now();
-            multiply_reg_by_sparse_database(
-                &mut intermediate,
-                db,
-                v_reg_reoriented.as_slice(),
-                params,
-                dim0,
-                num_per,
-                instance * trials + trial,
-            );
-            // println!("mul took {} us", now.elapsed().as_micros());
-
-            // let now = Instant::now();
-            for i in 0..intermediate.len() {
-                from_ntt(&mut intermediate_raw[i], &intermediate[i]);
+            match db {
+                DbRef::Sparse(db) => multiply_reg_by_sparse_database_many(
+                    &mut intermediate,
+                    db,
+                    &v_reg_slices,
+                    params,
+                    dim0,
+                    num_per,
+                    instance_trial,
+                ),
+                DbRef::Dense(db) => {
+                    let slice_sz = dim0 * num_per * params.poly_len;
+                    let offs = (instance_trial - first_trial) * slice_sz;
+                    let cur_db = &db[offs..offs + slice_sz];
+                    multiply_reg_by_database_many(
+                        &mut intermediate,
+                        cur_db,
+                        &v_reg_slices,
+                        params,
+                        dim0,
+                        num_per,
+                    );
+                }
             }
 
-            fold_ciphertexts(params, &mut intermediate_raw, &v_folding, &v_folding_neg);
-            // println!("fold took {} us", now.elapsed().as_micros());
-
-            intermediate_raw[0].clone()
+            intermediate
+                .iter()
+                .zip(expanded.iter())
+                .map(|(intermediate, e)| {
+                    let mut intermediate_raw: Vec<PolyMatrixRaw> =
+                        intermediate.iter().map(|x| x.raw()).collect();
+                    fold_ciphertexts(
+                        params,
+                        &mut intermediate_raw,
+                        &e.v_folding,
+                        &e.v_folding_neg,
+                    );
+                    intermediate_raw.swap_remove(0)
+                })
+                .collect()
         })
         .collect();
 
-    let v_packed_ct = v_cts
-        .par_chunks_exact(trials)
-        .map(|chunk: &[PolyMatrixRaw]| {
-            let packed_ct = pack(params, chunk, &v_packing);
-            packed_ct.raw()
+    let instances = instances.len();
+    let v_packed_cts: Vec<PolyMatrixRaw> = (0..(queries.len() * instances))
+        .into_par_iter()
+        .map(|query_instance| {
+            let q = query_instance / instances;
+            let instance = query_instance % instances;
+            let chunk: Vec<PolyMatrixRaw> = v_cts[instance * trials..(instance + 1) * trials]
+                .iter()
+                .map(|cts| cts[q].clone())
+                .collect();
+            pack(params, &chunk, &queries[q].0.v_packing).raw()
         })
         .collect();
 
-    encode(params, &v_packed_ct)
+    v_packed_cts
+        .chunks_exact(instances)
+        .map(|v_packed_ct| encode(params, v_packed_ct))
+        .collect()
+}
+
+/// The bytes of a response that hold one instance. Since `poly_len` is a
+/// multiple of 64, each instance's part is whole words, and a response is
+/// exactly `params.instances` of them.
+pub fn instance_response_bytes(params: &Params) -> usize {
+    let q1_bits = log2_ceil(params.q1) as usize;
+    let q2_bits = params.q2_bits as usize;
+    (q2_bits * params.n + q1_bits * params.n * params.n) * params.poly_len / 8
 }
 
-pub fn encode(params: &Params, v_packed_ct: &Vec<PolyMatrixRaw>) -> Vec<u8> {
-    let q1 = 4 * params.pt_modulus;
+/// Encodes the packed ciphertexts of consecutive instances; given all of
+/// them, this is a full response.
+pub fn encode(params: &Params, v_packed_ct: &[PolyMatrixRaw]) -> Vec<u8> {
+    let q1 = params.q1;
     let q1_bits = log2_ceil(q1) as usize;
     let q2 = Q2_VALUES[params.q2_bits as usize];
     let q2_bits = params.q2_bits as usize;
 
-    let num_bits = params.instances
-        * ((q2_bits * params.n * params.poly_len)
-            + (q1_bits * params.n * params.n * params.poly_len));
-    let round_to = 64;
-    let num_bytes_rounded_up = ((num_bits + round_to - 1) / round_to) * round_to / 8;
-
-    let mut result = vec![0u8; num_bytes_rounded_up];
+    let mut result = vec![0u8; instance_response_bytes(params) * v_packed_ct.len()];
     let mut bit_offs = 0;
-    for instance in 0..params.instances {
-        let packed_ct = &v_packed_ct[instance];
-
+    for packed_ct in v_packed_ct {
         let mut first_row = packed_ct.submatrix(0, 0, 1, packed_ct.cols);
         let mut rest_rows = packed_ct.submatrix(1, 0, packed_ct.rows - 1, packed_ct.cols);
         first_row.apply_func(|x| rescale(x, params.modulus, q2));
@@ -204,4 +361,81 @@ mod test {
     fn full_protocol_is_correct() {
         full_protocol_is_correct_for_params(&get_params());
     }
+
+    #[test]
+    fn batched_queries_are_correct() {
+        let params = get_params();
+        let mut seeded_rng = util::get_seeded_rng();
+        let num_items = 1 << (params.db_dim_1 + params.db_dim_2);
+        let target_idx = seeded_rng.gen::<usize>() % num_items;
+        let (corr_db_item, db) = generate_fake_sparse_db_and_get_item(&params, target_idx, 1000);
+        let p_bits = log2_ceil(params.pt_modulus) as usize;
+        let corr_result = corr_db_item.to_vec(p_bits, params.modp_words_per_chunk());
+
+        let mut client_a = Client::init(&params);
+        let public_params_a = client_a.generate_keys();
+        let mut client_b = Client::init(&params);
+        let public_params_b = client_b.generate_keys();
+
+        let other_idx = (target_idx + 1) % num_items;
+        let query_a = client_a.generate_query(target_idx);
+        let query_a_other = client_a.generate_query(other_idx);
+        let query_b = client_b.generate_query(target_idx);
+        let responses = process_queries(
+            &params,
+            &[
+                (&public_params_a, &query_a),
+                (&public_params_b, &query_b),
+                (&public_params_a, &query_a_other),
+            ],
+            &db,
+        );
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses[0],
+            process_query(&params, &public_params_a, &query_a, &db)
+        );
+        assert_eq!(client_a.decode_response(&responses[0]), corr_result);
+        assert_eq!(client_b.decode_response(&responses[1]), corr_result);
+        assert_eq!(
+            responses[2],
+            process_query(&params, &public_params_a, &query_a_other, &db)
+        );
+    }
+
+    #[test]
+    fn sharded_responses_are_correct() {
+        let params = util::params_from_json(
+            r#"{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 22, "t_gsw": 7,
+            "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5, "instances": 3,
+            "db_item_size": 12288}"#,
+        );
+        let target_idx = 77;
+        let (corr_db_item, db) = generate_random_db_and_get_item(&params, target_idx);
+        let p_bits = log2_ceil(params.pt_modulus) as usize;
+        let corr_result = corr_db_item.to_vec(p_bits, params.modp_words_per_chunk());
+
+        let mut client = Client::init(&params);
+        let public_params = client.generate_keys();
+        let query = client.generate_query(target_idx);
+        let queries = [(&public_params, &query)];
+        let full = process_queries_dense(&params, &queries, db.as_slice());
+        assert_eq!(full[0].len(), params.response_bytes());
+        assert_eq!(client.decode_response(&full[0]), corr_result);
+
+        let instance_words = DenseDb::instance_words(&params);
+        let mut combined = Vec::new();
+        for instances in [0..1, 1..3] {
+            let shard =
+                &db.as_slice()[instances.start * instance_words..instances.end * instance_words];
+            let part = process_queries_dense_shard(&params, &queries, shard, instances.clone());
+            assert_eq!(
+                part[0].len(),
+                instance_response_bytes(&params) * instances.len()
+            );
+            combined.extend_from_slice(&part[0]);
+        }
+        assert_eq!(combined, full[0]);
+    }
 }
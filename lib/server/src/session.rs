@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use filetime::FileTime;
+use spiral_rs::client::PublicParameters;
+use spiral_rs::params::Params;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// Limits on the sessions (uploaded public parameters) held by the server.
+/// `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    /// Sessions unused for this long are dropped.
+    pub ttl: Option<Duration>,
+    /// The maximum number of sessions; the least recently used are evicted first.
+    pub max_sessions: Option<usize>,
+    /// The maximum total size of all sessions, in bytes.
+    pub max_bytes: Option<usize>,
+}
+
+/// How often a session's file is touched while the session is in use.
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+type EvictFn<T> = Box<dyn Fn(&str, &T) + Send>;
+
+struct Session<T> {
+    value: Arc<T>,
+    size: usize,
+    last_used: Instant,
+    tick: u64,
+}
+
+/// Sessions keyed by UUID, with TTL expiry and least-recently-used eviction.
+///
+/// Since the TTL is measured from last use, expired sessions are always the
+/// least recently used ones, so expiry and eviction share one ordering.
+pub struct SessionStore<T> {
+    config: SessionConfig,
+    sessions: HashMap<String, Session<T>>,
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    total_bytes: usize,
+    on_evict: Option<EvictFn<T>>,
+}
+
+impl<T> SessionStore<T> {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            config,
+            sessions: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            total_bytes: 0,
+            on_evict: None,
+        }
+    }
+
+    /// Sets a function to call whenever a session is evicted or removed.
+    pub fn on_evict(mut self, f: impl Fn(&str, &T) + Send + 'static) -> Self {
+        self.on_evict = Some(Box::new(f));
+        self
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove_session(&mut self, uuid: &str) -> Option<Session<T>> {
+        let session = self.sessions.remove(uuid)?;
+        self.lru.remove(&session.tick);
+        self.total_bytes -= session.size;
+        Some(session)
+    }
+
+    fn evict(&mut self, uuid: &str) -> Option<Arc<T>> {
+        let session = self.remove_session(uuid)?;
+        if let Some(on_evict) = &self.on_evict {
+            on_evict(uuid, &session.value);
+        }
+        Some(session.value)
+    }
+
+    fn is_expired(&self, session: &Session<T>, now: Instant) -> bool {
+        match self.config.ttl {
+            Some(ttl) => now.duration_since(session.last_used) > ttl,
+            None => false,
+        }
+    }
+
+    fn evict_lru(&mut self) -> bool {
+        let oldest = self.lru.iter().next().map(|(_, uuid)| uuid.clone());
+        match oldest {
+            Some(uuid) => self.evict(&uuid).is_some(),
+            None => false,
+        }
+    }
+
+    /// Drops every session that has outlived the TTL.
+    pub fn evict_expired(&mut self) {
+        let now = Instant::now();
+        while let Some((_, uuid)) = self.lru.iter().next() {
+            if !self.is_expired(&self.sessions[uuid], now) {
+                break;
+            }
+            let uuid = uuid.clone();
+            self.evict(&uuid);
+        }
+    }
+
+    /// Adds a session of `size` bytes, evicting the least recently used
+    /// sessions as needed to stay within the configured limits. A session
+    /// too large to ever fit is passed straight to the eviction function.
+    pub fn insert(&mut self, uuid: String, value: T, size: usize) -> Result<(), Error> {
+        self.insert_used_at(uuid, value, size, Instant::now())
+    }
+
+    /// `insert` for a session last used at `last_used`, as when restoring
+    /// sessions, which must then be inserted least recently used first.
+    pub fn insert_used_at(
+        &mut self,
+        uuid: String,
+        value: T,
+        size: usize,
+        last_used: Instant,
+    ) -> Result<(), Error> {
+        if let Some(max_bytes) = self.config.max_bytes {
+            if size > max_bytes {
+                if let Some(on_evict) = &self.on_evict {
+                    on_evict(&uuid, &value);
+                }
+                return Err(Error::TooLarge(size, max_bytes));
+            }
+        }
+
+        self.evict_expired();
+        self.remove_session(&uuid);
+        while self.exceeds_limits(1, size) && self.evict_lru() {}
+
+        let tick = self.next_tick();
+        self.lru.insert(tick, uuid.clone());
+        self.total_bytes += size;
+        self.sessions.insert(
+            uuid,
+            Session {
+                value: Arc::new(value),
+                size,
+                last_used,
+                tick,
+            },
+        );
+        Ok(())
+    }
+
+    fn exceeds_limits(&self, extra_sessions: usize, extra_bytes: usize) -> bool {
+        let too_many = self
+            .config
+            .max_sessions
+            .is_some_and(|max| self.sessions.len() + extra_sessions > max);
+        let too_large = self
+            .config
+            .max_bytes
+            .is_some_and(|max| self.total_bytes + extra_bytes > max);
+        too_many || too_large
+    }
+
+    /// Looks up a session, marking it as recently used.
+    pub fn get(&mut self, uuid: &str) -> Option<Arc<T>> {
+        self.evict_expired();
+        let tick = self.next_tick();
+        let session = self.sessions.get_mut(uuid)?;
+        self.lru.remove(&session.tick);
+        self.lru.insert(tick, uuid.to_owned());
+        session.tick = tick;
+        session.last_used = Instant::now();
+        Some(session.value.clone())
+    }
+
+    /// Returns whether a session exists, without marking it as used.
+    pub fn contains(&mut self, uuid: &str) -> bool {
+        self.evict_expired();
+        self.sessions.contains_key(uuid)
+    }
+
+    pub fn remove(&mut self, uuid: &str) -> Option<Arc<T>> {
+        self.evict(uuid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+}
+
+/// The in-memory size of any set of public parameters for `params`.
+pub fn public_parameters_bytes_for(params: &Params) -> usize {
+    let placeholder = PublicParameters::deserialize(params, &vec![0u8; params.setup_bytes()]);
+    public_parameters_bytes(&placeholder)
+}
+
+/// The in-memory size of a set of public parameters.
+pub fn public_parameters_bytes(pub_params: &PublicParameters) -> usize {
+    let v_expansion_left = pub_params.v_expansion_left.iter().flatten();
+    let v_expansion_right = pub_params.v_expansion_right.iter().flatten();
+    let v_conversion = pub_params.v_conversion.iter().flatten();
+    pub_params
+        .v_packing
+        .iter()
+        .chain(v_expansion_left)
+        .chain(v_expansion_right)
+        .chain(v_conversion)
+        .map(|mat| mat.data.len() * size_of::<u64>())
+        .sum()
+}
+
+/// A client's uploaded public parameters, backed by their serialized form on
+/// disk when the server has a data directory. Sessions restored after a
+/// restart are only deserialized on first use.
+pub struct StoredPublicParameters {
+    /// Borrows from `params`, so it is declared, and dropped, first.
+    pub_params: OnceLock<PublicParameters<'static>>,
+    params: Arc<Params>,
+    path: Option<PathBuf>,
+    last_touched: Mutex<Option<Instant>>,
+}
+
+impl StoredPublicParameters {
+    /// Deserializes freshly uploaded public parameters, kept in memory only
+    /// until `stored_at` says where they were written.
+    pub fn deserialize(params: Arc<Params>, data: &[u8]) -> Result<Self, Error> {
+        let pub_params = PublicParameters::try_deserialize(borrow_params(&params), data)?;
+        Ok(Self {
+            pub_params: OnceLock::from(pub_params),
+            params,
+            path: None,
+            last_touched: Mutex::new(Some(Instant::now())),
+        })
+    }
+
+    /// Backs the public parameters with their serialized form at `path`.
+    pub fn stored_at(mut self, path: Option<PathBuf>) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Refers to public parameters stored at `path`, without reading them yet.
+    pub fn on_disk(params: Arc<Params>, path: PathBuf) -> Self {
+        Self {
+            pub_params: OnceLock::new(),
+            params,
+            path: Some(path),
+            last_touched: Mutex::new(None),
+        }
+    }
+
+    /// Returns the public parameters, reading them from disk if needed.
+    pub fn get(&self) -> Result<&PublicParameters<'_>, Error> {
+        if let Some(pub_params) = self.pub_params.get() {
+            return Ok(pub_params);
+        }
+        let path = self.path.as_ref().ok_or(Error::NotFound)?;
+        // the session may have been evicted, and its file removed, since it
+        // was looked up
+        let data = fs::read(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound,
+            _ => Error::from(e),
+        })?;
+        let pub_params = PublicParameters::try_deserialize(borrow_params(&self.params), &data)
+            .map_err(|e| Error::Corrupted(format!("bad session file {}: {}", path.display(), e)))?;
+        Ok(self.pub_params.get_or_init(|| pub_params))
+    }
+
+    /// Records a use of the session in its file's modification time, which
+    /// `list_session_files` reads back after a restart. To save a write per
+    /// query, the file is touched at most once per `TOUCH_INTERVAL`.
+    pub fn touch(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut last_touched = self.last_touched.lock().unwrap();
+        if last_touched.is_some_and(|t| t.elapsed() < TOUCH_INTERVAL) {
+            return;
+        }
+        *last_touched = Some(Instant::now());
+        // a failure only shortens the session's life after a restart
+        let _ = filetime::set_file_mtime(path, FileTime::now());
+    }
+
+    /// Deletes the serialized public parameters from disk, if they are stored there.
+    pub fn remove_file(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::remove_file(path) {
+                println!("Could not remove session file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Borrows `params` for the public parameters a `StoredPublicParameters`
+/// holds alongside them.
+fn borrow_params(params: &Arc<Params>) -> &'static Params {
+    // Safety: the `Params` are on the heap, so they stay put when the
+    // `Arc` moves, and the `StoredPublicParameters` holding the `Arc` drops
+    // its public parameters first. `get` only lends those out for as long as
+    // it is borrowed itself.
+    unsafe { &*Arc::as_ptr(params) }
+}
+
+/// Writes serialized public parameters to `dir`, returning the file's path.
+///
+/// The file is written under a temporary name and then renamed, so a crash
+/// never leaves a partial session behind; it is not fsynced, since a lost
+/// session only means the client has to call `/setup` again.
+pub fn write_session_file(dir: &Path, uuid: &str, data: &[u8]) -> Result<PathBuf, Error> {
+    let path = dir.join(uuid);
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(path)
+}
+
+/// Finds the sessions stored in `dir` by `write_session_file`, least recently
+/// used first, along with when they were last used (see
+/// `StoredPublicParameters::touch`). Files that are incomplete or were
+/// written for other parameters are skipped.
+pub fn list_session_files(
+    dir: &Path,
+    params: &Params,
+) -> Result<Vec<(String, PathBuf, SystemTime)>, Error> {
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let uuid = entry.file_name().to_string_lossy().into_owned();
+        if Uuid::parse_str(&uuid).is_err() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.len() as usize != params.setup_bytes() {
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        sessions.push((uuid, entry.path(), modified));
+    }
+    sessions.sort_by_key(|(_, _, modified)| *modified);
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{get_params, temp_dir};
+    use spiral_rs::client::Client;
+    use std::thread;
+
+    #[test]
+    fn session_store_evicts_lru() {
+        let mut store = SessionStore::new(SessionConfig {
+            max_sessions: Some(2),
+            ..Default::default()
+        });
+        store.insert("a".to_owned(), 1, 10).unwrap();
+        store.insert("b".to_owned(), 2, 10).unwrap();
+        assert_eq!(*store.get("a").unwrap(), 1);
+        store.insert("c".to_owned(), 3, 10).unwrap();
+
+        assert!(store.contains("a"));
+        assert!(!store.contains("b"));
+        assert!(store.contains("c"));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.total_bytes(), 20);
+    }
+
+    #[test]
+    fn session_store_respects_memory_budget() {
+        let mut store = SessionStore::new(SessionConfig {
+            max_bytes: Some(100),
+            ..Default::default()
+        });
+        store.insert("a".to_owned(), 1, 40).unwrap();
+        store.insert("b".to_owned(), 2, 40).unwrap();
+        store.insert("c".to_owned(), 3, 40).unwrap();
+        assert!(!store.contains("a"));
+        assert_eq!(store.total_bytes(), 80);
+
+        assert!(store.insert("d".to_owned(), 4, 101).is_err());
+        store.insert("d".to_owned(), 4, 100).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.total_bytes(), 100);
+    }
+
+    #[test]
+    fn session_store_expires_sessions() {
+        let mut store = SessionStore::new(SessionConfig {
+            ttl: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        store.insert("a".to_owned(), 1, 10).unwrap();
+        store.insert("b".to_owned(), 2, 10).unwrap();
+        thread::sleep(Duration::from_millis(60));
+        assert!(store.get("a").is_some());
+        thread::sleep(Duration::from_millis(60));
+
+        assert!(store.contains("a"));
+        assert!(!store.contains("b"));
+        assert_eq!(store.total_bytes(), 10);
+    }
+
+    #[test]
+    fn stored_public_parameters_roundtrip_is_correct() {
+        let params = get_params();
+        let mut client = Client::init(&params);
+        let data = client.generate_keys().serialize();
+
+        let dir = temp_dir();
+        let uuid = Uuid::new_v4().to_string();
+        write_session_file(&dir, &uuid, &data).unwrap();
+        fs::write(dir.join("not-a-session"), b"").unwrap();
+
+        let sessions = list_session_files(&dir, &params).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].0, uuid);
+
+        let stored = StoredPublicParameters::on_disk(params.clone(), sessions[0].1.clone());
+        assert_eq!(stored.get().unwrap().serialize(), data);
+        stored.remove_file();
+        assert!(list_session_files(&dir, &params).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn session_files_record_last_use() {
+        let params = get_params();
+        let mut client = Client::init(&params);
+        let data = client.generate_keys().serialize();
+
+        let dir = temp_dir();
+        let old_uuid = Uuid::new_v4().to_string();
+        let old_path = write_session_file(&dir, &old_uuid, &data).unwrap();
+        let week_ago = SystemTime::now() - Duration::from_secs(7 * 24 * 60 * 60);
+        filetime::set_file_mtime(&old_path, FileTime::from_system_time(week_ago)).unwrap();
+        let new_uuid = Uuid::new_v4().to_string();
+        write_session_file(&dir, &new_uuid, &data).unwrap();
+
+        let sessions = list_session_files(&dir, &params).unwrap();
+        assert_eq!(sessions[0].0, old_uuid);
+        let old = StoredPublicParameters::on_disk(params.clone(), old_path);
+        old.touch();
+        let sessions = list_session_files(&dir, &params).unwrap();
+        assert_eq!(sessions[1].0, old_uuid);
+        assert!(sessions[1].2.elapsed().unwrap() < Duration::from_secs(60));
+
+        // evicted, and its file removed, before it was loaded
+        old.remove_file();
+        assert!(matches!(old.get(), Err(Error::NotFound)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
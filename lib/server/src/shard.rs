@@ -0,0 +1,271 @@
+use std::io::Read;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+use spiral_rs::params::Params;
+use spiral_rs::util::try_params_from_json_obj;
+use spiral_rs::wire::{decode_frame, encode_frame, FrameKind, WIRE_CONTENT_TYPE};
+
+use crate::error::{Error, ErrorResponse};
+use crate::server::instance_response_bytes;
+use crate::session::{SessionConfig, SessionStore};
+
+const UUID_V4_STR_BYTES: usize = 36;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a worker may go without sending or accepting data, which covers
+/// its pass over its slice of the database.
+const IO_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A server holding the database slices of some of a bucket's instances.
+#[derive(Debug, Clone)]
+pub struct ShardWorker {
+    /// The URL of the worker's bucket, e.g. `http://10.0.0.2:8008/default`.
+    pub url: String,
+    pub instances: Range<usize>,
+}
+
+#[derive(Deserialize)]
+struct WorkerMeta {
+    pir_scheme: serde_json::Value,
+    instances: Option<(usize, usize)>,
+}
+
+#[derive(Deserialize)]
+struct WorkerSetup {
+    uuid: String,
+}
+
+/// Answers private reads for a bucket whose instances are split across
+/// workers. Each query goes to every worker, which computes and packs the
+/// ciphertexts of its instances; since `encode` writes instances one after
+/// another, the workers' responses, concatenated in instance order, are the
+/// response a single server would give.
+///
+/// A session maps to one session on each worker. Workers expire their
+/// sessions on their own; a query for one they have dropped fails with
+/// `NotFound`, and the client sets up again.
+pub struct Coordinator {
+    params: Arc<Params>,
+    workers: Vec<ShardWorker>,
+    sessions: Mutex<SessionStore<Vec<String>>>,
+    agent: ureq::Agent,
+}
+
+impl Coordinator {
+    /// Connects to the workers at `urls`, each the URL of a static bucket,
+    /// and reads which instances each holds. The workers must use `params`,
+    /// and together hold every instance exactly once.
+    pub fn connect(
+        params: Arc<Params>,
+        urls: &[String],
+        sessions: SessionConfig,
+    ) -> Result<Self, Error> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(IO_TIMEOUT)
+            .timeout_write(IO_TIMEOUT)
+            .build();
+        let mut workers = Vec::new();
+        for url in urls {
+            let url = url.trim_end_matches('/').to_owned();
+            let response = agent
+                .get(&format!("{}/meta", url))
+                .call()
+                .map_err(|e| worker_error(&url, e))?;
+            let meta: WorkerMeta = serde_json::from_reader(response.into_reader())
+                .map_err(|e| Error::Upstream(format!("{}: bad /meta: {}", url, e)))?;
+            let worker_params = try_params_from_json_obj(&meta.pir_scheme)
+                .map_err(|e| Error::InvalidParams(format!("{}: {}", url, e)))?;
+            if worker_params != *params {
+                return Err(Error::InvalidParams(format!(
+                    "{} uses a different scheme",
+                    url
+                )));
+            }
+            let (start, end) = meta
+                .instances
+                .ok_or_else(|| Error::InvalidParams(format!("{} is not a static bucket", url)))?;
+            workers.push(ShardWorker {
+                url,
+                instances: start..end,
+            });
+        }
+
+        workers.sort_by_key(|worker| worker.instances.start);
+        let mut next = 0;
+        for worker in &workers {
+            if worker.instances.start != next {
+                return Err(Error::InvalidParams(format!(
+                    "workers hold instances from {} where {} is expected",
+                    worker.instances.start, next
+                )));
+            }
+            next = worker.instances.end;
+        }
+        if next != params.instances {
+            return Err(Error::InvalidParams(format!(
+                "workers hold {} of {} instances",
+                next, params.instances
+            )));
+        }
+
+        Ok(Self {
+            params,
+            workers,
+            sessions: Mutex::new(SessionStore::new(sessions)),
+            agent,
+        })
+    }
+
+    pub fn workers(&self) -> &[ShardWorker] {
+        &self.workers
+    }
+
+    /// Sets up a session on every worker, returning a UUID that stands for
+    /// all of them. If any worker fails, the sessions the others set up are
+    /// left to expire there, since workers have no way to drop one early.
+    pub fn setup(&self, data: &[u8]) -> Result<String, Error> {
+        let frame = encode_frame(FrameKind::Setup, &[data]);
+        let worker_uuids = self.on_each_worker(|_, worker| {
+            let response = self
+                .agent
+                .post(&format!("{}/setup", worker.url))
+                .set("Content-Type", WIRE_CONTENT_TYPE)
+                .send_bytes(&frame)
+                .map_err(|e| worker_error(&worker.url, e))?;
+            let setup: WorkerSetup = serde_json::from_reader(response.into_reader())
+                .map_err(|e| Error::Upstream(format!("{}: bad /setup: {}", worker.url, e)))?;
+            if setup.uuid.len() != UUID_V4_STR_BYTES {
+                return Err(Error::Upstream(format!("{}: bad UUID", worker.url)));
+            }
+            Ok(setup.uuid)
+        })?;
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let size = worker_uuids.len() * UUID_V4_STR_BYTES;
+        self.sessions
+            .lock()?
+            .insert(uuid.clone(), worker_uuids, size)?;
+        Ok(uuid)
+    }
+
+    /// Whether the session `uuid` is live here, without extending it.
+    pub fn has_session(&self, uuid: &str) -> Result<bool, Error> {
+        Ok(self.sessions.lock()?.contains(uuid))
+    }
+
+    /// Answers queries in the format of `Bucket::private_read`, by sending
+    /// each worker its part of them in one request.
+    pub fn private_reads(&self, queries: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, Error> {
+        let params = &*self.params;
+        let mut worker_uuids = Vec::new();
+        for query in queries {
+            if params.expand_queries {
+                let expected_len = UUID_V4_STR_BYTES + params.query_bytes();
+                if query.len() != expected_len {
+                    return Err(Error::InvalidLength(query.len(), expected_len));
+                }
+                let uuid = std::str::from_utf8(&query[..UUID_V4_STR_BYTES])
+                    .map_err(|_| Error::NotFound)?;
+                worker_uuids.push(Some(
+                    self.sessions.lock()?.get(uuid).ok_or(Error::NotFound)?,
+                ));
+            } else {
+                // the public parameters are in the query, for every worker
+                let expected_len = params.setup_bytes() + params.query_bytes();
+                if query.len() != expected_len {
+                    return Err(Error::InvalidLength(query.len(), expected_len));
+                }
+                worker_uuids.push(None);
+            }
+        }
+
+        let parts: Vec<Vec<Vec<u8>>> = self.on_each_worker(|i, worker| {
+            let worker_queries: Vec<Vec<u8>> = queries
+                .iter()
+                .zip(worker_uuids.iter())
+                .map(|(query, uuids)| match uuids {
+                    Some(uuids) => {
+                        let mut worker_query = uuids[i].as_bytes().to_vec();
+                        worker_query.extend_from_slice(&query[UUID_V4_STR_BYTES..]);
+                        worker_query
+                    }
+                    None => query.clone(),
+                })
+                .collect();
+            let response = self
+                .agent
+                .post(&format!("{}/private-read", worker.url))
+                .set("Content-Type", WIRE_CONTENT_TYPE)
+                .set("Accept", WIRE_CONTENT_TYPE)
+                .send_bytes(&encode_frame(FrameKind::Queries, &worker_queries))
+                .map_err(|e| worker_error(&worker.url, e))?;
+            let mut body = Vec::new();
+            response
+                .into_reader()
+                .read_to_end(&mut body)
+                .map_err(|e| Error::Upstream(format!("{}: {}", worker.url, e)))?;
+
+            let part_len = instance_response_bytes(params) * worker.instances.len();
+            let bad_response = || Error::Upstream(format!("{}: bad response", worker.url));
+            let responses =
+                decode_frame(&body, FrameKind::Responses).map_err(|_| bad_response())?;
+            if responses.len() != queries.len()
+                || responses.iter().any(|part| part.len() != part_len)
+            {
+                return Err(bad_response());
+            }
+            Ok(responses.into_iter().map(|part| part.to_vec()).collect())
+        })?;
+
+        let mut results = vec![Vec::with_capacity(params.response_bytes()); queries.len()];
+        for worker_parts in parts {
+            for (result, part) in results.iter_mut().zip(worker_parts) {
+                result.extend_from_slice(&part);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Runs `f` for every worker concurrently, returning the results in
+    /// instance order, or the first error.
+    fn on_each_worker<T: Send>(
+        &self,
+        f: impl Fn(usize, &ShardWorker) -> Result<T, Error> + Sync,
+    ) -> Result<Vec<T>, Error> {
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .workers
+                .iter()
+                .enumerate()
+                .map(|(index, worker)| {
+                    let f = &f;
+                    scope.spawn(move || f(index, worker))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().map_err(|_| Error::Unknown)?)
+                .collect()
+        })
+    }
+}
+
+/// Maps a worker's failure to an error for the client: not-found and other
+/// client errors are passed on, and anything else, including a timeout, is
+/// the worker's fault.
+fn worker_error(url: &str, e: ureq::Error) -> Error {
+    match e {
+        ureq::Error::Status(404, _) => Error::NotFound,
+        ureq::Error::Status(status, response) if (400..500).contains(&status) => {
+            match serde_json::from_reader::<_, ErrorResponse>(response.into_reader()) {
+                Ok(response) => Error::InvalidRequest(response.error),
+                Err(_) => Error::InvalidRequest(format!("{} returned {}", url, status)),
+            }
+        }
+        e => Error::Upstream(format!("{}: {}", url, e)),
+    }
+}
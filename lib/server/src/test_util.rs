@@ -0,0 +1,54 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use spiral_rs::params::Params;
+use spiral_rs::util::params_from_json;
+use uuid::Uuid;
+
+/// The server's default scheme.
+pub fn get_params() -> Arc<Params> {
+    Arc::new(params_from_json(
+        r#"{
+        "n": 2,
+        "nu_1": 9,
+        "nu_2": 5,
+        "p": 256,
+        "q2_bits": 22,
+        "t_gsw": 7,
+        "t_conv": 3,
+        "t_exp_left": 5,
+        "t_exp_right": 5,
+        "instances": 4,
+        "db_item_size": 32768
+    }"#,
+    ))
+}
+
+/// Parameters small enough to build a dense database for.
+pub fn get_small_params() -> Arc<Params> {
+    Arc::new(params_from_json(
+        r#"{
+        "n": 2,
+        "nu_1": 6,
+        "nu_2": 2,
+        "p": 256,
+        "q2_bits": 22,
+        "t_gsw": 7,
+        "t_conv": 3,
+        "t_exp_left": 5,
+        "t_exp_right": 5,
+        "instances": 1,
+        "db_item_size": 4096
+    }"#,
+    ))
+}
+
+/// Creates a new, empty directory under the system's temporary directory.
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("spiral-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,197 @@
+//! Manages buckets through the routes of a server process.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose, Engine};
+use serde_json::{json, Value};
+use spiral_rs::client::Client;
+use spiral_rs::util;
+
+use common::{assert_error, get_json, post_json, start_server, temp_dir};
+
+const PARAMS_JSON: &str = r#"{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 22,
+    "t_gsw": 7, "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5, "instances": 1,
+    "db_item_size": 4096}"#;
+const SMALL_PARAMS_JSON: &str = r#"{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 22,
+    "t_gsw": 7, "t_conv": 3, "t_exp_left": 4, "t_exp_right": 4, "instances": 2,
+    "db_item_size": 2048}"#;
+
+fn bucket_names(url: &str) -> Vec<String> {
+    let (status, body) = get_json(&format!("{}/list-buckets", url));
+    assert_eq!(status, 200);
+    body["buckets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|meta| meta["name"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+fn write_params(dir: &Path) -> PathBuf {
+    let params_path = dir.join("params.json");
+    fs::write(&params_path, PARAMS_JSON).unwrap();
+    params_path
+}
+
+#[test]
+fn bucket_lifecycle_is_correct() {
+    let dir = temp_dir();
+    let params_path = write_params(&dir);
+    let data_dir = dir.join("data");
+    let envs = [("SPIRAL_DATA_DIR", data_dir.to_str().unwrap())];
+    let server = start_server(&params_path, &envs);
+    let url = &server.url;
+    assert_eq!(bucket_names(url), ["default"]);
+
+    let (status, meta) = post_json(
+        &format!("{}/create", url),
+        &json!({
+            "name": "b",
+            "parameters": { "maxItemSize": 1000, "keyStoragePolicy": "bloom" },
+            "open_access": true,
+        }),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(meta["name"], "b");
+    assert_eq!(meta["global_version"], 0);
+    assert_eq!(bucket_names(url), ["b", "default"]);
+
+    let value = general_purpose::STANDARD.encode(b"value");
+    let (status, _) = post_json(&format!("{}/b/write", url), &json!({ "key": value }));
+    assert_eq!(status, 200);
+    let (_, meta) = get_json(&format!("{}/b/meta", url));
+    assert_eq!(meta["global_version"], 1);
+
+    let (status, bloom) = get_json(&format!("{}/b/bloom", url));
+    assert_eq!(status, 200);
+    let bloom_url = bloom["url"].as_str().unwrap();
+    assert!(bloom_url.ends_with("/b/bloom-data"));
+    let bloom_data = ureq::get(bloom_url).call().unwrap();
+    assert_eq!(bloom_data.content_type(), "application/octet-stream");
+    assert_error(get_json(&format!("{}/bloom", url)), 400);
+
+    let (status, body) = post_json(&format!("{}/b/clear", url), &json!(null));
+    assert_eq!((status, body), (200, json!({ "status": "cleared" })));
+
+    let (status, meta) = post_json(
+        &format!("{}/b/modify", url),
+        &json!({ "open_access": false }),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(meta["open_access"], false);
+
+    let params = util::params_from_json(PARAMS_JSON);
+    let mut client = Client::init(&params);
+    let setup_data = general_purpose::STANDARD.encode(client.generate_keys().serialize());
+    let (status, setup) = post_json(&format!("{}/b/setup", url), &json!(setup_data));
+    assert_eq!(status, 200);
+    let uuid = setup["uuid"].as_str().unwrap().to_owned();
+    let (status, check) = get_json(&format!("{}/check/{}", url, uuid));
+    assert_eq!((status, check), (200, json!({ "uuid": uuid })));
+
+    // buckets, their changes and their sessions outlive the process
+    drop(server);
+    let server = start_server(&params_path, &envs);
+    let url = &server.url;
+    assert_eq!(bucket_names(url), ["b", "default"]);
+    let (_, meta) = get_json(&format!("{}/b/meta", url));
+    assert_eq!(meta["open_access"], false);
+    assert_eq!(get_json(&format!("{}/check/{}", url, uuid)).0, 200);
+
+    let (status, body) = post_json(&format!("{}/b/destroy", url), &json!(null));
+    assert_eq!((status, body), (200, json!({ "status": "destroyed" })));
+    assert_error(get_json(&format!("{}/b/meta", url)), 404);
+    assert_error(get_json(&format!("{}/check/{}", url, uuid)), 404);
+    assert_error(post_json(&format!("{}/b/destroy", url), &json!(null)), 404);
+    assert_eq!(bucket_names(url), ["default"]);
+
+    drop(server);
+    let server = start_server(&params_path, &envs);
+    assert_eq!(bucket_names(&server.url), ["default"]);
+
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn buckets_are_routed_by_name() {
+    let dir = temp_dir();
+    let params_path = write_params(&dir);
+    let server = start_server(&params_path, &[]);
+    let url = &server.url;
+
+    let scheme: Value = serde_json::from_str(SMALL_PARAMS_JSON).unwrap();
+    let (status, meta) = post_json(
+        &format!("{}/create", url),
+        &json!({ "name": "small", "pir_scheme": scheme }),
+    );
+    assert_eq!(status, 200);
+    assert_eq!(meta["pir_scheme"], scheme);
+    let (_, meta) = get_json(&format!("{}/small/meta", url));
+    assert_eq!(meta["pir_scheme"], scheme);
+    let (_, meta) = get_json(&format!("{}/meta", url));
+    assert_eq!(meta["name"], "default");
+    assert_eq!(meta["pir_scheme"]["instances"], 1);
+
+    let value = general_purpose::STANDARD.encode(b"value");
+    let (status, _) = post_json(&format!("{}/small/write", url), &json!({ "key": value }));
+    assert_eq!(status, 200);
+    let (_, meta) = get_json(&format!("{}/small/meta", url));
+    assert_eq!(meta["global_version"], 1);
+    let (_, meta) = get_json(&format!("{}/default/meta", url));
+    assert_eq!(meta["global_version"], 0);
+
+    // sessions are set up under the bucket's own scheme
+    let small_params = util::params_from_json(SMALL_PARAMS_JSON);
+    let mut client = Client::init(&small_params);
+    let setup_data = general_purpose::STANDARD.encode(client.generate_keys().serialize());
+    assert_eq!(
+        post_json(&format!("{}/small/setup", url), &json!(setup_data)).0,
+        200
+    );
+    assert_error(
+        post_json(&format!("{}/setup", url), &json!(setup_data)),
+        400,
+    );
+
+    assert_error(get_json(&format!("{}/missing/meta", url)), 404);
+    assert_error(
+        post_json(&format!("{}/missing/write", url), &json!({})),
+        404,
+    );
+    assert_error(
+        post_json(&format!("{}/create", url), &json!({ "name": "small" })),
+        409,
+    );
+    assert_error(
+        post_json(&format!("{}/create", url), &json!({ "name": "a/b" })),
+        400,
+    );
+
+    let (status, meta) = post_json(&format!("{}/small/modify", url), &json!({ "name": "tiny" }));
+    assert_eq!(status, 200);
+    assert_eq!(meta["name"], "tiny");
+    assert_error(get_json(&format!("{}/small/meta", url)), 404);
+    assert_eq!(get_json(&format!("{}/tiny/meta", url)).0, 200);
+    assert_error(
+        post_json(
+            &format!("{}/tiny/modify", url),
+            &json!({ "name": "default" }),
+        ),
+        409,
+    );
+    // the unscoped routes find the default bucket by its name
+    assert_error(
+        post_json(&format!("{}/modify", url), &json!({ "name": "renamed" })),
+        400,
+    );
+    let (_, meta) = get_json(&format!("{}/meta", url));
+    assert_eq!(meta["name"], "default");
+    assert_eq!(bucket_names(url), ["default", "tiny"]);
+
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
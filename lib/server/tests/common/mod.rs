@@ -0,0 +1,114 @@
+//! Helpers for tests that run server processes.
+
+#![allow(dead_code)]
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use spiral_rs::wire::{encode_frame, FrameKind, WIRE_CONTENT_TYPE};
+use uuid::Uuid;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A server process, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub url: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn start_server(params_path: &Path, envs: &[(&str, &str)]) -> Server {
+    let port = TcpListener::bind("localhost:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg(port.to_string())
+        .arg(params_path)
+        .envs(envs.iter().copied())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut server = Server {
+        child,
+        url: format!("http://localhost:{}", port),
+    };
+
+    let start = Instant::now();
+    while ureq::get(&server.url).call().is_err() {
+        assert!(
+            server.child.try_wait().unwrap().is_none(),
+            "server exited during startup"
+        );
+        assert!(start.elapsed() < STARTUP_TIMEOUT, "server did not start");
+        thread::sleep(Duration::from_millis(100));
+    }
+    server
+}
+
+/// Creates a new, empty directory under the system's temporary directory.
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("spiral-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Posts a frame, returning the response body, or the status of an error.
+pub fn post_wire(url: &str, kind: FrameKind, chunks: &[Vec<u8>]) -> Result<Vec<u8>, u16> {
+    let response = match ureq::post(url)
+        .set("Content-Type", WIRE_CONTENT_TYPE)
+        .set("Accept", WIRE_CONTENT_TYPE)
+        .send_bytes(&encode_frame(kind, chunks))
+    {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => return Err(status),
+        Err(e) => panic!("request failed: {}", e),
+    };
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body).unwrap();
+    Ok(body)
+}
+
+/// The status and JSON body of a response, successful or not.
+pub fn json_response(result: Result<ureq::Response, ureq::Error>) -> (u16, Value) {
+    let response = match result {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => panic!("request failed: {}", e),
+    };
+    let status = response.status();
+    let body = response.into_string().unwrap();
+    let value = serde_json::from_str(&body)
+        .unwrap_or_else(|e| panic!("{} is not JSON ({}): {}", status, e, body));
+    (status, value)
+}
+
+pub fn get_json(url: &str) -> (u16, Value) {
+    json_response(ureq::get(url).call())
+}
+
+pub fn post_json(url: &str, body: &Value) -> (u16, Value) {
+    json_response(
+        ureq::post(url)
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string()),
+    )
+}
+
+/// Asserts that a response is an error with `status` and a JSON error body.
+pub fn assert_error((status, body): (u16, Value), expected: u16) {
+    assert_eq!(status, expected, "unexpected response {}", body);
+    assert!(body["error"].is_string(), "bad error body {}", body);
+}
@@ -0,0 +1,86 @@
+//! Sends bad requests to a server process, which should answer each with an
+//! error status and a JSON error body.
+
+mod common;
+
+use std::fs;
+
+use base64::{engine::general_purpose, Engine};
+use serde_json::json;
+use spiral_rs::util;
+use spiral_rs::wire::WIRE_CONTENT_TYPE;
+use uuid::Uuid;
+
+use common::{assert_error, get_json, json_response, post_json, start_server, temp_dir};
+
+const PARAMS_JSON: &str = r#"{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 22,
+    "t_gsw": 7, "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5, "instances": 1,
+    "db_item_size": 4096}"#;
+
+fn post_raw(url: &str, content_type: &str, body: &[u8]) -> (u16, serde_json::Value) {
+    json_response(
+        ureq::post(url)
+            .set("Content-Type", content_type)
+            .send_bytes(body),
+    )
+}
+
+/// Bytes that bzip2 can't compress much.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn bad_requests_get_json_errors() {
+    let params = util::params_from_json(PARAMS_JSON);
+    let dir = temp_dir();
+    let params_path = dir.join("params.json");
+    fs::write(&params_path, PARAMS_JSON).unwrap();
+    let server = start_server(&params_path, &[("SPIRAL_MAX_PAYLOAD_MB", "1")]);
+    let url = &server.url;
+    let setup_url = format!("{}/setup", url);
+    let read_url = format!("{}/private-read", url);
+    let write_url = format!("{}/write", url);
+
+    // malformed input
+    assert_error(post_raw(&setup_url, "application/json", b"not json"), 400);
+    assert_error(post_json(&setup_url, &json!("not base64!")), 400);
+    let short = general_purpose::STANDARD.encode([0u8; 10]);
+    assert_error(post_json(&setup_url, &json!(short)), 400);
+    assert_error(post_raw(&setup_url, WIRE_CONTENT_TYPE, b"not a frame"), 400);
+    assert_error(post_json(&read_url, &json!(["not base64!"])), 400);
+    assert_error(post_json(&read_url, &json!([short])), 400);
+    assert_error(post_raw(&write_url, "application/json", b"not json"), 400);
+    assert_error(post_json(&write_url, &json!({ "key": "not base64!" })), 400);
+    assert_error(
+        post_json(&format!("{}/create", url), &json!({ "name": "" })),
+        400,
+    );
+
+    // unknown sessions and buckets
+    let mut query = Uuid::new_v4().to_string().into_bytes();
+    query.extend(vec![0; params.query_bytes()]);
+    let query = general_purpose::STANDARD.encode(query);
+    assert_error(post_json(&read_url, &json!([query])), 404);
+    assert_error(get_json(&format!("{}/check/{}", url, Uuid::new_v4())), 404);
+    assert_error(get_json(&format!("{}/missing/meta", url)), 404);
+
+    // oversized values and payloads
+    let value = general_purpose::STANDARD.encode(noise(2 * params.db_item_size));
+    assert_error(post_json(&write_url, &json!({ "key": value })), 413);
+    let (_, meta) = get_json(&format!("{}/meta", url));
+    assert_eq!(meta["global_version"], 0);
+    let huge = vec![b' '; 2 << 20];
+    assert_error(post_raw(&write_url, "application/json", &huge), 413);
+
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,107 @@
+//! Runs a bucket sharded across worker processes, behind a coordinator
+//! process, all on this machine.
+
+mod common;
+
+use std::fs;
+
+use spiral_rs::arith::log2_ceil;
+use spiral_rs::client::Client;
+use spiral_rs::util;
+use spiral_rs::wire::{decode_frame, FrameKind};
+use spiral_server::db::loading::generate_random_db_and_get_item;
+use uuid::Uuid;
+
+use common::{post_wire, start_server, temp_dir, Server};
+
+const PARAMS_JSON: &str = r#"{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 22,
+    "t_gsw": 7, "t_conv": 3, "t_exp_left": 5, "t_exp_right": 5, "instances": 3,
+    "db_item_size": 12288}"#;
+
+#[test]
+fn sharded_bucket_is_correct() {
+    let params = util::params_from_json(PARAMS_JSON);
+    let dir = temp_dir();
+    let params_path = dir.join("params.json");
+    fs::write(&params_path, PARAMS_JSON).unwrap();
+    let db_path = dir.join("db.preprocessed");
+    let target_idx = 77;
+    let (corr_item, db) = generate_random_db_and_get_item(&params, target_idx);
+    let db_bytes: Vec<u8> = db.as_slice().iter().flat_map(|x| x.to_ne_bytes()).collect();
+    fs::write(&db_path, &db_bytes).unwrap();
+    drop(db_bytes);
+
+    let db_path = db_path.to_str().unwrap();
+    let workers: Vec<Server> = ["0..1", "1..3"]
+        .iter()
+        .map(|instances| {
+            start_server(
+                &params_path,
+                &[
+                    ("SPIRAL_STATIC_DB", db_path),
+                    ("SPIRAL_STATIC_DB_MMAP", "true"),
+                    ("SPIRAL_SHARD_INSTANCES", instances),
+                ],
+            )
+        })
+        .collect();
+    let worker_urls: Vec<String> = workers
+        .iter()
+        .rev()
+        .map(|worker| format!("{}/default", worker.url))
+        .collect();
+    let coordinator = start_server(
+        &params_path,
+        &[("SPIRAL_SHARD_WORKERS", &worker_urls.join(","))],
+    );
+
+    let mut client = Client::init(&params);
+    let setup_body = post_wire(
+        &format!("{}/setup", coordinator.url),
+        FrameKind::Setup,
+        &[client.generate_keys().serialize()],
+    )
+    .unwrap();
+    let uuid = serde_json::from_slice::<serde_json::Value>(&setup_body).unwrap()["uuid"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let mut query = uuid.clone().into_bytes();
+    query.extend(client.generate_query(target_idx).serialize());
+
+    let body = post_wire(
+        &format!("{}/private-read", coordinator.url),
+        FrameKind::Queries,
+        &[query.clone(), query],
+    )
+    .unwrap();
+    let responses = decode_frame(&body, FrameKind::Responses).unwrap();
+    let p_bits = log2_ceil(params.pt_modulus) as usize;
+    let corr_result = corr_item.to_vec(p_bits, params.modp_words_per_chunk());
+    assert_eq!(responses.len(), 2);
+    for response in responses {
+        assert_eq!(response.len(), params.response_bytes());
+        assert_eq!(client.decode_response(response), corr_result);
+    }
+
+    let check = ureq::get(&format!("{}/check/{}", coordinator.url, uuid)).call();
+    assert!(check.is_ok());
+    let mut bad_query = Uuid::new_v4().to_string().into_bytes();
+    bad_query.extend(client.generate_query(target_idx).serialize());
+    assert!(matches!(
+        post_wire(
+            &format!("{}/private-read", coordinator.url),
+            FrameKind::Queries,
+            &[bad_query],
+        ),
+        Err(404)
+    ));
+    assert!(matches!(
+        ureq::post(&format!("{}/write", coordinator.url)).send_string("{}"),
+        Err(ureq::Error::Status(403, _))
+    ));
+
+    drop(coordinator);
+    drop(workers);
+    fs::remove_dir_all(&dir).unwrap();
+}
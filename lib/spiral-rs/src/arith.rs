@@ -18,6 +18,11 @@ pub fn log2_ceil_usize(a: usize) -> usize {
     f64::ceil(f64::log2(a as f64)) as usize
 }
 
+/// `a / b`, rounded up. Stands in for `usize::div_ceil`, which needs Rust 1.73.
+pub fn div_ceil_usize(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
 pub fn multiply_modular(params: &Params, a: u64, b: u64, c: usize) -> u64 {
     barrett_coeff_u64(params, a * b, c)
 }
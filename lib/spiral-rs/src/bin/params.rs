@@ -0,0 +1,50 @@
+use std::env;
+use std::process::exit;
+
+use serde_json::{json, Value};
+use spiral_rs::param_gen::generate_params;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        // [num_items] [item_size_bytes] [max_log2_err_prob]
+        eprintln!(
+            "Usage: {} <num_items> <item_size_bytes> <max_log2_err_prob>",
+            args[0]
+        );
+        eprintln!("  e.g. {} 16384 32768 -40", args[0]);
+        exit(2);
+    }
+    let parse_err = |name: &str| -> ! {
+        eprintln!("Invalid {}", name);
+        exit(2)
+    };
+    let num_items: usize = args[1].parse().unwrap_or_else(|_| parse_err("num_items"));
+    let item_size: usize = args[2]
+        .parse()
+        .unwrap_or_else(|_| parse_err("item_size_bytes"));
+    let max_log2_err: f64 = args[3]
+        .parse()
+        .unwrap_or_else(|_| parse_err("max_log2_err_prob"));
+
+    let front = generate_params(num_items, item_size, max_log2_err);
+    if front.is_empty() {
+        eprintln!("No parameters meet the target.");
+        exit(1);
+    }
+    eprintln!("{} Pareto-optimal parameter sets:", front.len());
+    let out: Vec<Value> = front
+        .iter()
+        .map(|c| {
+            json!({
+                "params": c.to_json(),
+                "setup_bytes": c.setup_bytes,
+                "query_bytes": c.query_bytes,
+                "response_bytes": c.response_bytes,
+                "server_work": c.server_work,
+                "log2_err_prob": c.log2_err_prob,
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&out).unwrap());
+}
@@ -1,12 +1,17 @@
 use crate::{
-    arith::*, discrete_gaussian::*, gadget::*, number_theory::*, params::*, poly::*, util::*,
+    arith::*, client_state, discrete_gaussian::*, error::Error, gadget::*, number_theory::*,
+    params::*, poly::*, util::*,
 };
 use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use std::{iter::once, mem::size_of};
+use std::{borrow::Cow, iter::once, mem::size_of, sync::Arc};
 use subtle::ConditionallySelectable;
 use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 pub type Seed = <ChaCha20Rng as SeedableRng>::Seed;
 pub const SEED_LENGTH: usize = 32;
@@ -65,7 +70,16 @@ fn serialize_vec_polymatrix_for_rng(vec: &mut Vec<u8>, a: &Vec<PolyMatrixRaw>) {
     }
 }
 
-fn deserialize_polymatrix_rng(a: &mut PolyMatrixRaw, data: &[u8], rng: &mut ChaCha20Rng) -> usize {
+fn deserialize_polymatrix_rng(
+    a: &mut PolyMatrixRaw,
+    data: &[u8],
+    rng: &mut ChaCha20Rng,
+) -> Result<usize, Error> {
+    let sz = mat_sz_bytes_excl_first_row(a);
+    if data.len() < sz {
+        return Err(Error::Truncated);
+    }
+    let modulus = a.params.modulus;
     let (first_row, rest) = a
         .data
         .as_mut_slice()
@@ -73,23 +87,41 @@ fn deserialize_polymatrix_rng(a: &mut PolyMatrixRaw, data: &[u8], rng: &mut ChaC
     for i in 0..first_row.len() {
         first_row[i] = get_inv_from_rng(a.params, rng);
     }
-    for (i, chunk) in data.chunks(size_of::<u64>()).enumerate() {
-        rest[i] = u64::from_ne_bytes(chunk.try_into().unwrap());
+    for (i, chunk) in data[..sz].chunks(size_of::<u64>()).enumerate() {
+        let val = u64::from_ne_bytes(chunk.try_into().unwrap());
+        if val >= modulus {
+            return Err(Error::OutOfRange(val, modulus));
+        }
+        rest[i] = val;
     }
-    mat_sz_bytes_excl_first_row(a)
+    Ok(sz)
 }
 
 fn deserialize_vec_polymatrix_rng(
     a: &mut Vec<PolyMatrixRaw>,
     data: &[u8],
     rng: &mut ChaCha20Rng,
-) -> usize {
-    let mut chunks = data.chunks(mat_sz_bytes_excl_first_row(&a[0]));
+) -> Result<usize, Error> {
     let mut bytes_read = 0;
     for i in 0..a.len() {
-        bytes_read += deserialize_polymatrix_rng(&mut a[i], chunks.next().unwrap(), rng);
+        bytes_read += deserialize_polymatrix_rng(&mut a[i], &data[bytes_read..], rng)?;
     }
-    bytes_read
+    Ok(bytes_read)
+}
+
+/// Checks that both CRT halves of each packed `v_buf` word are reduced.
+fn check_v_buf_range(params: &Params, v_buf: &[u64]) -> Result<(), Error> {
+    for &x in v_buf {
+        let lo = x & 0xffffffff;
+        let hi = x >> 32;
+        if lo >= params.moduli[0] {
+            return Err(Error::OutOfRange(lo, params.moduli[0]));
+        }
+        if hi >= params.moduli[1] {
+            return Err(Error::OutOfRange(hi, params.moduli[1]));
+        }
+    }
+    Ok(())
 }
 
 fn extract_excl_rng_data(v_buf: &[u64]) -> Vec<u64> {
@@ -209,8 +241,15 @@ impl<'a> PublicParameters<'a> {
         data
     }
 
+    /// Panics if `data` is malformed; see `try_deserialize`.
     pub fn deserialize(params: &'a Params, data: &[u8]) -> Self {
-        assert_eq!(params.setup_bytes(), data.len());
+        Self::try_deserialize(params, data).unwrap()
+    }
+
+    pub fn try_deserialize(params: &'a Params, data: &[u8]) -> Result<Self, Error> {
+        if data.len() != params.setup_bytes() {
+            return Err(Error::InvalidLength(data.len(), params.setup_bytes()));
+        }
 
         let mut idx = 0;
 
@@ -219,11 +258,11 @@ impl<'a> PublicParameters<'a> {
         idx += SEED_LENGTH;
 
         let mut v_packing = new_vec_raw(params, params.n, params.n + 1, params.t_conv);
-        idx += deserialize_vec_polymatrix_rng(&mut v_packing, &data[idx..], &mut rng);
+        idx += deserialize_vec_polymatrix_rng(&mut v_packing, &data[idx..], &mut rng)?;
 
         if params.expand_queries {
             let mut v_expansion_left = new_vec_raw(params, params.g(), 2, params.t_exp_left);
-            idx += deserialize_vec_polymatrix_rng(&mut v_expansion_left, &data[idx..], &mut rng);
+            idx += deserialize_vec_polymatrix_rng(&mut v_expansion_left, &data[idx..], &mut rng)?;
 
             let mut v_expansion_right = v_expansion_left.clone();
             if params.version == 0 || params.t_exp_right != params.t_exp_left {
@@ -233,28 +272,28 @@ impl<'a> PublicParameters<'a> {
                     &mut v_expansion_right_tmp,
                     &data[idx..],
                     &mut rng,
-                );
+                )?;
                 v_expansion_right = v_expansion_right_tmp;
             }
 
             let mut v_conversion = new_vec_raw(params, 1, 2, 2 * params.t_conv);
-            _ = deserialize_vec_polymatrix_rng(&mut v_conversion, &data[idx..], &mut rng);
+            _ = deserialize_vec_polymatrix_rng(&mut v_conversion, &data[idx..], &mut rng)?;
 
-            Self {
+            Ok(Self {
                 v_packing: Self::to_ntt_alloc_vec(&v_packing).unwrap(),
                 v_expansion_left: Self::to_ntt_alloc_vec(&v_expansion_left),
                 v_expansion_right: Self::to_ntt_alloc_vec(&v_expansion_right),
                 v_conversion: Self::to_ntt_alloc_vec(&v_conversion),
                 seed: Some(seed),
-            }
+            })
         } else {
-            Self {
+            Ok(Self {
                 v_packing: Self::to_ntt_alloc_vec(&v_packing).unwrap(),
                 v_expansion_left: None,
                 v_expansion_right: None,
                 v_conversion: None,
                 seed: Some(seed),
-            }
+            })
         }
     }
 }
@@ -300,8 +339,15 @@ impl<'a> Query<'a> {
         data
     }
 
-    pub fn deserialize(params: &'a Params, mut data: &[u8]) -> Self {
-        assert_eq!(params.query_bytes(), data.len());
+    /// Panics if `data` is malformed; see `try_deserialize`.
+    pub fn deserialize(params: &'a Params, data: &[u8]) -> Self {
+        Self::try_deserialize(params, data).unwrap()
+    }
+
+    pub fn try_deserialize(params: &'a Params, mut data: &[u8]) -> Result<Self, Error> {
+        if data.len() != params.query_bytes() {
+            return Err(Error::InvalidLength(data.len(), params.query_bytes()));
+        }
 
         let mut out = Query::empty();
         let seed = data[0..SEED_LENGTH].try_into().unwrap();
@@ -310,7 +356,7 @@ impl<'a> Query<'a> {
         data = &data[SEED_LENGTH..];
         if params.expand_queries {
             let mut ct = PolyMatrixRaw::zero(params, 2, 1);
-            deserialize_polymatrix_rng(&mut ct, data, &mut rng);
+            deserialize_polymatrix_rng(&mut ct, data, &mut rng)?;
             out.ct = Some(ct);
         } else {
             let v_buf_bytes = params.query_v_buf_bytes();
@@ -318,17 +364,149 @@ impl<'a> Query<'a> {
                 .chunks(size_of::<u64>())
                 .map(|x| u64::from_ne_bytes(x.try_into().unwrap()))
                 .collect();
+            check_v_buf_range(params, &v_buf)?;
             let v_buf_interleaved = interleave_rng_data(params, &v_buf, &mut rng);
             out.v_buf = Some(v_buf_interleaved);
 
             let mut v_ct = new_vec_raw(params, params.db_dim_2, 2, 2 * params.t_gsw);
-            deserialize_vec_polymatrix_rng(&mut v_ct, &data[v_buf_bytes..], &mut rng);
+            deserialize_vec_polymatrix_rng(&mut v_ct, &data[v_buf_bytes..], &mut rng)?;
             out.v_ct = Some(v_ct);
         }
-        out
+        Ok(out)
     }
 }
 
+/// Encryptions of zero for every ciphertext in one query, from
+/// `Client::precompute_query`. A template must be used for only one query,
+/// since two queries built from it would reveal the difference between their
+/// indices; `Client::generate_query_from` takes it by value for that reason.
+pub struct QueryTemplate<'a> {
+    seed: Seed,
+    ct: Option<PolyMatrixRaw<'a>>,
+    reg_cts: Option<Vec<PolyMatrixNTT<'a>>>,
+    gsw_cts: Option<Vec<PolyMatrixRaw<'a>>>,
+}
+
+/// Adds `b` into `a` coefficient-wise, mod the ciphertext modulus.
+fn add_raw_into(params: &Params, a: &mut [u64], b: &[u64]) {
+    for (x, &y) in a.iter_mut().zip(b) {
+        *x = barrett_u64(params, *x + y);
+    }
+}
+
+/// Adds the selection vector for `idx_target` into `template`; see
+/// `Client::generate_query_from`. `sk_reg` is the client's raw regev key.
+fn query_from_template<'a>(
+    params: &'a Params,
+    sk_reg: &[u64],
+    template: QueryTemplate<'a>,
+    idx_target: usize,
+) -> Query<'a> {
+    let further_dims = params.db_dim_2;
+    let idx_dim0 = idx_target / (1 << further_dims);
+    let idx_further = idx_target % (1 << further_dims);
+    let scale_k = params.modulus / params.pt_modulus;
+    let bits_per = get_bits_per(params, params.t_gsw);
+
+    let mut query = Query::empty();
+    query.seed = Some(template.seed);
+    if params.expand_queries {
+        // pack query into single ciphertext
+        let mut sigma = PolyMatrixRaw::zero(params, 1, 1);
+        let inv_2_g_first = invert_uint_mod(1 << params.g(), params.modulus).unwrap();
+        let inv_2_g_rest = invert_uint_mod(1 << (params.stop_round() + 1), params.modulus).unwrap();
+
+        if params.db_dim_2 == 0 {
+            for i in 0..(1 << params.db_dim_1) {
+                sigma.data[i].conditional_assign(&scale_k, (i as u64).ct_eq(&(idx_dim0 as u64)))
+            }
+
+            for i in 0..params.poly_len {
+                sigma.data[i] = multiply_uint_mod(sigma.data[i], inv_2_g_first, params.modulus);
+            }
+        } else {
+            for i in 0..(1 << params.db_dim_1) {
+                sigma.data[2 * i].conditional_assign(&scale_k, (i as u64).ct_eq(&(idx_dim0 as u64)))
+            }
+
+            for i in 0..further_dims as u64 {
+                let mask = 1 << i;
+                let bit = ((idx_further as u64) & mask).ct_eq(&mask);
+                for j in 0..params.t_gsw {
+                    let val = u64::conditional_select(&0, &(1u64 << (bits_per * j)), bit);
+                    let idx = (i as usize) * params.t_gsw + (j as usize);
+                    sigma.data[2 * idx + 1] = val;
+                }
+            }
+
+            for i in 0..params.poly_len / 2 {
+                sigma.data[2 * i] =
+                    multiply_uint_mod(sigma.data[2 * i], inv_2_g_first, params.modulus);
+                sigma.data[2 * i + 1] =
+                    multiply_uint_mod(sigma.data[2 * i + 1], inv_2_g_rest, params.modulus);
+            }
+        }
+
+        let mut ct = template.ct.expect("template is for expanded queries");
+        add_raw_into(params, ct.get_poly_mut(1, 0), sigma.get_poly(0, 0));
+        query.ct = Some(ct);
+    } else {
+        let mut reg_cts = template
+            .reg_cts
+            .expect("template is for unexpanded queries");
+        let mut v_ct = template.gsw_cts.unwrap();
+
+        // add the selection bit to every regev ciphertext
+        let scale_ntt = to_ntt_alloc(&PolyMatrixRaw::single_value(params, scale_k));
+        for (i, ct) in reg_cts.iter_mut().enumerate() {
+            let mask = u64::conditional_select(&0, &u64::MAX, (i as u64).ct_eq(&(idx_dim0 as u64)));
+            let pol = ct.get_poly_mut(1, 0);
+            for c in 0..params.crt_count {
+                for k in 0..params.poly_len {
+                    let idx = c * params.poly_len + k;
+                    pol[idx] = add_modular(params, pol[idx], scale_ntt.data[idx] & mask, c);
+                }
+            }
+        }
+        // reorient into server's preferred indexing
+        let mut reg_cts_buf = vec![0u64; params.num_expanded() * 2 * params.poly_len];
+        reorient_reg_ciphertexts(params, reg_cts_buf.as_mut_slice(), &reg_cts);
+
+        // make the GSW ciphertexts encrypt the bits of idx_further; the
+        // even columns encrypt sk_reg times the gadget, the odd ones the
+        // gadget alone
+        for (i, ct_gsw) in v_ct.iter_mut().enumerate() {
+            let mask = 1 << i;
+            let bit = ((idx_further as u64) & mask).ct_eq(&mask);
+            for j in 0..params.t_gsw {
+                let value = u64::conditional_select(&0, &(1u64 << (bits_per * j)), bit);
+                let prod = Zeroizing::new(
+                    sk_reg
+                        .iter()
+                        .map(|&x| multiply_uint_mod(x, value, params.modulus))
+                        .collect::<Vec<_>>(),
+                );
+                add_raw_into(params, ct_gsw.get_poly_mut(1, 2 * j), &prod);
+                let pol = ct_gsw.get_poly_mut(1, 2 * j + 1);
+                pol[0] = barrett_u64(params, pol[0] + value);
+            }
+        }
+
+        query.v_buf = Some(reg_cts_buf);
+        query.v_ct = Some(v_ct);
+    }
+    query
+}
+
+/// A serialized query, prefixed with the session's UUID.
+fn full_query(id: &str, query: &Query) -> Vec<u8> {
+    assert_eq!(id.len(), UUID_V4_LEN);
+    let mut query_buf = query.serialize();
+    let mut full_query_buf = id.as_bytes().to_vec();
+    full_query_buf.append(&mut query_buf);
+    full_query_buf
+}
+
 pub fn matrix_with_identity<'a>(p: &PolyMatrixRaw<'a>) -> PolyMatrixRaw<'a> {
     assert_eq!(p.cols, 1);
     let mut r = PolyMatrixRaw::zero(p.params, p.rows, p.rows + 1);
@@ -338,7 +516,7 @@ pub fn matrix_with_identity<'a>(p: &PolyMatrixRaw<'a>) -> PolyMatrixRaw<'a> {
 }
 
 fn params_with_moduli(params: &Params, moduli: &Vec<u64>) -> Params {
-    Params::init(
+    let mut out = Params::init(
         params.poly_len,
         moduli,
         params.noise_width,
@@ -355,20 +533,54 @@ fn params_with_moduli(params: &Params, moduli: &Vec<u64>) -> Params {
         params.instances,
         params.db_item_size,
         params.version,
-    )
+    );
+    out.q1 = params.q1;
+    out
 }
 
+/// A client's secret keys, and what it needs to use them. The keys are wiped
+/// when the client is dropped, and `Debug` does not print them.
 pub struct Client<'a> {
     params: &'a Params,
     sk_gsw: PolyMatrixRaw<'a>,
     sk_reg: PolyMatrixRaw<'a>,
     sk_gsw_full: PolyMatrixRaw<'a>,
     sk_reg_full: PolyMatrixRaw<'a>,
-    dg: DiscreteGaussian,
+    dg: Cow<'a, DiscreteGaussian>,
+}
+
+impl Zeroize for Client<'_> {
+    fn zeroize(&mut self) {
+        self.sk_gsw.zeroize();
+        self.sk_reg.zeroize();
+        self.sk_gsw_full.zeroize();
+        self.sk_reg_full.zeroize();
+    }
+}
+
+impl Drop for Client<'_> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl std::fmt::Debug for Client<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("params", &self.params.fingerprint())
+            .finish_non_exhaustive()
+    }
 }
 
 impl<'a> Client<'a> {
     pub fn init(params: &'a Params) -> Self {
+        let dg = DiscreteGaussian::init(params.noise_width);
+        Self::with_gaussian(params, Cow::Owned(dg))
+    }
+
+    /// A client sampling noise from `dg`, which must be for
+    /// `params.noise_width`.
+    fn with_gaussian(params: &'a Params, dg: Cow<'a, DiscreteGaussian>) -> Self {
         let sk_gsw_dims = params.get_sk_gsw();
         let sk_reg_dims = params.get_sk_reg();
         let sk_gsw = PolyMatrixRaw::zero(params, sk_gsw_dims.0, sk_gsw_dims.1);
@@ -376,8 +588,6 @@ impl<'a> Client<'a> {
         let sk_gsw_full = matrix_with_identity(&sk_gsw);
         let sk_reg_full = matrix_with_identity(&sk_reg);
 
-        let dg = DiscreteGaussian::init(params.noise_width);
-
         Self {
             params,
             sk_gsw,
@@ -501,16 +711,22 @@ impl<'a> Client<'a> {
         res
     }
 
-    pub fn generate_keys_from_seed(&mut self, seed: Seed) -> PublicParameters<'a> {
-        self.generate_keys_impl(&mut ChaCha20Rng::from_seed(seed))
+    /// The copy of `seed` passed in is wiped once the generator is seeded.
+    pub fn generate_keys_from_seed(&mut self, mut seed: Seed) -> PublicParameters<'a> {
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        seed.zeroize();
+        self.generate_keys_impl(&mut rng)
     }
 
     pub fn generate_keys(&mut self) -> PublicParameters<'a> {
         self.generate_keys_impl(&mut ChaCha20Rng::from_entropy())
     }
 
-    pub fn generate_secret_keys_from_seed(&mut self, seed: Seed) {
-        self.generate_secret_keys_impl(&mut ChaCha20Rng::from_seed(seed))
+    /// The copy of `seed` passed in is wiped once the generator is seeded.
+    pub fn generate_secret_keys_from_seed(&mut self, mut seed: Seed) {
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        seed.zeroize();
+        self.generate_secret_keys_impl(&mut rng)
     }
 
     pub fn generate_secret_keys(&mut self) {
@@ -519,15 +735,51 @@ impl<'a> Client<'a> {
 
     pub fn generate_keys_optional(
         &mut self,
-        seed: Seed,
+        mut seed: Seed,
         generate_pub_params: bool,
     ) -> Option<Vec<u8>> {
-        if generate_pub_params {
+        let pub_params = if generate_pub_params {
             Some(self.generate_keys_from_seed(seed).serialize())
         } else {
             self.generate_secret_keys_from_seed(seed);
             None
-        }
+        };
+        seed.zeroize();
+        pub_params
+    }
+
+    /// Saves the secret keys, and optionally the server's UUID for the
+    /// session they were set up in, as a blob `import_state` reads back; see
+    /// `client_state` for the format. With a passphrase, the blob is
+    /// encrypted under a key derived from it.
+    pub fn export_state(&self, uuid: Option<&str>, passphrase: Option<&str>) -> Vec<u8> {
+        client_state::export_state(
+            self.params,
+            self.sk_gsw.as_slice(),
+            self.sk_reg.as_slice(),
+            uuid,
+            passphrase,
+        )
+    }
+
+    /// Restores a client saved by `export_state`, along with the UUID saved
+    /// with it. `params` must be the parameters it was saved under.
+    pub fn import_state(
+        params: &'a Params,
+        data: &[u8],
+        passphrase: Option<&str>,
+    ) -> Result<(Self, Option<String>), Error> {
+        let state = client_state::import_state(params, data, passphrase)?;
+        let mut client = Self::init(params);
+        client.set_secret_keys(&state.sk_gsw, &state.sk_reg);
+        Ok((client, state.uuid))
+    }
+
+    fn set_secret_keys(&mut self, sk_gsw: &[u64], sk_reg: &[u64]) {
+        self.sk_gsw.as_mut_slice().copy_from_slice(sk_gsw);
+        self.sk_reg.as_mut_slice().copy_from_slice(sk_reg);
+        self.sk_gsw_full = matrix_with_identity(&self.sk_gsw);
+        self.sk_reg_full = matrix_with_identity(&self.sk_reg);
     }
 
     fn generate_secret_keys_impl(&mut self, rng: &mut ChaCha20Rng) {
@@ -541,8 +793,8 @@ impl<'a> Client<'a> {
         let params = self.params;
 
         self.generate_secret_keys_impl(rng);
-        let sk_reg_ntt = to_ntt_alloc(&self.sk_reg);
-        let sk_gsw_ntt = to_ntt_alloc(&self.sk_gsw);
+        let sk_reg_ntt = Zeroizing::new(to_ntt_alloc(&self.sk_reg));
+        let sk_gsw_ntt = Zeroizing::new(to_ntt_alloc(&self.sk_gsw));
 
         let mut rng = ChaCha20Rng::from_entropy();
         let mut pp = PublicParameters::init(params);
@@ -563,7 +815,7 @@ impl<'a> Client<'a> {
         }
 
         if params.version > 0 {
-            let scaled = &sk_gsw_ntt * &gadget_conv_ntt;
+            let scaled = &*sk_gsw_ntt * &gadget_conv_ntt;
             let scaled_rotated = shift_rows_by_one(&scaled);
             let w = self.encrypt_matrix_gsw(&scaled_rotated, &mut rng, &mut rng_pub);
             pp.v_packing.push(w);
@@ -591,8 +843,8 @@ impl<'a> Client<'a> {
 
             // Params for converison
             let g_conv = build_gadget(params, 2, 2 * params.t_conv);
-            let sk_reg_ntt = self.sk_reg.ntt();
-            let sk_reg_squared_ntt = &sk_reg_ntt * &sk_reg_ntt;
+            let sk_reg_ntt = Zeroizing::new(self.sk_reg.ntt());
+            let sk_reg_squared_ntt = Zeroizing::new(&*sk_reg_ntt * &*sk_reg_ntt);
             pp.v_conversion = Some(Vec::from_iter(once(PolyMatrixNTT::zero(
                 params,
                 2,
@@ -602,10 +854,10 @@ impl<'a> Client<'a> {
                 let sigma;
                 if i % 2 == 0 {
                     let val = g_conv.get_poly(0, i)[0];
-                    sigma = &sk_reg_squared_ntt * &single_poly(params, val).ntt();
+                    sigma = &*sk_reg_squared_ntt * &single_poly(params, val).ntt();
                 } else {
                     let val = g_conv.get_poly(1, i)[0];
-                    sigma = &sk_reg_ntt * &single_poly(params, val).ntt();
+                    sigma = &*sk_reg_ntt * &single_poly(params, val).ntt();
                 }
                 let ct = self.encrypt_matrix_reg(&sigma, &mut rng, &mut rng_pub);
                 pp.v_conversion.as_mut().unwrap()[0].copy_into(&ct, 0, i);
@@ -616,122 +868,142 @@ impl<'a> Client<'a> {
     }
 
     pub fn generate_query(&self, idx_target: usize) -> Query<'a> {
-        let params = self.params;
-        let further_dims = params.db_dim_2;
-        let idx_dim0 = idx_target / (1 << further_dims);
-        let idx_further = idx_target % (1 << further_dims);
-        let scale_k = params.modulus / params.pt_modulus;
-        let bits_per = get_bits_per(params, params.t_gsw);
+        self.generate_query_from(self.precompute_query(), idx_target)
+    }
 
+    /// Does the expensive part of `generate_query` ahead of time: it samples
+    /// an encryption of zero for every ciphertext in a query.
+    /// `generate_query_from` then only has to add in the index.
+    pub fn precompute_query(&self) -> QueryTemplate<'a> {
+        let params = self.params;
         let mut rng = ChaCha20Rng::from_entropy();
+        let seed = ChaCha20Rng::from_entropy().gen();
+        let mut rng_pub = ChaCha20Rng::from_seed(seed);
+        let mut zero = || self.get_fresh_reg_public_key(1, &mut rng, &mut rng_pub);
 
-        let mut query = Query::empty();
-        let query_seed = ChaCha20Rng::from_entropy().gen();
-        query.seed = Some(query_seed);
-        let mut rng_pub = ChaCha20Rng::from_seed(query_seed);
         if params.expand_queries {
-            // pack query into single ciphertext
-            let mut sigma = PolyMatrixRaw::zero(params, 1, 1);
-            let inv_2_g_first = invert_uint_mod(1 << params.g(), params.modulus).unwrap();
-            let inv_2_g_rest =
-                invert_uint_mod(1 << (params.stop_round() + 1), params.modulus).unwrap();
-
-            if params.db_dim_2 == 0 {
-                for i in 0..(1 << params.db_dim_1) {
-                    sigma.data[i].conditional_assign(&scale_k, (i as u64).ct_eq(&(idx_dim0 as u64)))
-                }
+            return QueryTemplate {
+                seed,
+                ct: Some(from_ntt_alloc(&zero())),
+                reg_cts: None,
+                gsw_cts: None,
+            };
+        }
 
-                for i in 0..params.poly_len {
-                    sigma.data[i] = multiply_uint_mod(sigma.data[i], inv_2_g_first, params.modulus);
-                }
-            } else {
-                for i in 0..(1 << params.db_dim_1) {
-                    sigma.data[2 * i]
-                        .conditional_assign(&scale_k, (i as u64).ct_eq(&(idx_dim0 as u64)))
-                }
+        let reg_cts = (0..params.num_expanded()).map(|_| zero()).collect();
+        let mut gsw_cts = Vec::with_capacity(params.db_dim_2);
+        for _ in 0..params.db_dim_2 {
+            let mut ct_gsw = PolyMatrixNTT::zero(params, 2, 2 * params.t_gsw);
+            for j in 0..2 * params.t_gsw {
+                ct_gsw.copy_into(&zero(), 0, j);
+            }
+            gsw_cts.push(from_ntt_alloc(&ct_gsw));
+        }
+        QueryTemplate {
+            seed,
+            ct: None,
+            reg_cts: Some(reg_cts),
+            gsw_cts: Some(gsw_cts),
+        }
+    }
 
-                for i in 0..further_dims as u64 {
-                    let mask = 1 << i;
-                    let bit = ((idx_further as u64) & mask).ct_eq(&mask);
-                    for j in 0..params.t_gsw {
-                        let val = u64::conditional_select(&0, &(1u64 << (bits_per * j)), bit);
-                        let idx = (i as usize) * params.t_gsw + (j as usize);
-                        sigma.data[2 * idx + 1] = val;
-                    }
-                }
+    /// Turns a template from `precompute_query` into a query for
+    /// `idx_target`. The result is distributed exactly as one from
+    /// `generate_query`, which is built the same way.
+    pub fn generate_query_from(&self, template: QueryTemplate<'a>, idx_target: usize) -> Query<'a> {
+        query_from_template(self.params, self.sk_reg.as_slice(), template, idx_target)
+    }
 
-                for i in 0..params.poly_len / 2 {
-                    sigma.data[2 * i] =
-                        multiply_uint_mod(sigma.data[2 * i], inv_2_g_first, params.modulus);
-                    sigma.data[2 * i + 1] =
-                        multiply_uint_mod(sigma.data[2 * i + 1], inv_2_g_rest, params.modulus);
-                }
-            }
+    /// `generate_full_query`, from a template made by `precompute_query`.
+    pub fn generate_full_query_from(
+        &self,
+        template: QueryTemplate<'a>,
+        id: &str,
+        idx_target: usize,
+    ) -> Vec<u8> {
+        full_query(id, &self.generate_query_from(template, idx_target))
+    }
 
-            query.ct = Some(from_ntt_alloc(&self.encrypt_matrix_reg(
-                &to_ntt_alloc(&sigma),
-                &mut rng,
-                &mut rng_pub,
-            )));
-        } else {
-            let num_expanded = 1 << params.db_dim_1;
-            let mut sigma_v = Vec::<PolyMatrixNTT>::new();
-
-            // generate regev ciphertexts
-            let reg_cts_buf_words = num_expanded * 2 * params.poly_len;
-            let mut reg_cts_buf = vec![0u64; reg_cts_buf_words];
-            let mut reg_cts = Vec::<PolyMatrixNTT>::new();
-            for i in 0..num_expanded {
-                let value = ((i == idx_dim0) as u64) * scale_k;
-                let sigma = PolyMatrixRaw::single_value(&params, value);
-                reg_cts.push(self.encrypt_matrix_reg(
-                    &to_ntt_alloc(&sigma),
-                    &mut rng,
-                    &mut rng_pub,
-                ));
-            }
-            // reorient into server's preferred indexing
-            reorient_reg_ciphertexts(self.params, reg_cts_buf.as_mut_slice(), &reg_cts);
+    pub fn generate_full_query(&self, id: &str, idx_target: usize) -> Vec<u8> {
+        full_query(id, &self.generate_query(idx_target))
+    }
 
-            // generate GSW ciphertexts
-            for i in 0..further_dims {
-                let bit = ((idx_further as u64) & (1 << (i as u64))) >> (i as u64);
-                let mut ct_gsw = PolyMatrixNTT::zero(&params, 2, 2 * params.t_gsw);
+    /// Panics if `data` is malformed; see `try_decode_response`.
+    pub fn decode_response(&self, data: &[u8]) -> Vec<u8> {
+        self.try_decode_response(data).unwrap()
+    }
 
-                for j in 0..params.t_gsw {
-                    let value = (1u64 << (bits_per * j)) * bit;
-                    let sigma = PolyMatrixRaw::single_value(&params, value);
-                    let sigma_ntt = to_ntt_alloc(&sigma);
+    pub fn try_decode_response(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let q2_params = self.q2_params();
+        let sk_gsw_q2_ntt = self.sk_gsw_q2_ntt(&q2_params);
+        self.decode_response_with(&q2_params, &sk_gsw_q2_ntt, data)
+    }
 
-                    // important to rng in the right order here
-                    let prod = &to_ntt_alloc(&self.sk_reg) * &sigma_ntt;
-                    let ct = &self.encrypt_matrix_reg(&prod, &mut rng, &mut rng_pub);
-                    ct_gsw.copy_into(ct, 0, 2 * j);
+    /// `generate_query` for each index. With the `rayon` feature, the queries
+    /// are built in parallel.
+    pub fn generate_queries(&self, idx_targets: &[usize]) -> Vec<Query<'a>> {
+        #[cfg(feature = "rayon")]
+        let idx_targets = idx_targets.par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let idx_targets = idx_targets.iter();
+        idx_targets.map(|&idx| self.generate_query(idx)).collect()
+    }
 
-                    let ct = &self.encrypt_matrix_reg(&sigma_ntt, &mut rng, &mut rng_pub);
-                    ct_gsw.copy_into(ct, 0, 2 * j + 1);
-                }
-                sigma_v.push(ct_gsw);
-            }
+    /// `generate_full_query` for each index; see `generate_queries`.
+    pub fn generate_full_queries(&self, id: &str, idx_targets: &[usize]) -> Vec<Vec<u8>> {
+        self.generate_queries(idx_targets)
+            .iter()
+            .map(|query| full_query(id, query))
+            .collect()
+    }
 
-            query.v_buf = Some(reg_cts_buf);
-            query.v_ct = Some(sigma_v.iter().map(|x| from_ntt_alloc(x)).collect());
-        }
-        query
+    /// Panics if any response is malformed; see `try_decode_responses`.
+    pub fn decode_responses(&self, responses: &[&[u8]]) -> Vec<Vec<u8>> {
+        self.try_decode_responses(responses).unwrap()
     }
 
-    pub fn generate_full_query(&self, id: &str, idx_target: usize) -> Vec<u8> {
-        assert_eq!(id.len(), UUID_V4_LEN);
-        let query = self.generate_query(idx_target);
-        let mut query_buf = query.serialize();
-        let mut full_query_buf = id.as_bytes().to_vec();
-        full_query_buf.append(&mut query_buf);
-        full_query_buf
+    /// `try_decode_response` for each response. The secret key is moved to
+    /// the q2 domain once for the whole batch rather than once per response,
+    /// and with the `rayon` feature the responses are decoded in parallel.
+    pub fn try_decode_responses(&self, responses: &[&[u8]]) -> Result<Vec<Vec<u8>>, Error> {
+        let q2_params = self.q2_params();
+        let sk_gsw_q2_ntt = self.sk_gsw_q2_ntt(&q2_params);
+        #[cfg(feature = "rayon")]
+        let responses = responses.par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let responses = responses.iter();
+        responses
+            .map(|data| self.decode_response_with(&q2_params, &sk_gsw_q2_ntt, data))
+            .collect()
     }
 
-    pub fn decode_response(&self, data: &[u8]) -> Vec<u8> {
+    /// The parameters over the single modulus q2 that the first row of a
+    /// response is switched to.
+    fn q2_params(&self) -> Params {
+        params_with_moduli(self.params, &vec![Q2_VALUES[self.params.q2_bits as usize]])
+    }
+
+    /// `sk_gsw`, recentered mod q2 and in NTT form; see `q2_params`.
+    fn sk_gsw_q2_ntt<'q>(&self, q2_params: &'q Params) -> Zeroizing<PolyMatrixNTT<'q>> {
+        let params = self.params;
+        let q2 = q2_params.modulus;
+        let mut sk_gsw_q2 = Zeroizing::new(PolyMatrixRaw::zero(q2_params, params.n, 1));
+        for i in 0..params.poly_len * params.n {
+            sk_gsw_q2.data[i] = recenter(self.sk_gsw.data[i], params.modulus, q2);
+        }
+        let mut sk_gsw_q2_ntt = Zeroizing::new(PolyMatrixNTT::zero(q2_params, params.n, 1));
+        to_ntt(&mut sk_gsw_q2_ntt, &sk_gsw_q2);
+        sk_gsw_q2_ntt
+    }
+
+    fn decode_response_with(
+        &self,
+        q2_params: &Params,
+        sk_gsw_q2_ntt: &PolyMatrixNTT,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
         /*
-            0. NTT over q2 the secret key
+            0. NTT over q2 the secret key (see sk_gsw_q2_ntt)
 
             1. read first row in q2_bit chunks
             2. read rest in q1_bit chunks
@@ -742,41 +1014,43 @@ impl<'a> Client<'a> {
         let params = self.params;
         let p = params.pt_modulus;
         let p_bits = log2_ceil(params.pt_modulus);
-        let q1 = 4 * params.pt_modulus;
+        let q1 = params.q1;
         let q1_bits = log2_ceil(q1) as usize;
-        let q2 = Q2_VALUES[params.q2_bits as usize];
+        let q2 = q2_params.modulus;
         let q2_bits = params.q2_bits as usize;
 
-        let q2_params = params_with_moduli(params, &vec![q2]);
-
-        // this only needs to be done during keygen
-        let mut sk_gsw_q2 = PolyMatrixRaw::zero(&q2_params, params.n, 1);
-        for i in 0..params.poly_len * params.n {
-            sk_gsw_q2.data[i] = recenter(self.sk_gsw.data[i], params.modulus, q2);
+        if data.len() != params.response_bytes() {
+            return Err(Error::InvalidLength(data.len(), params.response_bytes()));
         }
-        let mut sk_gsw_q2_ntt = PolyMatrixNTT::zero(&q2_params, params.n, 1);
-        to_ntt(&mut sk_gsw_q2_ntt, &sk_gsw_q2);
 
         let mut result = PolyMatrixRaw::zero(&params, params.instances * params.n, params.n);
 
         let mut bit_offs = 0;
         for instance in 0..params.instances {
             // this must be done during decoding
-            let mut first_row = PolyMatrixRaw::zero(&q2_params, 1, params.n);
+            let mut first_row = PolyMatrixRaw::zero(q2_params, 1, params.n);
             let mut rest_rows = PolyMatrixRaw::zero(&params, params.n, params.n);
             for i in 0..params.n * params.poly_len {
-                first_row.data[i] = read_arbitrary_bits(data, bit_offs, q2_bits);
+                let val = read_arbitrary_bits(data, bit_offs, q2_bits);
+                if val >= q2 {
+                    return Err(Error::OutOfRange(val, q2));
+                }
+                first_row.data[i] = val;
                 bit_offs += q2_bits;
             }
             for i in 0..params.n * params.n * params.poly_len {
-                rest_rows.data[i] = read_arbitrary_bits(data, bit_offs, q1_bits);
+                let val = read_arbitrary_bits(data, bit_offs, q1_bits);
+                if val >= q1 {
+                    return Err(Error::OutOfRange(val, q1));
+                }
+                rest_rows.data[i] = val;
                 bit_offs += q1_bits;
             }
 
-            let mut first_row_q2 = PolyMatrixNTT::zero(&q2_params, 1, params.n);
+            let mut first_row_q2 = PolyMatrixNTT::zero(q2_params, 1, params.n);
             to_ntt(&mut first_row_q2, &first_row);
 
-            let sk_prod = (&sk_gsw_q2_ntt * &first_row_q2).raw();
+            let sk_prod = (sk_gsw_q2_ntt * &first_row_q2).raw();
 
             let q1_i64 = q1 as i64;
             let q2_i64 = q2 as i64;
@@ -806,7 +1080,151 @@ impl<'a> Client<'a> {
         }
 
         // println!("{:?}", result.data.as_slice().to_vec());
-        result.to_vec(p_bits as usize, params.modp_words_per_chunk())
+        Ok(result.to_vec(p_bits as usize, params.modp_words_per_chunk()))
+    }
+}
+
+/// A client that owns its parameters, so that it can be created and dropped
+/// freely, moved between threads, and stored without a borrowed `Params`.
+/// It keeps the secret keys and the noise distribution; `client()` borrows a
+/// `Client` built from them for anything not wrapped here.
+#[derive(Clone)]
+pub struct OwnedClient {
+    params: Arc<Params>,
+    dg: DiscreteGaussian,
+    sk_gsw: Zeroizing<Vec<u64>>,
+    sk_reg: Zeroizing<Vec<u64>>,
+}
+
+impl std::fmt::Debug for OwnedClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedClient")
+            .field("params", &self.params.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+impl OwnedClient {
+    pub fn init(params: Arc<Params>) -> Self {
+        let dg = DiscreteGaussian::init(params.noise_width);
+        let client = Client::with_gaussian(&params, Cow::Borrowed(&dg));
+        let sk_gsw = Zeroizing::new(client.sk_gsw.as_slice().to_vec());
+        let sk_reg = Zeroizing::new(client.sk_reg.as_slice().to_vec());
+        drop(client);
+        Self {
+            params,
+            dg,
+            sk_gsw,
+            sk_reg,
+        }
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn shared_params(&self) -> Arc<Params> {
+        self.params.clone()
+    }
+
+    /// A `Client` with these keys. It borrows the parameters and noise
+    /// distribution, so building one only copies the keys.
+    pub fn client(&self) -> Client<'_> {
+        let mut client = Client::with_gaussian(&self.params, Cow::Borrowed(&self.dg));
+        client.set_secret_keys(&self.sk_gsw, &self.sk_reg);
+        client
+    }
+
+    /// Generates fresh keys, and returns the serialized public parameters.
+    pub fn generate_keys(&mut self) -> Vec<u8> {
+        let mut client = Client::with_gaussian(&self.params, Cow::Borrowed(&self.dg));
+        let pub_params = client.generate_keys().serialize();
+        self.sk_gsw.copy_from_slice(client.sk_gsw.as_slice());
+        self.sk_reg.copy_from_slice(client.sk_reg.as_slice());
+        pub_params
+    }
+
+    pub fn generate_keys_optional(
+        &mut self,
+        mut seed: Seed,
+        generate_pub_params: bool,
+    ) -> Option<Vec<u8>> {
+        let mut client = Client::with_gaussian(&self.params, Cow::Borrowed(&self.dg));
+        let pub_params = client.generate_keys_optional(seed, generate_pub_params);
+        seed.zeroize();
+        self.sk_gsw.copy_from_slice(client.sk_gsw.as_slice());
+        self.sk_reg.copy_from_slice(client.sk_reg.as_slice());
+        pub_params
+    }
+
+    /// See `Client::export_state`.
+    pub fn export_state(&self, uuid: Option<&str>, passphrase: Option<&str>) -> Vec<u8> {
+        client_state::export_state(&self.params, &self.sk_gsw, &self.sk_reg, uuid, passphrase)
+    }
+
+    /// See `Client::import_state`.
+    pub fn import_state(
+        params: Arc<Params>,
+        data: &[u8],
+        passphrase: Option<&str>,
+    ) -> Result<(Self, Option<String>), Error> {
+        let state = client_state::import_state(&params, data, passphrase)?;
+        Ok((
+            Self {
+                dg: DiscreteGaussian::init(params.noise_width),
+                params,
+                sk_gsw: state.sk_gsw,
+                sk_reg: state.sk_reg,
+            },
+            state.uuid,
+        ))
+    }
+
+    pub fn generate_full_query(&self, id: &str, idx_target: usize) -> Vec<u8> {
+        self.client().generate_full_query(id, idx_target)
+    }
+
+    /// See `Client::precompute_query`.
+    pub fn precompute_query(&self) -> QueryTemplate<'_> {
+        self.client().precompute_query()
+    }
+
+    /// See `Client::generate_full_query_from`. Unlike the other methods here,
+    /// this does not build a `Client`, so it doesn't copy the keys.
+    pub fn generate_full_query_from(
+        &self,
+        template: QueryTemplate<'_>,
+        id: &str,
+        idx_target: usize,
+    ) -> Vec<u8> {
+        full_query(
+            id,
+            &query_from_template(&self.params, &self.sk_reg, template, idx_target),
+        )
+    }
+
+    /// Panics if `data` is malformed; see `try_decode_response`.
+    pub fn decode_response(&self, data: &[u8]) -> Vec<u8> {
+        self.client().decode_response(data)
+    }
+
+    pub fn try_decode_response(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.client().try_decode_response(data)
+    }
+
+    /// See `Client::generate_full_queries`.
+    pub fn generate_full_queries(&self, id: &str, idx_targets: &[usize]) -> Vec<Vec<u8>> {
+        self.client().generate_full_queries(id, idx_targets)
+    }
+
+    /// Panics if any response is malformed; see `try_decode_responses`.
+    pub fn decode_responses(&self, responses: &[&[u8]]) -> Vec<Vec<u8>> {
+        self.client().decode_responses(responses)
+    }
+
+    /// See `Client::try_decode_responses`.
+    pub fn try_decode_responses(&self, responses: &[&[u8]]) -> Result<Vec<Vec<u8>>, Error> {
+        self.client().try_decode_responses(responses)
     }
 }
 
@@ -826,6 +1244,57 @@ mod test {
         assert_eq!(*client.params, params);
     }
 
+    #[test]
+    fn secret_keys_are_wiped_and_redacted() {
+        let params = get_params();
+        let mut client = Client::init(&params);
+        client.generate_secret_keys_from_seed(get_chacha_static_seed());
+        let key_bufs = |c: &Client| {
+            [&c.sk_gsw, &c.sk_reg, &c.sk_gsw_full, &c.sk_reg_full]
+                .map(|m| m.as_slice().iter().any(|&x| x != 0))
+        };
+        assert_eq!(key_bufs(&client), [true; 4]);
+
+        let coeff = client.sk_gsw.as_slice()[0];
+        for debug in [
+            format!("{:?}", client),
+            format!("{:?}", OwnedClient::init(Arc::new(get_params()))),
+        ] {
+            assert!(!debug.contains("sk_"), "{}", debug);
+            assert!(!debug.contains(&format!(", {},", coeff)), "{}", debug);
+        }
+
+        // `Drop` does exactly this before the buffers are freed
+        client.zeroize();
+        assert_eq!(key_bufs(&client), [false; 4]);
+    }
+
+    #[test]
+    fn owned_client_keeps_keys() {
+        let params = Arc::new(get_params());
+        let seed = get_chacha_static_seed();
+
+        let mut owned = OwnedClient::init(params.clone());
+        assert!(owned.generate_keys_optional(seed, false).is_none());
+        let plain_params = get_params();
+        let mut client = Client::init(&plain_params);
+        client.generate_secret_keys_from_seed(seed);
+
+        let borrowed = owned.client();
+        assert_eq!(borrowed.sk_gsw.as_slice(), client.sk_gsw.as_slice());
+        assert_eq!(borrowed.sk_reg.as_slice(), client.sk_reg.as_slice());
+        assert_eq!(
+            borrowed.sk_reg_full.as_slice(),
+            client.sk_reg_full.as_slice()
+        );
+        drop(borrowed);
+
+        // the client can outlive every other handle on its parameters
+        drop(params);
+        let moved = std::thread::spawn(move || owned).join().unwrap();
+        assert_eq!(moved.client().sk_gsw.as_slice(), client.sk_gsw.as_slice());
+    }
+
     #[test]
     fn keygen_is_correct() {
         let params = get_params();
@@ -953,4 +1422,98 @@ mod test {
     fn no_expansion_query_serialization_is_correct() {
         query_serialization_is_correct_for_params(get_no_expansion_testing_params())
     }
+
+    #[test]
+    fn precomputed_queries_are_well_formed() {
+        for params in [get_params(), get_no_expansion_testing_params()] {
+            let mut owned = OwnedClient::init(Arc::new(params.clone()));
+            owned.generate_keys();
+            let id = "7b1a5b3c-8f1e-4d2a-9c3b-2e4f6a8b0c1d";
+
+            let pool: Vec<_> = (0..3).map(|_| owned.precompute_query()).collect();
+            let queries: Vec<_> = pool
+                .into_iter()
+                .map(|template| owned.generate_full_query_from(template, id, 1))
+                .collect();
+            for query in &queries {
+                assert_eq!(query.len(), id.len() + params.query_bytes());
+                let parsed = Query::deserialize(&params, &query[id.len()..]);
+                assert_eq!(parsed.serialize(), query[id.len()..]);
+            }
+            // each template holds fresh randomness
+            assert_ne!(queries[0], queries[1]);
+            assert_ne!(queries[1], queries[2]);
+        }
+    }
+
+    fn malformed_inputs_are_rejected_for_params(params: Params) {
+        let mut client = Client::init(&params);
+        let pub_params = client.generate_keys().serialize();
+        let query = client.generate_query(1).serialize();
+
+        let setup_bytes = params.setup_bytes();
+        assert_eq!(
+            PublicParameters::try_deserialize(&params, &pub_params[1..]).err(),
+            Some(Error::InvalidLength(setup_bytes - 1, setup_bytes))
+        );
+        let mut bad = pub_params.clone();
+        bad[SEED_LENGTH..SEED_LENGTH + 8].copy_from_slice(&params.modulus.to_ne_bytes());
+        assert_eq!(
+            PublicParameters::try_deserialize(&params, &bad).err(),
+            Some(Error::OutOfRange(params.modulus, params.modulus))
+        );
+        assert!(PublicParameters::try_deserialize(&params, &pub_params).is_ok());
+
+        let query_bytes = params.query_bytes();
+        let mut long = query.clone();
+        long.push(0);
+        assert_eq!(
+            Query::try_deserialize(&params, &long).err(),
+            Some(Error::InvalidLength(query_bytes + 1, query_bytes))
+        );
+        let mut bad = query.clone();
+        bad[SEED_LENGTH..SEED_LENGTH + 8].copy_from_slice(&u64::MAX.to_ne_bytes());
+        assert!(matches!(
+            Query::try_deserialize(&params, &bad),
+            Err(Error::OutOfRange(..))
+        ));
+        assert!(Query::try_deserialize(&params, &query).is_ok());
+
+        let response_bytes = params.response_bytes();
+        assert_eq!(
+            client.try_decode_response(&[]).err(),
+            Some(Error::InvalidLength(0, response_bytes))
+        );
+        let q2 = Q2_VALUES[params.q2_bits as usize];
+        let mut bad = vec![0u8; response_bytes];
+        write_arbitrary_bits(&mut bad, u64::MAX, 0, params.q2_bits as usize);
+        assert_eq!(
+            client.try_decode_response(&bad).err(),
+            Some(Error::OutOfRange((1 << params.q2_bits) - 1, q2))
+        );
+
+        // the rest rows are mod q1, which needn't be a power of two
+        let mut params_q1 = params.clone();
+        params_q1.q1 = 3 * params.pt_modulus;
+        let mut client_q1 = Client::init(&params_q1);
+        client_q1.generate_keys();
+        let q1_bits = log2_ceil(params_q1.q1) as usize;
+        let mut bad = vec![0u8; params_q1.response_bytes()];
+        let rest_offs = params.n * params.poly_len * params.q2_bits as usize;
+        write_arbitrary_bits(&mut bad, u64::MAX, rest_offs, q1_bits);
+        assert_eq!(
+            client_q1.try_decode_response(&bad).err(),
+            Some(Error::OutOfRange((1 << q1_bits) - 1, params_q1.q1))
+        );
+    }
+
+    #[test]
+    fn malformed_inputs_are_rejected() {
+        malformed_inputs_are_rejected_for_params(get_params())
+    }
+
+    #[test]
+    fn no_expansion_malformed_inputs_are_rejected() {
+        malformed_inputs_are_rejected_for_params(get_no_expansion_testing_params())
+    }
 }
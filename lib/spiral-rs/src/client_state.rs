@@ -0,0 +1,359 @@
+//! Saving a client's secret state, so that a session can be resumed later
+//! without repeating setup.
+//!
+//! A state blob is:
+//! - 4 bytes: `STATE_MAGIC`
+//! - 1 byte: `STATE_VERSION`
+//! - 1 byte: flags; bit 0 is set if the payload is encrypted
+//! - 2 bytes: reserved, zero
+//! - if encrypted:
+//!   - 12 bytes: Argon2id memory cost in KiB, iterations and lanes (u32 LE each)
+//!   - 16 bytes: salt
+//!   - 12 bytes: nonce
+//!   - the payload, sealed with ChaCha20-Poly1305 under the key Argon2id
+//!     derives from the passphrase and salt, with every byte before it as
+//!     associated data
+//! - otherwise, the payload
+//!
+//! The payload is:
+//! - 32 bytes: `Params::fingerprint` of the parameters the keys are for
+//! - 2 bytes: length of the server's UUID for the session (u16 LE), 0 if none
+//! - (UUID)
+//! - the coefficients of `sk_gsw`, then of `sk_reg` (u64 LE each)
+
+use argon2::{Algorithm, Argon2, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{error::Error, params::Params};
+
+pub const STATE_MAGIC: [u8; 4] = *b"SPCS";
+pub const STATE_VERSION: u8 = 1;
+
+const FLAG_ENCRYPTED: u8 = 1;
+const HEADER_BYTES: usize = 8;
+const KDF_PARAMS_BYTES: usize = 12;
+const SALT_BYTES: usize = 16;
+const NONCE_BYTES: usize = 12;
+const FINGERPRINT_BYTES: usize = 32;
+
+/// Blobs asking for more memory than this (1 GiB) to derive their key are
+/// rejected rather than attempted.
+const MAX_KDF_MEMORY_KIB: u32 = 1 << 20;
+/// Likewise for the number of passes, and of lanes, the derivation takes.
+const MAX_KDF_TIME_COST: u32 = 16;
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// What `import_state` recovers from a blob.
+pub(crate) struct SecretState {
+    pub sk_gsw: Zeroizing<Vec<u64>>,
+    pub sk_reg: Zeroizing<Vec<u64>>,
+    pub uuid: Option<String>,
+}
+
+fn sk_lens(params: &Params) -> (usize, usize) {
+    let (gsw_rows, gsw_cols) = params.get_sk_gsw();
+    let (reg_rows, reg_cols) = params.get_sk_reg();
+    (
+        gsw_rows * gsw_cols * params.poly_len,
+        reg_rows * reg_cols * params.poly_len,
+    )
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<Key, Error> {
+    if m_cost > MAX_KDF_MEMORY_KIB {
+        return Err(Error::InvalidState(format!(
+            "key derivation needs {} KiB, more than the {} KiB allowed",
+            m_cost, MAX_KDF_MEMORY_KIB
+        )));
+    }
+    if t_cost > MAX_KDF_TIME_COST || p_cost > MAX_KDF_PARALLELISM {
+        return Err(Error::InvalidState(format!(
+            "key derivation takes {} passes over {} lanes, more than the {} passes or {} lanes allowed",
+            t_cost, p_cost, MAX_KDF_TIME_COST, MAX_KDF_PARALLELISM
+        )));
+    }
+    let kdf_params = argon2::Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| Error::InvalidState(format!("key derivation parameters: {}", e)))?;
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, kdf_params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::InvalidState(format!("key derivation: {}", e)))?;
+    Ok(key)
+}
+
+pub(crate) fn export_state(
+    params: &Params,
+    sk_gsw: &[u64],
+    sk_reg: &[u64],
+    uuid: Option<&str>,
+    passphrase: Option<&str>,
+) -> Vec<u8> {
+    let uuid = uuid.unwrap_or("").as_bytes();
+    assert!(uuid.len() <= u16::MAX as usize, "UUID is too long");
+
+    let mut payload = Zeroizing::new(Vec::with_capacity(
+        FINGERPRINT_BYTES + 2 + uuid.len() + 8 * (sk_gsw.len() + sk_reg.len()),
+    ));
+    payload.extend(params.fingerprint());
+    payload.extend((uuid.len() as u16).to_le_bytes());
+    payload.extend(uuid);
+    for &coeff in sk_gsw.iter().chain(sk_reg) {
+        payload.extend(coeff.to_le_bytes());
+    }
+
+    let mut out = Vec::new();
+    out.extend(STATE_MAGIC);
+    out.push(STATE_VERSION);
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => {
+            out.extend([0u8; 3]);
+            out.extend(payload.iter());
+            return out;
+        }
+    };
+    out.push(FLAG_ENCRYPTED);
+    out.extend([0u8; 2]);
+
+    let (m_cost, t_cost, p_cost) = (
+        argon2::Params::DEFAULT_M_COST,
+        argon2::Params::DEFAULT_T_COST,
+        argon2::Params::DEFAULT_P_COST,
+    );
+    let mut rng = ChaCha20Rng::from_entropy();
+    let salt: [u8; SALT_BYTES] = rng.gen();
+    let nonce: [u8; NONCE_BYTES] = rng.gen();
+    for cost in [m_cost, t_cost, p_cost] {
+        out.extend(cost.to_le_bytes());
+    }
+    out.extend(salt);
+    out.extend(nonce);
+
+    let mut key = derive_key(passphrase, &salt, m_cost, t_cost, p_cost)
+        .expect("default key derivation parameters are valid");
+    let cipher = ChaCha20Poly1305::new(&key);
+    key.as_mut_slice().zeroize();
+    let sealed = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &payload,
+                aad: &out,
+            },
+        )
+        .expect("payload is within the cipher's limits");
+    out.extend(sealed);
+    out
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn import_state(
+    params: &Params,
+    data: &[u8],
+    passphrase: Option<&str>,
+) -> Result<SecretState, Error> {
+    if data.len() < HEADER_BYTES {
+        return Err(Error::Truncated);
+    }
+    if data[..4] != STATE_MAGIC {
+        return Err(Error::InvalidState("not a client state".to_string()));
+    }
+    if data[4] != STATE_VERSION {
+        return Err(Error::InvalidState(format!(
+            "unsupported state version {}",
+            data[4]
+        )));
+    }
+    let flags = data[5];
+    if flags & !FLAG_ENCRYPTED != 0 || data[6..HEADER_BYTES] != [0, 0] {
+        return Err(Error::InvalidState("unknown flags".to_string()));
+    }
+
+    let decrypted;
+    let payload = match (flags & FLAG_ENCRYPTED != 0, passphrase) {
+        (false, None) => &data[HEADER_BYTES..],
+        (false, Some(_)) => {
+            return Err(Error::InvalidState(
+                "state is not encrypted, but a passphrase was given".to_string(),
+            ))
+        }
+        (true, None) => {
+            return Err(Error::InvalidState(
+                "state is encrypted, but no passphrase was given".to_string(),
+            ))
+        }
+        (true, Some(passphrase)) => {
+            let sealed_start = HEADER_BYTES + KDF_PARAMS_BYTES + SALT_BYTES + NONCE_BYTES;
+            if data.len() < sealed_start {
+                return Err(Error::Truncated);
+            }
+            let salt_start = HEADER_BYTES + KDF_PARAMS_BYTES;
+            let mut key = derive_key(
+                passphrase,
+                &data[salt_start..salt_start + SALT_BYTES],
+                read_u32(data, HEADER_BYTES),
+                read_u32(data, HEADER_BYTES + 4),
+                read_u32(data, HEADER_BYTES + 8),
+            )?;
+            let cipher = ChaCha20Poly1305::new(&key);
+            key.as_mut_slice().zeroize();
+            decrypted = cipher
+                .decrypt(
+                    Nonce::from_slice(&data[salt_start + SALT_BYTES..sealed_start]),
+                    Payload {
+                        msg: &data[sealed_start..],
+                        aad: &data[..sealed_start],
+                    },
+                )
+                .map(Zeroizing::new)
+                .map_err(|_| Error::DecryptionFailed)?;
+            &decrypted[..]
+        }
+    };
+
+    if payload.len() < FINGERPRINT_BYTES + 2 {
+        return Err(Error::Truncated);
+    }
+    if payload[..FINGERPRINT_BYTES] != params.fingerprint() {
+        return Err(Error::ParamsMismatch);
+    }
+    let uuid_len =
+        u16::from_le_bytes([payload[FINGERPRINT_BYTES], payload[FINGERPRINT_BYTES + 1]]) as usize;
+    let uuid_start = FINGERPRINT_BYTES + 2;
+    let keys_start = uuid_start + uuid_len;
+    let (gsw_len, reg_len) = sk_lens(params);
+    let expected = keys_start + 8 * (gsw_len + reg_len);
+    if payload.len() != expected {
+        return Err(Error::InvalidLength(payload.len(), expected));
+    }
+
+    let uuid = match uuid_len {
+        0 => None,
+        _ => Some(
+            String::from_utf8(payload[uuid_start..keys_start].to_vec())
+                .map_err(|_| Error::InvalidState("UUID is not UTF-8".to_string()))?,
+        ),
+    };
+    let mut coeffs = payload[keys_start..]
+        .chunks_exact(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+    let mut read_key = |len: usize| -> Result<Zeroizing<Vec<u64>>, Error> {
+        let key = Zeroizing::new(coeffs.by_ref().take(len).collect::<Vec<u64>>());
+        match key.iter().find(|&&c| c >= params.modulus) {
+            Some(&c) => Err(Error::OutOfRange(c, params.modulus)),
+            None => Ok(key),
+        }
+    };
+    let sk_gsw = read_key(gsw_len)?;
+    let sk_reg = read_key(reg_len)?;
+
+    Ok(SecretState {
+        sk_gsw,
+        sk_reg,
+        uuid,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HEADER_BYTES, MAX_KDF_MEMORY_KIB, MAX_KDF_PARALLELISM, MAX_KDF_TIME_COST};
+    use crate::{
+        client::Client,
+        error::Error,
+        poly::PolyMatrix,
+        util::{get_chacha_static_seed, get_short_keygen_params, get_test_params},
+    };
+
+    const UUID: &str = "7b1a5b3c-8f1e-4d2a-9c3b-2e4f6a8b0c1d";
+
+    fn keyed_client(params: &crate::params::Params) -> Client<'_> {
+        let mut client = Client::init(params);
+        client.generate_secret_keys_from_seed(get_chacha_static_seed());
+        client
+    }
+
+    fn same_keys(a: &Client, b: &Client) -> bool {
+        a.get_sk_gsw().as_slice() == b.get_sk_gsw().as_slice()
+            && a.get_sk_reg().as_slice() == b.get_sk_reg().as_slice()
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let params = get_short_keygen_params();
+        let client = keyed_client(&params);
+
+        let plain = client.export_state(Some(UUID), None);
+        let (restored, uuid) = Client::import_state(&params, &plain, None).unwrap();
+        assert!(same_keys(&client, &restored));
+        assert_eq!(uuid.as_deref(), Some(UUID));
+
+        let sealed = client.export_state(None, Some("hunter2"));
+        let (restored, uuid) = Client::import_state(&params, &sealed, Some("hunter2")).unwrap();
+        assert!(same_keys(&client, &restored));
+        assert_eq!(uuid, None);
+    }
+
+    #[test]
+    fn bad_state_is_rejected() {
+        let params = get_short_keygen_params();
+        let client = keyed_client(&params);
+        let plain = client.export_state(Some(UUID), None);
+        let sealed = client.export_state(Some(UUID), Some("hunter2"));
+
+        let import = |data: &[u8], passphrase| {
+            Client::import_state(&params, data, passphrase)
+                .map(|_| ())
+                .unwrap_err()
+        };
+        assert_eq!(import(&sealed, Some("hunter3")), Error::DecryptionFailed);
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(import(&tampered, Some("hunter2")), Error::DecryptionFailed);
+        assert!(matches!(import(&sealed, None), Error::InvalidState(_)));
+        for (offs, cost) in [
+            (0, MAX_KDF_MEMORY_KIB),
+            (4, MAX_KDF_TIME_COST),
+            (8, MAX_KDF_PARALLELISM),
+        ] {
+            let mut costly = sealed.clone();
+            let start = HEADER_BYTES + offs;
+            costly[start..start + 4].copy_from_slice(&(cost + 1).to_le_bytes());
+            assert!(matches!(
+                import(&costly, Some("hunter2")),
+                Error::InvalidState(_)
+            ));
+        }
+        assert!(matches!(
+            import(&plain, Some("hunter2")),
+            Error::InvalidState(_)
+        ));
+        assert_eq!(
+            import(&plain[..plain.len() - 8], None),
+            Error::InvalidLength(plain.len() - 16, plain.len() - 8)
+        );
+        assert_eq!(import(&plain[..4], None), Error::Truncated);
+
+        let other_params = get_test_params();
+        assert_eq!(
+            Client::import_state(&other_params, &plain, None)
+                .map(|_| ())
+                .unwrap_err(),
+            Error::ParamsMismatch
+        );
+    }
+}
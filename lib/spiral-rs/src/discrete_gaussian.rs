@@ -70,6 +70,7 @@ pub const NUM_WIDTHS: usize = 4;
 //     18446744073709551615,
 // ];
 
+#[derive(Clone)]
 pub struct DiscreteGaussian {
     pub cdf_table: Vec<u64>,
     pub max_val: i64,
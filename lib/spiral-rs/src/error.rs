@@ -0,0 +1,61 @@
+//! Errors from parsing untrusted parameters, setup data, queries and
+//! responses.
+
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The input had the wrong length: (got, expected).
+    InvalidLength(usize, usize),
+    /// A coefficient was not reduced: (value, modulus).
+    OutOfRange(u64, u64),
+    /// The input ended in the middle of a value.
+    Truncated,
+    /// A varint ran past its maximum length.
+    InvalidVarint,
+    /// The key is not in the decoded result.
+    KeyNotFound,
+    /// No stored parameters hold this many items of this size: (items, bytes).
+    NoParamsFit(usize, usize),
+    /// A parameter set is malformed or unsupported.
+    InvalidParams(String),
+    /// A saved client state is malformed, or was given the wrong kind of
+    /// passphrase.
+    InvalidState(String),
+    /// A saved client state could not be decrypted: the passphrase is wrong,
+    /// or the state was modified.
+    DecryptionFailed,
+    /// A saved client state is for different parameters.
+    ParamsMismatch,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidLength(got, expected) => {
+                write!(f, "invalid length {} (expected {})", got, expected)
+            }
+            Error::OutOfRange(val, modulus) => {
+                write!(
+                    f,
+                    "coefficient {} out of range for modulus {}",
+                    val, modulus
+                )
+            }
+            Error::Truncated => write!(f, "input is truncated"),
+            Error::InvalidVarint => write!(f, "invalid varint"),
+            Error::KeyNotFound => write!(f, "key not found"),
+            Error::NoParamsFit(num_items, item_size) => write!(
+                f,
+                "no stored parameters fit {} items of {} bytes",
+                num_items, item_size
+            ),
+            Error::InvalidParams(reason) => write!(f, "invalid parameters: {}", reason),
+            Error::InvalidState(reason) => write!(f, "invalid client state: {}", reason),
+            Error::DecryptionFailed => write!(f, "could not decrypt client state"),
+            Error::ParamsMismatch => write!(f, "client state is for other parameters"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
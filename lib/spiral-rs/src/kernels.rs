@@ -0,0 +1,195 @@
+//! Runtime selection of the SIMD kernels used for the NTT and for polynomial
+//! multiplication.
+//!
+//! The CPU is probed once, on first use, and the fastest supported table is
+//! used from then on. Setting `SPIRAL_SIMD` to `scalar`, `avx2` or `avx512`
+//! caps the level (it never raises it above what the CPU supports), which is
+//! handy for benchmarking and for ruling out a miscompiled kernel. The AVX-512
+//! kernels are only built with the `avx512` feature.
+
+use std::sync::OnceLock;
+
+use crate::{ntt, params::Params, poly};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SimdLevel {
+    Scalar,
+    Avx2,
+    Avx512,
+}
+
+impl SimdLevel {
+    pub const ALL: [SimdLevel; 3] = [SimdLevel::Scalar, SimdLevel::Avx2, SimdLevel::Avx512];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SimdLevel::Scalar => "scalar",
+            SimdLevel::Avx2 => "avx2",
+            SimdLevel::Avx512 => "avx512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SimdLevel> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// The best level this CPU supports, ignoring `SPIRAL_SIMD`.
+    pub fn supported() -> SimdLevel {
+        static SUPPORTED: OnceLock<SimdLevel> = OnceLock::new();
+        *SUPPORTED.get_or_init(probe)
+    }
+
+    /// The level the dispatching functions use: `supported()`, capped by
+    /// `SPIRAL_SIMD` if it is set.
+    pub fn detect() -> SimdLevel {
+        static DETECTED: OnceLock<SimdLevel> = OnceLock::new();
+        *DETECTED.get_or_init(|| {
+            let supported = Self::supported();
+            match std::env::var("SPIRAL_SIMD") {
+                Ok(name) => match Self::from_name(&name) {
+                    Some(level) => level.min(supported),
+                    None => supported,
+                },
+                Err(_) => supported,
+            }
+        })
+    }
+
+    /// Every level this CPU can run, lowest first.
+    pub fn available() -> impl Iterator<Item = SimdLevel> {
+        let supported = Self::supported();
+        Self::ALL
+            .into_iter()
+            .filter(move |level| *level <= supported)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn probe() -> SimdLevel {
+    if cfg!(feature = "avx512") && is_x86_feature_detected!("avx512f") {
+        SimdLevel::Avx512
+    } else if is_x86_feature_detected!("avx2") {
+        SimdLevel::Avx2
+    } else {
+        SimdLevel::Scalar
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn probe() -> SimdLevel {
+    SimdLevel::Scalar
+}
+
+/// One implementation of each hot kernel.
+///
+/// `multiply_add_poly_lazy` accumulates `a * b` into `res` coefficient-wise
+/// without reducing; inputs must be below 2^32 and the caller is responsible
+/// for reducing before the accumulator can overflow.
+pub struct Kernels {
+    pub level: SimdLevel,
+    pub ntt_forward: fn(&Params, &mut [u64]),
+    pub ntt_inverse: fn(&Params, &mut [u64]),
+    pub multiply_add_poly_lazy: fn(&Params, &mut [u64], &[u64], &[u64]),
+}
+
+static SCALAR: Kernels = Kernels {
+    level: SimdLevel::Scalar,
+    ntt_forward: ntt::ntt_forward_scalar,
+    ntt_inverse: ntt::ntt_inverse_scalar,
+    multiply_add_poly_lazy: poly::multiply_add_poly_lazy_scalar,
+};
+
+#[cfg(target_arch = "x86_64")]
+static AVX2: Kernels = Kernels {
+    level: SimdLevel::Avx2,
+    ntt_forward: x86::ntt_forward_avx2,
+    ntt_inverse: x86::ntt_inverse_avx2,
+    multiply_add_poly_lazy: x86::multiply_add_poly_lazy_avx2,
+};
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+static AVX512: Kernels = Kernels {
+    level: SimdLevel::Avx512,
+    ntt_forward: x86::ntt_forward_avx512,
+    ntt_inverse: x86::ntt_inverse_avx512,
+    multiply_add_poly_lazy: x86::multiply_add_poly_lazy_avx512,
+};
+
+impl Kernels {
+    /// The table for `level`. Panics if this CPU cannot run `level`.
+    pub fn for_level(level: SimdLevel) -> &'static Kernels {
+        assert!(
+            level <= SimdLevel::supported(),
+            "{} kernels are not supported on this CPU",
+            level.name()
+        );
+        match level {
+            SimdLevel::Scalar => &SCALAR,
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 => &AVX2,
+            #[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+            SimdLevel::Avx512 => &AVX512,
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The table for `SimdLevel::detect()`.
+pub fn kernels() -> &'static Kernels {
+    static KERNELS: OnceLock<&'static Kernels> = OnceLock::new();
+    KERNELS.get_or_init(|| Kernels::for_level(SimdLevel::detect()))
+}
+
+/// Safe wrappers around the `#[target_feature]` kernels. They are only
+/// reachable through the tables above, which `Kernels::for_level` refuses to
+/// hand out unless the CPU supports the level.
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use crate::{ntt, params::Params, poly};
+
+    pub fn ntt_forward_avx2(params: &Params, operand: &mut [u64]) {
+        unsafe { ntt::ntt_forward_avx2(params, operand) }
+    }
+
+    pub fn ntt_inverse_avx2(params: &Params, operand: &mut [u64]) {
+        unsafe { ntt::ntt_inverse_avx2(params, operand) }
+    }
+
+    pub fn multiply_add_poly_lazy_avx2(params: &Params, res: &mut [u64], a: &[u64], b: &[u64]) {
+        unsafe { poly::multiply_add_poly_lazy_avx2(params, res, a, b) }
+    }
+
+    #[cfg(feature = "avx512")]
+    pub fn ntt_forward_avx512(params: &Params, operand: &mut [u64]) {
+        unsafe { ntt::ntt_forward_avx512(params, operand) }
+    }
+
+    #[cfg(feature = "avx512")]
+    pub fn ntt_inverse_avx512(params: &Params, operand: &mut [u64]) {
+        unsafe { ntt::ntt_inverse_avx512(params, operand) }
+    }
+
+    #[cfg(feature = "avx512")]
+    pub fn multiply_add_poly_lazy_avx512(params: &Params, res: &mut [u64], a: &[u64], b: &[u64]) {
+        unsafe { poly::multiply_add_poly_lazy_avx512(params, res, a, b) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simd_level_names_round_trip() {
+        for level in SimdLevel::ALL {
+            assert_eq!(SimdLevel::from_name(level.name()), Some(level));
+        }
+        assert_eq!(SimdLevel::from_name(" AVX2 "), Some(SimdLevel::Avx2));
+        assert_eq!(SimdLevel::from_name("neon"), None);
+        assert!(SimdLevel::detect() <= SimdLevel::supported());
+        assert_eq!(SimdLevel::available().next(), Some(SimdLevel::Scalar));
+    }
+}
@@ -1,16 +1,26 @@
+use crate::error::Error;
 use crate::params::Params;
 use sha2::{Digest, Sha256};
 
 const VARINT_MAX_BYTES: usize = 8;
 const MAX_VARINT_BITS: u64 = 63;
 
+/// Panics if `data` is truncated; see `try_varint_decode`.
 pub fn varint_decode(data: &[u8]) -> (usize, usize) {
+    try_varint_decode(data).unwrap()
+}
+
+/// Decodes a varint, returning the value and the number of bytes it took.
+pub fn try_varint_decode(data: &[u8]) -> Result<(usize, usize), Error> {
     let mut shift = 0u64;
     let mut result = 0u64;
     let mut j = 0;
 
-    while shift < MAX_VARINT_BITS {
-        let i = data[j] as u64;
+    loop {
+        if shift >= MAX_VARINT_BITS {
+            return Err(Error::InvalidVarint);
+        }
+        let i = *data.get(j).ok_or(Error::Truncated)? as u64;
         j += 1;
         result |= (i & 0x7f) << shift;
         shift += 7;
@@ -19,7 +29,7 @@ pub fn varint_decode(data: &[u8]) -> (usize, usize) {
         }
     }
 
-    (result as usize, j)
+    Ok((result as usize, j))
 }
 
 pub fn row_from_key(params: &Params, key: &str) -> usize {
@@ -39,22 +49,41 @@ pub fn row_from_key(params: &Params, key: &str) -> usize {
     idx
 }
 
+/// Returns "key not found" or "malformed result"; see `try_extract_result`.
 pub fn extract_result_impl(key: &str, result: &[u8]) -> Result<Vec<u8>, &'static str> {
-    let hash_bytes = result[0] as usize;
+    try_extract_result(key, result).map_err(|e| match e {
+        Error::KeyNotFound => "key not found",
+        _ => "malformed result",
+    })
+}
+
+/// Finds the value for `key` in a decoded row, failing with `KeyNotFound` if
+/// it isn't there, or another error if the row is malformed.
+pub fn try_extract_result(key: &str, result: &[u8]) -> Result<Vec<u8>, Error> {
+    let hash_bytes = *result.first().ok_or(Error::Truncated)? as usize;
     let hash = Sha256::digest(key.as_bytes());
+    if hash_bytes > hash.len() {
+        return Err(Error::OutOfRange(hash_bytes as u64, hash.len() as u64 + 1));
+    }
     let target = &hash[(hash.len() - hash_bytes)..];
     let mut i = 1;
     while i < result.len() {
-        // read key
-        let key_hash = &result[i..i + hash_bytes];
+        // read key; a tail too short for one is padding
+        let Some(key_hash) = result.get(i..i + hash_bytes) else {
+            break;
+        };
         i += hash_bytes;
 
         // read len
-        let (value_len, value_len_len) = varint_decode(&result[i..i + VARINT_MAX_BYTES]);
+        let len_end = usize::min(i + VARINT_MAX_BYTES, result.len());
+        let (value_len, value_len_len) = try_varint_decode(&result[i..len_end])?;
         i += value_len_len;
 
         // read value
-        let value = &result[i..i + value_len];
+        let value = i
+            .checked_add(value_len)
+            .and_then(|end| result.get(i..end))
+            .ok_or(Error::Truncated)?;
         i += value_len;
 
         if key_hash == target {
@@ -62,7 +91,7 @@ pub fn extract_result_impl(key: &str, result: &[u8]) -> Result<Vec<u8>, &'static
         }
     }
 
-    Err("key not found")
+    Err(Error::KeyNotFound)
 }
 
 #[cfg(test)]
@@ -95,4 +124,31 @@ mod test {
         assert_eq!(row_from_key(&params, "CA"), 4825);
         assert_eq!(row_from_key(&params, "OR"), 8359);
     }
+
+    #[test]
+    fn malformed_results_are_rejected() {
+        let hash = Sha256::digest(b"CA");
+        let mut result = vec![4u8];
+        result.extend(&hash[hash.len() - 4..]);
+        result.extend([3, b'a', b'b', b'c']);
+        result.extend([0u8; 3]);
+        assert_eq!(try_extract_result("CA", &result).unwrap(), b"abc");
+        assert_eq!(extract_result_impl("OR", &result), Err("key not found"));
+        assert_eq!(extract_result_impl("CA", &[]), Err("malformed result"));
+        assert_eq!(try_extract_result("OR", &result), Err(Error::KeyNotFound));
+
+        assert_eq!(try_extract_result("CA", &[]), Err(Error::Truncated));
+        assert_eq!(
+            try_extract_result("CA", &result[..result.len() - 5]),
+            Err(Error::Truncated)
+        );
+        assert_eq!(
+            try_extract_result("CA", &[64]),
+            Err(Error::OutOfRange(64, 33))
+        );
+
+        assert_eq!(try_varint_decode(&[0x80, 0x01]), Ok((128, 2)));
+        assert_eq!(try_varint_decode(&[0x80]), Err(Error::Truncated));
+        assert_eq!(try_varint_decode(&[0xff; 16]), Err(Error::InvalidVarint));
+    }
 }
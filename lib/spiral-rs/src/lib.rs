@@ -1,17 +1,22 @@
 pub mod aligned_memory;
 pub mod arith;
 pub mod discrete_gaussian;
+pub mod error;
 pub mod noise_estimate;
+pub mod param_gen;
 pub mod number_theory;
 pub mod util;
 
 pub mod gadget;
+pub mod kernels;
 pub mod ntt;
 pub mod params;
 pub mod poly;
 
 pub mod client;
+pub mod client_state;
 pub mod key_value;
+pub mod wire;
 
 #[cfg(feature = "server")]
 pub mod server;
@@ -11,6 +11,7 @@ pub struct Paramset {
     pub d: usize,
     pub p: u64,
     pub q: u64,
+    pub q1: u64,
     pub sigma: f64,
     pub t_conv: usize,
     pub t_exp_left: usize,
@@ -27,6 +28,7 @@ pub fn extract_paramset(params: &Params) -> Paramset {
         d: params.poly_len,
         p: params.pt_modulus,
         q: params.modulus,
+        q1: params.q1,
         sigma: params.noise_width,
         t_conv: params.t_conv,
         t_exp_left: params.t_exp_left,
@@ -97,15 +99,21 @@ pub fn get_noise_from_paramset(s: &Paramset) -> f64 {
     sigma_r_2 + sigma_packing_2
 }
 
+/// The log2 probability that a response switched to `s.q1` and `q_prime`
+/// fails to decode. Rounding to q1 costs up to p / (2 * q1) of the 1/2
+/// decoding margin; twice that is set aside, which is 1/4 for q1 = 4p.
 pub fn get_p_err(s: &Paramset, s_e: f64, q_prime: u64) -> f64 {
     let p_f = s.p as f64;
     let q_prime_f = q_prime as f64;
     let q_f = s.q as f64;
+    let q1_f = s.q1 as f64;
 
     let q_mod_p = 1;
-    let modswitch_adj = (1. / 8.) * ((4. * p_f) * (q_mod_p as f64) / q_f);
-    let thresh = (1. / 4.) - modswitch_adj;
-    assert!((thresh > 0.) && (thresh < (1. / 4.)));
+    let modswitch_adj = (1. / 8.) * (q1_f * (q_mod_p as f64) / q_f);
+    let thresh = (1. / 2.) - (p_f / q1_f) - modswitch_adj;
+    if thresh <= 0. {
+        return 0.;
+    }
 
     let s_round_2 = s.sigma.powi(2) * (s.d as f64) / 4.;
     let numer = -PI * thresh.powi(2);
@@ -168,4 +176,17 @@ mod test {
         // assert!(noise_log2 < 87.0);
         assert!(p_err <= -40.0);
     }
+
+    #[test]
+    fn larger_q1_lowers_error_probability() {
+        let mut params = get_params_from_store(1 << 14, 32768).unwrap().params;
+        assert_eq!(params.q1, 4 * params.pt_modulus);
+        let base = params.estimate_log2_err_prob();
+
+        params.q1 = 8 * params.pt_modulus;
+        assert!(params.estimate_log2_err_prob() < base);
+
+        params.q1 = 2 * params.pt_modulus;
+        assert_eq!(params.estimate_log2_err_prob(), 0.);
+    }
 }
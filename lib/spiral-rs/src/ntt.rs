@@ -1,7 +1,7 @@
-#[cfg(target_feature = "avx2")]
+#[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
-use crate::{arith::*, number_theory::*, params::*};
+use crate::{arith::*, kernels::kernels, number_theory::*, params::*};
 
 pub fn powers_of_primitive_root(root: u64, modulus: u64, poly_len_log2: usize) -> Vec<u64> {
     let poly_len = 1usize << poly_len_log2;
@@ -48,8 +48,8 @@ pub fn build_ntt_tables(poly_len: usize, moduli: &[u64]) -> Vec<Vec<Vec<u64>>> {
         let root_powers = powers_of_primitive_root(root, modulus, poly_len_log2);
         let scaled_root_powers = scale_powers_u32(modulus_as_u32, poly_len, root_powers.as_slice());
         let mut inv_root_powers = powers_of_primitive_root(inv_root, modulus, poly_len_log2);
-        for i in 0..poly_len {
-            inv_root_powers[i] = div2_uint_mod(inv_root_powers[i], modulus);
+        for x in inv_root_powers.iter_mut().take(poly_len) {
+            *x = div2_uint_mod(*x, modulus);
         }
         let scaled_inv_root_powers =
             scale_powers_u32(modulus_as_u32, poly_len, inv_root_powers.as_slice());
@@ -64,8 +64,64 @@ pub fn build_ntt_tables(poly_len: usize, moduli: &[u64]) -> Vec<Vec<Vec<u64>>> {
     output
 }
 
-#[cfg(not(target_feature = "avx2"))]
+/// Forward NTT of every CRT limb of `operand_overall`, in place, using the
+/// fastest kernel this CPU supports.
 pub fn ntt_forward(params: &Params, operand_overall: &mut [u64]) {
+    (kernels().ntt_forward)(params, operand_overall)
+}
+
+/// Inverse NTT of every CRT limb of `operand_overall`, in place, using the
+/// fastest kernel this CPU supports.
+pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
+    (kernels().ntt_inverse)(params, operand_overall)
+}
+
+#[inline(always)]
+fn forward_butterflies(op: &mut [u64], t: usize, w: u64, w_prime: u64, modulus_small: u32) {
+    let two_times_modulus_small: u32 = 2 * modulus_small;
+    for j in 0..t {
+        let x: u32 = op[j] as u32;
+        let y: u32 = op[t + j] as u32;
+
+        let curr_x: u32 = x - (two_times_modulus_small * ((x >= two_times_modulus_small) as u32));
+        let q_tmp: u64 = ((y as u64) * w_prime) >> 32u64;
+        let q_new = w * (y as u64) - q_tmp * (modulus_small as u64);
+
+        op[j] = curr_x as u64 + q_new;
+        op[t + j] = curr_x as u64 + ((two_times_modulus_small as u64) - q_new);
+    }
+}
+
+#[inline(always)]
+fn inverse_butterflies(op: &mut [u64], t: usize, w: u64, w_prime: u64, modulus: u64) {
+    let two_times_modulus: u64 = 2 * modulus;
+    for j in 0..t {
+        let x = op[j];
+        let y = op[t + j];
+
+        let t_tmp = two_times_modulus - y + x;
+        let curr_x = x + y - (two_times_modulus * (((x << 1) >= t_tmp) as u64));
+        let h_tmp = (t_tmp * w_prime) >> 32;
+
+        let res_x = (curr_x + (modulus * (t_tmp & 1))) >> 1;
+        let res_y = w * t_tmp - h_tmp * modulus;
+
+        op[j] = res_x;
+        op[t + j] = res_y;
+    }
+}
+
+/// Brings values in [0, 4q) down to [0, q).
+#[inline(always)]
+fn final_reduce(operand: &mut [u64], modulus: u64) {
+    let two_times_modulus = 2 * modulus;
+    for x in operand.iter_mut() {
+        *x -= ((*x >= two_times_modulus) as u64) * two_times_modulus;
+        *x -= ((*x >= modulus) as u64) * modulus;
+    }
+}
+
+pub fn ntt_forward_scalar(params: &Params, operand_overall: &mut [u64]) {
     let log_n = params.poly_len_log2;
     let n = 1 << log_n;
 
@@ -75,7 +131,6 @@ pub fn ntt_forward(params: &Params, operand_overall: &mut [u64]) {
         let forward_table = params.get_ntt_forward_table(coeff_mod);
         let forward_table_prime = params.get_ntt_forward_prime_table(coeff_mod);
         let modulus_small = params.moduli[coeff_mod] as u32;
-        let two_times_modulus_small: u32 = 2 * modulus_small;
 
         for mm in 0..log_n {
             let m = 1 << mm;
@@ -88,32 +143,53 @@ pub fn ntt_forward(params: &Params, operand_overall: &mut [u64]) {
                 let w_prime = forward_table_prime[m + i];
 
                 let op = it.next().unwrap();
+                forward_butterflies(op, t, w, w_prime, modulus_small);
+            }
+        }
 
-                for j in 0..t {
-                    let x: u32 = op[j] as u32;
-                    let y: u32 = op[t + j] as u32;
+        final_reduce(operand, modulus_small as u64);
+    }
+}
 
-                    let curr_x: u32 =
-                        x - (two_times_modulus_small * ((x >= two_times_modulus_small) as u32));
-                    let q_tmp: u64 = ((y as u64) * (w_prime as u64)) >> 32u64;
-                    let q_new = w * (y as u64) - q_tmp * (modulus_small as u64);
+pub fn ntt_inverse_scalar(params: &Params, operand_overall: &mut [u64]) {
+    for coeff_mod in 0..params.crt_count {
+        let n = params.poly_len;
 
-                    op[j] = curr_x as u64 + q_new;
-                    op[t + j] = curr_x as u64 + ((two_times_modulus_small as u64) - q_new);
-                }
+        let operand = &mut operand_overall[coeff_mod * n..coeff_mod * n + n];
+
+        let inverse_table = params.get_ntt_inverse_table(coeff_mod);
+        let inverse_table_prime = params.get_ntt_inverse_prime_table(coeff_mod);
+        let modulus = params.moduli[coeff_mod];
+
+        for mm in (0..params.poly_len_log2).rev() {
+            let h = 1 << mm;
+            let t = n >> (mm + 1);
+
+            let mut it = operand.chunks_exact_mut(2 * t);
+
+            for i in 0..h {
+                let w = inverse_table[h + i];
+                let w_prime = inverse_table_prime[h + i];
+
+                let op = it.next().unwrap();
+                inverse_butterflies(op, t, w, w_prime, modulus);
             }
         }
 
-        for i in 0..n {
-            operand[i] -= ((operand[i] >= two_times_modulus_small as u64) as u64)
-                * two_times_modulus_small as u64;
-            operand[i] -= ((operand[i] >= modulus_small as u64) as u64) * modulus_small as u64;
-        }
+        final_reduce(operand, modulus);
     }
 }
 
-#[cfg(target_feature = "avx2")]
-pub fn ntt_forward(params: &Params, operand_overall: &mut [u64]) {
+// The SIMD kernels below compute exactly what the scalar ones do, lane by
+// lane, so their outputs are bit-for-bit identical. Comparisons are written
+// as `x > c - 1` where the scalar code has `x >= c`. Loads and stores are
+// unaligned since callers may pass any `&mut [u64]`.
+
+/// # Safety
+/// The CPU must support AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub(crate) unsafe fn ntt_forward_avx2(params: &Params, operand_overall: &mut [u64]) {
     let log_n = params.poly_len_log2;
     let n = 1 << log_n;
 
@@ -125,6 +201,10 @@ pub fn ntt_forward(params: &Params, operand_overall: &mut [u64]) {
         let modulus_small = params.moduli[coeff_mod] as u32;
         let two_times_modulus_small: u32 = 2 * modulus_small;
 
+        let cmp_val = _mm256_set1_epi64x(two_times_modulus_small as i64);
+        let cmp_val_minus_one = _mm256_set1_epi64x(two_times_modulus_small as i64 - 1);
+        let modulus_small_vec = _mm256_set1_epi64x(modulus_small as i64);
+
         for mm in 0..log_n {
             let m = 1 << mm;
             let t = n >> (mm + 1);
@@ -138,79 +218,63 @@ pub fn ntt_forward(params: &Params, operand_overall: &mut [u64]) {
                 let op = it.next().unwrap();
 
                 if t < 4 {
-                    for j in 0..t {
-                        let x: u32 = op[j] as u32;
-                        let y: u32 = op[t + j] as u32;
-
-                        let curr_x: u32 =
-                            x - (two_times_modulus_small * ((x >= two_times_modulus_small) as u32));
-                        let q_tmp: u64 = ((y as u64) * (w_prime as u64)) >> 32u64;
-                        let q_new = w * (y as u64) - q_tmp * (modulus_small as u64);
-
-                        op[j] = curr_x as u64 + q_new;
-                        op[t + j] = curr_x as u64 + ((two_times_modulus_small as u64) - q_new);
-                    }
-                } else {
-                    unsafe {
-                        for j in (0..t).step_by(4) {
-                            // Use AVX2 here
-                            let p_x = &mut op[j] as *mut u64;
-                            let p_y = &mut op[j + t] as *mut u64;
-                            let x = _mm256_load_si256(p_x as *const __m256i);
-                            let y = _mm256_load_si256(p_y as *const __m256i);
-
-                            let cmp_val = _mm256_set1_epi64x(two_times_modulus_small as i64);
-                            let gt_mask = _mm256_cmpgt_epi64(x, cmp_val);
-
-                            let to_subtract = _mm256_and_si256(gt_mask, cmp_val);
-                            let curr_x = _mm256_sub_epi64(x, to_subtract);
-
-                            // uint32_t q_val = ((y) * (uint64_t)(Wprime)) >> 32;
-                            let w_prime_vec = _mm256_set1_epi64x(w_prime as i64);
-                            let product = _mm256_mul_epu32(y, w_prime_vec);
-                            let q_val = _mm256_srli_epi64(product, 32);
-
-                            // q_val = W * y - q_val * modulus_small;
-                            let w_vec = _mm256_set1_epi64x(w as i64);
-                            let w_times_y = _mm256_mul_epu32(y, w_vec);
-                            let modulus_small_vec = _mm256_set1_epi64x(modulus_small as i64);
-                            let q_scaled = _mm256_mul_epu32(q_val, modulus_small_vec);
-                            let q_final = _mm256_sub_epi64(w_times_y, q_scaled);
-
-                            let new_x = _mm256_add_epi64(curr_x, q_final);
-                            let q_final_inverted = _mm256_sub_epi64(cmp_val, q_final);
-                            let new_y = _mm256_add_epi64(curr_x, q_final_inverted);
-
-                            _mm256_store_si256(p_x as *mut __m256i, new_x);
-                            _mm256_store_si256(p_y as *mut __m256i, new_y);
-                        }
-                    }
+                    forward_butterflies(op, t, w, w_prime, modulus_small);
+                    continue;
+                }
+
+                let w_prime_vec = _mm256_set1_epi64x(w_prime as i64);
+                let w_vec = _mm256_set1_epi64x(w as i64);
+                for j in (0..t).step_by(4) {
+                    let p_x = op.as_mut_ptr().add(j) as *mut __m256i;
+                    let p_y = op.as_mut_ptr().add(j + t) as *mut __m256i;
+                    let x = _mm256_loadu_si256(p_x);
+                    let y = _mm256_loadu_si256(p_y);
+
+                    let ge_mask = _mm256_cmpgt_epi64(x, cmp_val_minus_one);
+                    let to_subtract = _mm256_and_si256(ge_mask, cmp_val);
+                    let curr_x = _mm256_sub_epi64(x, to_subtract);
+
+                    // uint32_t q_val = ((y) * (uint64_t)(Wprime)) >> 32;
+                    let product = _mm256_mul_epu32(y, w_prime_vec);
+                    let q_val = _mm256_srli_epi64(product, 32);
+
+                    // q_val = W * y - q_val * modulus_small;
+                    let w_times_y = _mm256_mul_epu32(y, w_vec);
+                    let q_scaled = _mm256_mul_epu32(q_val, modulus_small_vec);
+                    let q_final = _mm256_sub_epi64(w_times_y, q_scaled);
+
+                    let new_x = _mm256_add_epi64(curr_x, q_final);
+                    let q_final_inverted = _mm256_sub_epi64(cmp_val, q_final);
+                    let new_y = _mm256_add_epi64(curr_x, q_final_inverted);
+
+                    _mm256_storeu_si256(p_x, new_x);
+                    _mm256_storeu_si256(p_y, new_y);
                 }
             }
         }
 
+        let modulus_small_minus_one = _mm256_set1_epi64x(modulus_small as i64 - 1);
         for i in (0..n).step_by(4) {
-            unsafe {
-                let p_x = &mut operand[i] as *mut u64;
-
-                let cmp_val1 = _mm256_set1_epi64x(two_times_modulus_small as i64);
-                let mut x = _mm256_load_si256(p_x as *const __m256i);
-                let mut gt_mask = _mm256_cmpgt_epi64(x, cmp_val1);
-                let mut to_subtract = _mm256_and_si256(gt_mask, cmp_val1);
-                x = _mm256_sub_epi64(x, to_subtract);
-
-                let cmp_val2 = _mm256_set1_epi64x(modulus_small as i64);
-                gt_mask = _mm256_cmpgt_epi64(x, cmp_val2);
-                to_subtract = _mm256_and_si256(gt_mask, cmp_val2);
-                x = _mm256_sub_epi64(x, to_subtract);
-                _mm256_store_si256(p_x as *mut __m256i, x);
-            }
+            let p_x = operand.as_mut_ptr().add(i) as *mut __m256i;
+            let mut x = _mm256_loadu_si256(p_x);
+
+            let mut ge_mask = _mm256_cmpgt_epi64(x, cmp_val_minus_one);
+            let mut to_subtract = _mm256_and_si256(ge_mask, cmp_val);
+            x = _mm256_sub_epi64(x, to_subtract);
+
+            ge_mask = _mm256_cmpgt_epi64(x, modulus_small_minus_one);
+            to_subtract = _mm256_and_si256(ge_mask, modulus_small_vec);
+            x = _mm256_sub_epi64(x, to_subtract);
+            _mm256_storeu_si256(p_x, x);
         }
     }
 }
 
-#[cfg(not(target_feature = "avx2"))]
-pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
+/// # Safety
+/// The CPU must support AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub(crate) unsafe fn ntt_inverse_avx2(params: &Params, operand_overall: &mut [u64]) {
     for coeff_mod in 0..params.crt_count {
         let n = params.poly_len;
 
@@ -221,6 +285,10 @@ pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
         let modulus = params.moduli[coeff_mod];
         let two_times_modulus: u64 = 2 * modulus;
 
+        let modulus_vec = _mm256_set1_epi64x(modulus as i64);
+        let two_times_modulus_vec = _mm256_set1_epi64x(two_times_modulus as i64);
+        let one = _mm256_set1_epi64x(1);
+
         for mm in (0..params.poly_len_log2).rev() {
             let h = 1 << mm;
             let t = n >> (mm + 1);
@@ -233,32 +301,132 @@ pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
 
                 let op = it.next().unwrap();
 
-                for j in 0..t {
-                    let x = op[j];
-                    let y = op[t + j];
+                if t < 4 {
+                    inverse_butterflies(op, t, w, w_prime, modulus);
+                    continue;
+                }
+
+                let w_prime_vec = _mm256_set1_epi64x(w_prime as i64);
+                let w_vec = _mm256_set1_epi64x(w as i64);
+                for j in (0..t).step_by(4) {
+                    let p_x = op.as_mut_ptr().add(j) as *mut __m256i;
+                    let p_y = op.as_mut_ptr().add(j + t) as *mut __m256i;
+                    let x = _mm256_loadu_si256(p_x);
+                    let y = _mm256_loadu_si256(p_y);
+
+                    let mut t_tmp = _mm256_sub_epi64(two_times_modulus_vec, y);
+                    t_tmp = _mm256_add_epi64(t_tmp, x);
+                    // (x << 1) >= t_tmp
+                    let ge_mask =
+                        _mm256_cmpgt_epi64(_mm256_add_epi64(_mm256_slli_epi64(x, 1), one), t_tmp);
+                    let to_subtract = _mm256_and_si256(ge_mask, two_times_modulus_vec);
+                    let mut curr_x = _mm256_add_epi64(x, y);
+                    curr_x = _mm256_sub_epi64(curr_x, to_subtract);
+
+                    let mut h_tmp = _mm256_mul_epu32(t_tmp, w_prime_vec);
+                    h_tmp = _mm256_srli_epi64(h_tmp, 32);
+
+                    let eq_mask = _mm256_cmpeq_epi64(_mm256_and_si256(t_tmp, one), one);
+                    let to_add = _mm256_and_si256(eq_mask, modulus_vec);
+
+                    let new_x = _mm256_srli_epi64(_mm256_add_epi64(curr_x, to_add), 1);
+
+                    let w_times_t_tmp = _mm256_mul_epu32(t_tmp, w_vec);
+                    let h_tmp_times_modulus = _mm256_mul_epu32(h_tmp, modulus_vec);
+                    let new_y = _mm256_sub_epi64(w_times_t_tmp, h_tmp_times_modulus);
+
+                    _mm256_storeu_si256(p_x, new_x);
+                    _mm256_storeu_si256(p_y, new_y);
+                }
+            }
+        }
+
+        final_reduce(operand, modulus);
+    }
+}
+
+/// # Safety
+/// The CPU must support AVX-512F.
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+#[allow(clippy::incompatible_msrv)] // the `avx512` feature needs Rust 1.89
+pub(crate) unsafe fn ntt_forward_avx512(params: &Params, operand_overall: &mut [u64]) {
+    let log_n = params.poly_len_log2;
+    let n = 1 << log_n;
+
+    for coeff_mod in 0..params.crt_count {
+        let operand = &mut operand_overall[coeff_mod * n..coeff_mod * n + n];
+
+        let forward_table = params.get_ntt_forward_table(coeff_mod);
+        let forward_table_prime = params.get_ntt_forward_prime_table(coeff_mod);
+        let modulus_small = params.moduli[coeff_mod] as u32;
+        let two_times_modulus_small: u32 = 2 * modulus_small;
+
+        let two_times_modulus_vec = _mm512_set1_epi64(two_times_modulus_small as i64);
+        let modulus_vec = _mm512_set1_epi64(modulus_small as i64);
+
+        for mm in 0..log_n {
+            let m = 1 << mm;
+            let t = n >> (mm + 1);
+
+            let mut it = operand.chunks_exact_mut(2 * t);
 
-                    let t_tmp = two_times_modulus - y + x;
-                    let curr_x = x + y - (two_times_modulus * (((x << 1) >= t_tmp) as u64));
-                    let h_tmp = (t_tmp * w_prime) >> 32;
+            for i in 0..m {
+                let w = forward_table[m + i];
+                let w_prime = forward_table_prime[m + i];
 
-                    let res_x = (curr_x + (modulus * ((t_tmp & 1) as u64))) >> 1;
-                    let res_y = w * t_tmp - h_tmp * modulus;
+                let op = it.next().unwrap();
 
-                    op[j] = res_x;
-                    op[t + j] = res_y;
+                if t < 8 {
+                    forward_butterflies(op, t, w, w_prime, modulus_small);
+                    continue;
+                }
+
+                let w_prime_vec = _mm512_set1_epi64(w_prime as i64);
+                let w_vec = _mm512_set1_epi64(w as i64);
+                for j in (0..t).step_by(8) {
+                    let p_x = op.as_mut_ptr().add(j);
+                    let p_y = op.as_mut_ptr().add(j + t);
+                    let x = _mm512_loadu_epi64(p_x as *const i64);
+                    let y = _mm512_loadu_epi64(p_y as *const i64);
+
+                    let ge_mask = _mm512_cmpge_epu64_mask(x, two_times_modulus_vec);
+                    let curr_x = _mm512_mask_sub_epi64(x, ge_mask, x, two_times_modulus_vec);
+
+                    let q_val = _mm512_srli_epi64(_mm512_mul_epu32(y, w_prime_vec), 32);
+                    let q_final = _mm512_sub_epi64(
+                        _mm512_mul_epu32(y, w_vec),
+                        _mm512_mul_epu32(q_val, modulus_vec),
+                    );
+
+                    let new_x = _mm512_add_epi64(curr_x, q_final);
+                    let new_y =
+                        _mm512_add_epi64(curr_x, _mm512_sub_epi64(two_times_modulus_vec, q_final));
+
+                    _mm512_storeu_epi64(p_x as *mut i64, new_x);
+                    _mm512_storeu_epi64(p_y as *mut i64, new_y);
                 }
             }
         }
 
-        for i in 0..n {
-            operand[i] -= ((operand[i] >= two_times_modulus) as u64) * two_times_modulus;
-            operand[i] -= ((operand[i] >= modulus) as u64) * modulus;
+        for i in (0..n).step_by(8) {
+            let p_x = operand.as_mut_ptr().add(i) as *mut i64;
+            let mut x = _mm512_loadu_epi64(p_x);
+            let mut ge_mask = _mm512_cmpge_epu64_mask(x, two_times_modulus_vec);
+            x = _mm512_mask_sub_epi64(x, ge_mask, x, two_times_modulus_vec);
+            ge_mask = _mm512_cmpge_epu64_mask(x, modulus_vec);
+            x = _mm512_mask_sub_epi64(x, ge_mask, x, modulus_vec);
+            _mm512_storeu_epi64(p_x, x);
         }
     }
 }
 
-#[cfg(target_feature = "avx2")]
-pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
+/// # Safety
+/// The CPU must support AVX-512F.
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+#[allow(clippy::incompatible_msrv)] // the `avx512` feature needs Rust 1.89
+pub(crate) unsafe fn ntt_inverse_avx512(params: &Params, operand_overall: &mut [u64]) {
     for coeff_mod in 0..params.crt_count {
         let n = params.poly_len;
 
@@ -268,6 +436,11 @@ pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
         let inverse_table_prime = params.get_ntt_inverse_prime_table(coeff_mod);
         let modulus = params.moduli[coeff_mod];
         let two_times_modulus: u64 = 2 * modulus;
+
+        let modulus_vec = _mm512_set1_epi64(modulus as i64);
+        let two_times_modulus_vec = _mm512_set1_epi64(two_times_modulus as i64);
+        let one = _mm512_set1_epi64(1);
+
         for mm in (0..params.poly_len_log2).rev() {
             let h = 1 << mm;
             let t = n >> (mm + 1);
@@ -280,94 +453,54 @@ pub fn ntt_inverse(params: &Params, operand_overall: &mut [u64]) {
 
                 let op = it.next().unwrap();
 
-                if t < 4 {
-                    for j in 0..t {
-                        let x = op[j];
-                        let y = op[t + j];
-
-                        let t_tmp = two_times_modulus - y + x;
-                        let curr_x = x + y - (two_times_modulus * (((x << 1) >= t_tmp) as u64));
-                        let h_tmp = (t_tmp * w_prime) >> 32;
-
-                        let res_x = (curr_x + (modulus * ((t_tmp & 1) as u64))) >> 1;
-                        let res_y = w * t_tmp - h_tmp * modulus;
-
-                        op[j] = res_x;
-                        op[t + j] = res_y;
-                    }
-                } else {
-                    unsafe {
-                        for j in (0..t).step_by(4) {
-                            // Use AVX2 here
-                            let p_x = &mut op[j] as *mut u64;
-                            let p_y = &mut op[j + t] as *mut u64;
-                            let x = _mm256_load_si256(p_x as *const __m256i);
-                            let y = _mm256_load_si256(p_y as *const __m256i);
-
-                            let modulus_vec = _mm256_set1_epi64x(modulus as i64);
-                            let two_times_modulus_vec =
-                                _mm256_set1_epi64x(two_times_modulus as i64);
-                            let mut t_tmp = _mm256_set1_epi64x(two_times_modulus as i64);
-                            t_tmp = _mm256_sub_epi64(t_tmp, y);
-                            t_tmp = _mm256_add_epi64(t_tmp, x);
-                            let gt_mask = _mm256_cmpgt_epi64(_mm256_slli_epi64(x, 1), t_tmp);
-                            let to_subtract = _mm256_and_si256(gt_mask, two_times_modulus_vec);
-                            let mut curr_x = _mm256_add_epi64(x, y);
-                            curr_x = _mm256_sub_epi64(curr_x, to_subtract);
-
-                            let w_prime_vec = _mm256_set1_epi64x(w_prime as i64);
-                            let mut h_tmp = _mm256_mul_epu32(t_tmp, w_prime_vec);
-                            h_tmp = _mm256_srli_epi64(h_tmp, 32);
-
-                            let and_mask = _mm256_set_epi64x(1, 1, 1, 1);
-                            let eq_mask =
-                                _mm256_cmpeq_epi64(_mm256_and_si256(t_tmp, and_mask), and_mask);
-                            let to_add = _mm256_and_si256(eq_mask, modulus_vec);
-
-                            let new_x = _mm256_srli_epi64(_mm256_add_epi64(curr_x, to_add), 1);
-
-                            let w_vec = _mm256_set1_epi64x(w as i64);
-                            let w_times_t_tmp = _mm256_mul_epu32(t_tmp, w_vec);
-                            let h_tmp_times_modulus = _mm256_mul_epu32(h_tmp, modulus_vec);
-                            let new_y = _mm256_sub_epi64(w_times_t_tmp, h_tmp_times_modulus);
-
-                            _mm256_store_si256(p_x as *mut __m256i, new_x);
-                            _mm256_store_si256(p_y as *mut __m256i, new_y);
-                        }
-                    }
+                if t < 8 {
+                    inverse_butterflies(op, t, w, w_prime, modulus);
+                    continue;
                 }
-            }
-        }
 
-        for i in 0..n {
-            operand[i] -= ((operand[i] >= two_times_modulus) as u64) * two_times_modulus;
-            operand[i] -= ((operand[i] >= modulus) as u64) * modulus;
+                let w_prime_vec = _mm512_set1_epi64(w_prime as i64);
+                let w_vec = _mm512_set1_epi64(w as i64);
+                for j in (0..t).step_by(8) {
+                    let p_x = op.as_mut_ptr().add(j);
+                    let p_y = op.as_mut_ptr().add(j + t);
+                    let x = _mm512_loadu_epi64(p_x as *const i64);
+                    let y = _mm512_loadu_epi64(p_y as *const i64);
+
+                    let t_tmp = _mm512_add_epi64(_mm512_sub_epi64(two_times_modulus_vec, y), x);
+                    let ge_mask = _mm512_cmpge_epu64_mask(_mm512_slli_epi64(x, 1), t_tmp);
+                    let sum = _mm512_add_epi64(x, y);
+                    let curr_x = _mm512_mask_sub_epi64(sum, ge_mask, sum, two_times_modulus_vec);
+
+                    let h_tmp = _mm512_srli_epi64(_mm512_mul_epu32(t_tmp, w_prime_vec), 32);
+
+                    let odd_mask = _mm512_test_epi64_mask(t_tmp, one);
+                    let new_x = _mm512_srli_epi64(
+                        _mm512_mask_add_epi64(curr_x, odd_mask, curr_x, modulus_vec),
+                        1,
+                    );
+                    let new_y = _mm512_sub_epi64(
+                        _mm512_mul_epu32(t_tmp, w_vec),
+                        _mm512_mul_epu32(h_tmp, modulus_vec),
+                    );
+
+                    _mm512_storeu_epi64(p_x as *mut i64, new_x);
+                    _mm512_storeu_epi64(p_y as *mut i64, new_y);
+                }
+            }
         }
 
-        // for i in (0..n).step_by(4) {
-        //     unsafe {
-        //         let p_x = &mut operand[i] as *mut u64;
-
-        //         let cmp_val1 = _mm256_set1_epi64x(two_times_modulus as i64);
-        //         let mut x = _mm256_load_si256(p_x as *const __m256i);
-        //         let mut gt_mask = _mm256_cmpgt_epi64(x, cmp_val1);
-        //         let mut to_subtract = _mm256_and_si256(gt_mask, cmp_val1);
-        //         x = _mm256_sub_epi64(x, to_subtract);
-
-        //         let cmp_val2 = _mm256_set1_epi64x(modulus as i64);
-        //         gt_mask = _mm256_cmpgt_epi64(x, cmp_val2);
-        //         to_subtract = _mm256_and_si256(gt_mask, cmp_val2);
-        //         x = _mm256_sub_epi64(x, to_subtract);
-        //         _mm256_store_si256(p_x as *mut __m256i, x);
-        //     }
-        // }
+        final_reduce(operand, modulus);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{aligned_memory::AlignedMemory64, util::*};
+    use crate::{
+        aligned_memory::AlignedMemory64,
+        kernels::{Kernels, SimdLevel},
+        util::*,
+    };
     use rand::Rng;
 
     fn get_params() -> Params {
@@ -442,6 +575,88 @@ mod test {
         }
     }
 
+    fn ring_params(poly_len: usize, moduli: &[u64]) -> Params {
+        let base = get_params();
+        Params::init(
+            poly_len,
+            moduli,
+            base.noise_width,
+            base.n,
+            base.pt_modulus,
+            base.q2_bits,
+            base.t_conv,
+            base.t_exp_left,
+            base.t_exp_right,
+            base.t_gsw,
+            base.expand_queries,
+            base.db_dim_1,
+            base.db_dim_2,
+            base.instances,
+            base.db_item_size,
+            base.version,
+        )
+    }
+
+    #[test]
+    fn ntt_kernels_agree_with_scalar() {
+        let rings = [
+            ring_params(1024, &[268369921, 249561089]),
+            get_params(),
+            ring_params(4096, &[268369921, 249561089]),
+            ring_params(1024, &[1191937, 1196033, 1253377]),
+        ];
+        let mut rng = get_seeded_rng();
+        for params in rings {
+            let len = params.crt_count * params.poly_len;
+            // Offset by one word so the SIMD kernels see an unaligned slice.
+            let mut input = vec![0u64; len + 1];
+            for i in 0..len {
+                let modulus = params.moduli[i / params.poly_len];
+                input[i + 1] = rng.gen::<u64>() % modulus;
+            }
+            // Values at the boundaries of the conditional subtractions.
+            input[1] = params.moduli[0] - 1;
+            input[2] = 0;
+
+            let mut fwd_ref = input.clone();
+            ntt_forward_scalar(&params, &mut fwd_ref[1..]);
+            let mut inv_ref = input.clone();
+            ntt_inverse_scalar(&params, &mut inv_ref[1..]);
+
+            for level in SimdLevel::available() {
+                let kernels = Kernels::for_level(level);
+                let mut fwd = input.clone();
+                (kernels.ntt_forward)(&params, &mut fwd[1..]);
+                assert_eq!(
+                    fwd,
+                    fwd_ref,
+                    "{} forward, poly_len {}",
+                    level.name(),
+                    params.poly_len
+                );
+
+                let mut inv = input.clone();
+                (kernels.ntt_inverse)(&params, &mut inv[1..]);
+                assert_eq!(
+                    inv,
+                    inv_ref,
+                    "{} inverse, poly_len {}",
+                    level.name(),
+                    params.poly_len
+                );
+
+                (kernels.ntt_inverse)(&params, &mut fwd[1..]);
+                assert_eq!(
+                    fwd,
+                    input,
+                    "{} round trip, poly_len {}",
+                    level.name(),
+                    params.poly_len
+                );
+            }
+        }
+    }
+
     #[test]
     fn calc_index_correct() {
         assert_eq!(calc_index(&[2, 3, 4], &[10, 10, 100]), 2304);
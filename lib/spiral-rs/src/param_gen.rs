@@ -0,0 +1,335 @@
+//! Searches the parameter space for schemes that fit a database and meet a
+//! target error probability, using the noise estimator.
+
+use serde_json::{json, Value};
+
+use crate::{
+    arith::*,
+    noise_estimate::{extract_paramset, get_p_err, NoiseEstimator},
+    params::{Params, DEFAULT_Q1_FACTOR, MAX_Q1_BITS, MAX_Q2_BITS, MIN_Q2_BITS, Q2_VALUES},
+    util::params_from_json_obj,
+};
+
+/// The plaintext modulus of every generated scheme.
+pub const GEN_PT_MODULUS: u64 = 256;
+pub const GEN_MAX_N: usize = 4;
+pub const GEN_MAX_NU_1: usize = 11;
+/// The shortest GSW and expansion gadgets to try. The estimator leaves a
+/// factor of the polynomial length out of the expansion noise, so it is only
+/// accurate for small gadget bases; schemes with shorter gadgets fail far more
+/// often than estimated.
+pub const GEN_MIN_T: usize = 5;
+/// How many bits wider than the default q1 to try. A wider q1 leaves more
+/// room for the q2 rounding error, so it can pay for itself with a narrower
+/// q2 when responses have few rest rows.
+pub const GEN_Q1_EXTRA_BITS: u64 = 4;
+
+/// Gadget lengths to try; each gives a distinct base for the ~56-bit modulus.
+pub const GEN_GADGET_LENGTHS: [usize; 13] = [2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 19, 28, 56];
+
+/// A generated scheme, with its costs and estimated error probability.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamCandidate {
+    pub n: usize,
+    pub nu_1: usize,
+    pub nu_2: usize,
+    pub p: u64,
+    pub q1: u64,
+    pub q2_bits: u64,
+    pub t_gsw: usize,
+    pub t_conv: usize,
+    pub t_exp_left: usize,
+    pub t_exp_right: usize,
+    pub instances: usize,
+    pub db_item_size: usize,
+
+    pub setup_bytes: usize,
+    pub query_bytes: usize,
+    pub response_bytes: usize,
+    pub server_work: usize,
+    pub log2_err_prob: f64,
+}
+
+impl ParamCandidate {
+    /// The scheme, in the JSON format `params_from_json` reads.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "n": self.n,
+            "nu_1": self.nu_1,
+            "nu_2": self.nu_2,
+            "p": self.p,
+            "q1": self.q1,
+            "q2_bits": self.q2_bits,
+            "t_gsw": self.t_gsw,
+            "t_conv": self.t_conv,
+            "t_exp_left": self.t_exp_left,
+            "t_exp_right": self.t_exp_right,
+            "instances": self.instances,
+            "db_item_size": self.db_item_size,
+        })
+    }
+
+    pub fn params(&self) -> Params {
+        params_from_json_obj(&self.to_json())
+    }
+
+    fn costs(&self) -> [usize; 4] {
+        [
+            self.setup_bytes,
+            self.query_bytes,
+            self.response_bytes,
+            self.server_work,
+        ]
+    }
+
+    fn dominates(&self, other: &ParamCandidate) -> bool {
+        let (a, b) = (self.costs(), other.costs());
+        a.iter().zip(b.iter()).all(|(x, y)| x <= y) && a != b
+    }
+}
+
+/// A rough count of the coefficient multiplications the server does to
+/// answer one query.
+pub fn estimate_server_work(params: &Params) -> usize {
+    let n = params.n;
+    let mut expansion = 0;
+    if params.expand_queries {
+        expansion = 2
+            * (params.num_expanded() * params.t_exp_left
+                + params.t_gsw * params.db_dim_2 * params.t_exp_right);
+    }
+    let first_dim = params.num_items() * n * n * 2;
+    let folding = ((1 << params.db_dim_2) - 1) * 2 * (2 * params.t_gsw) * n;
+    let packing = n * (n + 1) * params.t_conv;
+    let per_instance = first_dim + folding + packing;
+
+    params.crt_count * params.poly_len * (expansion + params.instances * per_instance)
+}
+
+/// Whether the server can expand a query for `params`: expansion interleaves
+/// the GSW inputs with the first dimension's, and can't take more rounds than
+/// a polynomial has coefficients for.
+fn is_expandable(params: &Params) -> bool {
+    params.t_gsw * params.db_dim_2 <= params.num_expanded() && params.g() <= params.poly_len_log2
+}
+
+/// The q1 values worth trying for plaintext modulus `p`: for each width, the
+/// largest multiple of `p` that fits in it.
+fn q1_candidates(p: u64) -> Vec<u64> {
+    let max_bits = log2_ceil(DEFAULT_Q1_FACTOR * p) + GEN_Q1_EXTRA_BITS;
+    let mut q1s: Vec<u64> = (log2_ceil(2 * p)..=u64::min(max_bits, MAX_Q1_BITS - 1))
+        .map(|bits| ((1 << bits) / p) * p)
+        .filter(|&q1| q1 > 2 * p)
+        .collect();
+    q1s.dedup();
+    q1s
+}
+
+fn log2_err_prob(params: &Params, s_e: f64) -> f64 {
+    get_p_err(
+        &extract_paramset(params),
+        s_e,
+        Q2_VALUES[params.q2_bits as usize],
+    )
+}
+
+/// The smallest `q2_bits` for which `params`, with noise `s_e`, meets
+/// `max_log2_err`, if any.
+fn min_q2_bits(params: &mut Params, s_e: f64, max_log2_err: f64) -> Option<(u64, f64)> {
+    let mut lo = MIN_Q2_BITS;
+    let mut hi = MAX_Q2_BITS;
+    params.q2_bits = hi;
+    let mut best = (hi, log2_err_prob(params, s_e));
+    if best.1 > max_log2_err {
+        return None;
+    }
+    // the error only shrinks as q2 grows
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        params.q2_bits = mid;
+        let err = log2_err_prob(params, s_e);
+        if err <= max_log2_err {
+            best = (mid, err);
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Some(best)
+}
+
+/// The `q1` and `q2_bits` that give `params` the smallest response while
+/// meeting `max_log2_err`, and the error they give, if any do. Of equally
+/// small responses, the one with the smallest q1 wins.
+fn min_response_moduli(params: &mut Params, max_log2_err: f64) -> Option<(u64, u64, f64)> {
+    let s_e = params.estimate_noise();
+    let mut best: Option<(usize, u64, u64, f64)> = None;
+    for q1 in q1_candidates(params.pt_modulus) {
+        params.q1 = q1;
+        let Some((q2_bits, err)) = min_q2_bits(params, s_e, max_log2_err) else {
+            continue;
+        };
+        params.q2_bits = q2_bits;
+        let response_bytes = params.response_bytes();
+        if best.map_or(true, |(b, ..)| response_bytes < b) {
+            best = Some((response_bytes, q1, q2_bits, err));
+        }
+    }
+    best.map(|(_, q1, q2_bits, err)| (q1, q2_bits, err))
+}
+
+/// Finds the schemes that hold `num_items` items of `item_size` bytes with a
+/// log2 error probability of at most `max_log2_err`, and returns those that
+/// are Pareto-optimal for setup, query and response size and server work,
+/// ordered by setup size.
+pub fn generate_params(
+    num_items: usize,
+    item_size: usize,
+    max_log2_err: f64,
+) -> Vec<ParamCandidate> {
+    let total_dims = usize::max(log2_ceil_usize(usize::max(num_items, 1)), 2);
+    let item_size = usize::max(item_size, 1);
+    let logp = log2(GEN_PT_MODULUS) as usize;
+
+    // one scratch instance, so that the NTT tables are only built once
+    let mut params = params_from_json_obj(&json!({
+        "n": 1, "nu_1": 1, "nu_2": 1, "p": GEN_PT_MODULUS, "q2_bits": MIN_Q2_BITS,
+        "t_gsw": 2, "t_conv": 2, "t_exp_left": 2, "t_exp_right": 2,
+    }));
+
+    let long_ts: Vec<usize> = GEN_GADGET_LENGTHS
+        .into_iter()
+        .filter(|&t| t >= GEN_MIN_T)
+        .collect();
+
+    let mut candidates = Vec::new();
+    for n in 1..=GEN_MAX_N {
+        let instance_bytes = n * n * params.poly_len * logp / 8;
+        let instances = div_ceil_usize(item_size, instance_bytes);
+        for nu_1 in 1..usize::min(total_dims, GEN_MAX_NU_1 + 1) {
+            let nu_2 = total_dims - nu_1;
+            for &t_gsw in &long_ts {
+                for &t_conv in &GEN_GADGET_LENGTHS {
+                    for &t_exp_left in &long_ts {
+                        for &t_exp_right in &long_ts {
+                            params.n = n;
+                            params.db_dim_1 = nu_1;
+                            params.db_dim_2 = nu_2;
+                            params.t_gsw = t_gsw;
+                            params.t_conv = t_conv;
+                            params.t_exp_left = t_exp_left;
+                            params.t_exp_right = t_exp_right;
+                            params.instances = instances;
+                            params.db_item_size = item_size;
+                            if !is_expandable(&params) {
+                                continue;
+                            }
+
+                            let Some((q1, q2_bits, log2_err_prob)) =
+                                min_response_moduli(&mut params, max_log2_err)
+                            else {
+                                continue;
+                            };
+                            params.q1 = q1;
+                            params.q2_bits = q2_bits;
+                            candidates.push(ParamCandidate {
+                                n,
+                                nu_1,
+                                nu_2,
+                                p: GEN_PT_MODULUS,
+                                q1,
+                                q2_bits,
+                                t_gsw,
+                                t_conv,
+                                t_exp_left,
+                                t_exp_right,
+                                instances,
+                                db_item_size: item_size,
+                                setup_bytes: params.setup_bytes(),
+                                query_bytes: params.query_bytes(),
+                                response_bytes: params.response_bytes(),
+                                server_work: estimate_server_work(&params),
+                                log2_err_prob,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pareto_front(candidates)
+}
+
+/// Keeps the candidates no other candidate beats on every cost. Of those with
+/// equal costs, keeps the one least likely to fail.
+fn pareto_front(mut candidates: Vec<ParamCandidate>) -> Vec<ParamCandidate> {
+    candidates.sort_by(|a, b| {
+        a.costs()
+            .cmp(&b.costs())
+            .then(a.log2_err_prob.total_cmp(&b.log2_err_prob))
+    });
+    candidates.dedup_by(|b, a| a.costs() == b.costs());
+
+    // a candidate can only be dominated by one that sorts before it
+    let mut front: Vec<ParamCandidate> = Vec::new();
+    for c in candidates {
+        if !front.iter().any(|f| f.dominates(&c)) {
+            front.push(c);
+        }
+    }
+    front
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_params_are_pareto_optimal() {
+        let front = generate_params(1 << 14, 32768, -40.0);
+        assert!(!front.is_empty());
+        for (i, a) in front.iter().enumerate() {
+            assert!(a.log2_err_prob <= -40.0);
+            for b in &front[i + 1..] {
+                assert!(!a.dominates(b) && !b.dominates(a));
+            }
+        }
+
+        // the sizes the generator reports are the scheme's own
+        let c = &front[0];
+        let params = c.params();
+        assert_eq!(params.num_items(), 1 << 14);
+        assert!(params.item_size() >= 32768);
+        assert_eq!(params.setup_bytes(), c.setup_bytes);
+        assert_eq!(params.response_bytes(), c.response_bytes);
+        assert_eq!(estimate_server_work(&params), c.server_work);
+        assert!(params.estimate_log2_err_prob() <= -40.0);
+    }
+
+    #[test]
+    fn response_moduli_are_minimal() {
+        assert_eq!(q1_candidates(256), vec![1024, 2048, 4096, 8192, 16384]);
+        assert_eq!(
+            q1_candidates(300),
+            vec![900, 1800, 3900, 8100, 16200, 32700]
+        );
+
+        let c = &generate_params(1 << 14, 32768, -40.0)[0];
+        let mut params = c.params();
+        assert_eq!(params.q1, c.q1);
+        let s_e = params.estimate_noise();
+        let response_bytes = params.response_bytes();
+        for q1 in q1_candidates(c.p) {
+            params.q1 = q1;
+            if let Some((q2_bits, _)) = min_q2_bits(&mut params, s_e, -40.0) {
+                params.q2_bits = q2_bits;
+                assert!(params.response_bytes() >= response_bytes);
+            }
+        }
+        // q2 can't be any narrower at the chosen q1
+        params.q1 = c.q1;
+        params.q2_bits = c.q2_bits - 1;
+        assert!(c.q2_bits == MIN_Q2_BITS || params.estimate_log2_err_prob() > -40.0);
+    }
+}
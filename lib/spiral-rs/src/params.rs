@@ -1,10 +1,29 @@
 use std::mem::size_of;
 
-use crate::{arith::*, client::SEED_LENGTH, ntt::*, number_theory::*, poly::*};
+use sha2::{Digest, Sha256};
+
+use crate::{arith::*, client::SEED_LENGTH, error::Error, ntt::*, number_theory::*, poly::*};
 
 pub const MAX_MODULI: usize = 4;
 
+/// The supported ring dimensions.
+pub const POLY_LENS: [usize; 3] = [1024, 2048, 4096];
+pub const DEFAULT_POLY_LEN: usize = 2048;
+/// The CRT moduli used unless a scheme names its own. Both are 1 mod 2N for
+/// every supported N.
+pub const DEFAULT_MODULI: [u64; 2] = [268369921, 249561089];
+/// The NTT keeps coefficients below 4q in 32-bit lanes, so every modulus it
+/// runs over, including q2, must be below 2^30.
+pub const MAX_MODULUS_BITS: u64 = 30;
+
+/// Unless a scheme sets `q1`, the rest rows of a response are switched to
+/// `DEFAULT_Q1_FACTOR * p`.
+pub const DEFAULT_Q1_FACTOR: u64 = 4;
+/// Decoding multiplies values mod q2 by q1 in 64-bit arithmetic.
+pub const MAX_Q1_BITS: u64 = 32;
+
 pub static MIN_Q2_BITS: u64 = 14;
+pub static MAX_Q2_BITS: u64 = MAX_MODULUS_BITS;
 pub static Q2_VALUES: [u64; 37] = [
     0,
     0,
@@ -45,6 +64,70 @@ pub static Q2_VALUES: [u64; 37] = [
     68718428161,
 ];
 
+/// Checks that a ring can be set up over `moduli` and decoded over the
+/// `q2_bits` modulus: `poly_len` must be supported, and every modulus must be
+/// a prime below 2^30 with a primitive `2 * poly_len`-th root of unity. The
+/// server's first dimension and query packing take exactly two moduli.
+pub fn check_ring(poly_len: usize, moduli: &[u64], q2_bits: u64) -> Result<(), Error> {
+    if !POLY_LENS.contains(&poly_len) {
+        return Err(Error::InvalidParams(format!(
+            "poly_len must be one of {:?}",
+            POLY_LENS
+        )));
+    }
+    if moduli.len() != 2 || moduli[0] == moduli[1] {
+        return Err(Error::InvalidParams(
+            "moduli must be two distinct primes".to_owned(),
+        ));
+    }
+    if !(MIN_Q2_BITS..=MAX_Q2_BITS).contains(&q2_bits) {
+        return Err(Error::InvalidParams(format!(
+            "q2_bits must be between {} and {}",
+            MIN_Q2_BITS, MAX_Q2_BITS
+        )));
+    }
+
+    let degree = 2 * poly_len as u64;
+    for &q in moduli.iter().chain([Q2_VALUES[q2_bits as usize]].iter()) {
+        if q >> MAX_MODULUS_BITS != 0 {
+            return Err(Error::InvalidParams(format!(
+                "modulus {} is not below 2^{}",
+                q, MAX_MODULUS_BITS
+            )));
+        }
+        if !is_prime(q) {
+            return Err(Error::InvalidParams(format!("modulus {} is not prime", q)));
+        }
+        if q % degree != 1 || get_primitive_root(degree, q).is_none() {
+            return Err(Error::InvalidParams(format!(
+                "modulus {} has no primitive {}-th root of unity",
+                q, degree
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the modulus the rest rows of a response are switched to. `q1` must
+/// be a multiple of `pt_modulus`, so that decoding can divide it out, and more
+/// than twice it, since below that rounding to q1 alone can flip a plaintext
+/// digit. It must also be below 2^`MAX_Q1_BITS`.
+pub fn check_q1(pt_modulus: u64, q1: u64) -> Result<(), Error> {
+    if q1 % pt_modulus != 0 || q1 <= 2 * pt_modulus {
+        return Err(Error::InvalidParams(format!(
+            "q1 must be a multiple of p greater than 2 * p = {}",
+            2 * pt_modulus
+        )));
+    }
+    if q1 >> MAX_Q1_BITS != 0 {
+        return Err(Error::InvalidParams(format!(
+            "q1 {} is not below 2^{}",
+            q1, MAX_Q1_BITS
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Params {
     pub poly_len: usize,
@@ -66,6 +149,9 @@ pub struct Params {
 
     pub n: usize,
     pub pt_modulus: u64,
+    /// The modulus the rest rows of a response are switched to. `init` sets
+    /// it to `DEFAULT_Q1_FACTOR * pt_modulus`.
+    pub q1: u64,
     pub q2_bits: u64,
     pub t_conv: usize,
     pub t_exp_left: usize,
@@ -143,6 +229,39 @@ impl Params {
         }
     }
 
+    /// A SHA-256 digest of every field that determines the scheme, so that
+    /// state saved under one parameter set is not used with another. Derived
+    /// tables are left out, since they follow from the rest.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"spiral-params-v1");
+        for modulus in &self.moduli[..self.crt_count] {
+            hasher.update(modulus.to_le_bytes());
+        }
+        for field in [
+            self.poly_len as u64,
+            self.crt_count as u64,
+            self.noise_width.to_bits(),
+            self.n as u64,
+            self.pt_modulus,
+            self.q1,
+            self.q2_bits,
+            self.t_conv as u64,
+            self.t_exp_left as u64,
+            self.t_exp_right as u64,
+            self.t_gsw as u64,
+            self.expand_queries as u64,
+            self.db_dim_1 as u64,
+            self.db_dim_2 as u64,
+            self.instances as u64,
+            self.db_item_size as u64,
+            self.version as u64,
+        ] {
+            hasher.update(field.to_le_bytes());
+        }
+        hasher.finalize().into()
+    }
+
     pub fn setup_bytes(&self) -> usize {
         let mut sz_polys = 0;
 
@@ -185,6 +304,15 @@ impl Params {
         self.num_expanded() * self.poly_len * size_of::<u64>()
     }
 
+    pub fn response_bytes(&self) -> usize {
+        let q1_bits = log2_ceil(self.q1) as usize;
+        let q2_bits = self.q2_bits as usize;
+        let num_bits = self.instances
+            * ((q2_bits * self.n * self.poly_len) + (q1_bits * self.n * self.n * self.poly_len));
+        let round_to = 64;
+        div_ceil_usize(num_bits, round_to) * round_to / 8
+    }
+
     pub fn bytes_per_chunk(&self) -> usize {
         let trials = self.n * self.n;
         let chunks = self.instances * trials;
@@ -281,6 +409,7 @@ impl Params {
             noise_width,
             n,
             pt_modulus,
+            q1: DEFAULT_Q1_FACTOR * pt_modulus,
             q2_bits,
             t_conv,
             t_exp_left,
@@ -1,4 +1,4 @@
-#[cfg(target_feature = "avx2")]
+#[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
 use rand::distributions::Standard;
@@ -6,8 +6,11 @@ use rand::Rng;
 use rand_chacha::ChaCha20Rng;
 use std::cell::RefCell;
 use std::ops::{Add, Mul, Neg};
+use zeroize::Zeroize;
 
-use crate::{aligned_memory::*, arith::*, discrete_gaussian::*, ntt::*, params::*, util::*};
+use crate::{
+    aligned_memory::*, arith::*, discrete_gaussian::*, kernels::kernels, ntt::*, params::*, util::*,
+};
 
 const SCRATCH_SPACE: usize = 8192;
 thread_local!(static SCRATCH: RefCell<AlignedMemory64> = RefCell::new(AlignedMemory64::new(SCRATCH_SPACE)));
@@ -156,6 +159,12 @@ impl<'a> Clone for PolyMatrixRaw<'a> {
     }
 }
 
+impl Zeroize for PolyMatrixRaw<'_> {
+    fn zeroize(&mut self) {
+        self.data.as_mut_slice().zeroize();
+    }
+}
+
 impl<'a> PolyMatrixRaw<'a> {
     pub fn identity(params: &'a Params, rows: usize, cols: usize) -> PolyMatrixRaw<'a> {
         let num_coeffs = rows * cols * params.poly_len;
@@ -331,6 +340,12 @@ impl<'a> Clone for PolyMatrixNTT<'a> {
     }
 }
 
+impl Zeroize for PolyMatrixNTT<'_> {
+    fn zeroize(&mut self) {
+        self.data.as_mut_slice().zeroize();
+    }
+}
+
 impl<'a> PolyMatrixNTT<'a> {
     pub fn raw(&self) -> PolyMatrixRaw<'a> {
         from_ntt_alloc(&self)
@@ -392,36 +407,77 @@ pub fn invert_poly(params: &Params, res: &mut [u64], a: &[u64]) {
 
 pub fn automorph_poly(params: &Params, res: &mut [u64], a: &[u64], t: usize) {
     let poly_len = params.poly_len;
-    for i in 0..poly_len {
+    for (i, &a_i) in a.iter().enumerate().take(poly_len) {
         let num = (i * t) / poly_len;
         let rem = (i * t) % poly_len;
 
         if num % 2 == 0 {
-            res[rem] = a[i];
+            res[rem] = a_i;
         } else {
-            res[rem] = params.modulus - a[i];
+            res[rem] = params.modulus - a_i;
         }
     }
 }
 
-#[cfg(target_feature = "avx2")]
-pub fn multiply_add_poly_avx(params: &Params, res: &mut [u64], a: &[u64], b: &[u64]) {
-    for c in 0..params.crt_count {
-        for i in (0..params.poly_len).step_by(4) {
-            unsafe {
-                let p_x = &a[c * params.poly_len + i] as *const u64;
-                let p_y = &b[c * params.poly_len + i] as *const u64;
-                let p_z = &mut res[c * params.poly_len + i] as *mut u64;
-                let x = _mm256_load_si256(p_x as *const __m256i);
-                let y = _mm256_load_si256(p_y as *const __m256i);
-                let z = _mm256_load_si256(p_z as *const __m256i);
-
-                let product = _mm256_mul_epu32(x, y);
-                let out = _mm256_add_epi64(z, product);
-
-                _mm256_store_si256(p_z as *mut __m256i, out);
-            }
-        }
+/// Accumulates `a * b` into `res` without reducing; see `Kernels`.
+pub fn multiply_add_poly_lazy_scalar(params: &Params, res: &mut [u64], a: &[u64], b: &[u64]) {
+    let len = params.crt_count * params.poly_len;
+    for ((z, x), y) in res[..len].iter_mut().zip(&a[..len]).zip(&b[..len]) {
+        *z += (*x as u32 as u64) * (*y as u32 as u64);
+    }
+}
+
+/// # Safety
+/// The CPU must support AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub(crate) unsafe fn multiply_add_poly_lazy_avx2(
+    params: &Params,
+    res: &mut [u64],
+    a: &[u64],
+    b: &[u64],
+) {
+    let len = params.crt_count * params.poly_len;
+    assert!(res.len() >= len && a.len() >= len && b.len() >= len);
+    for i in (0..len).step_by(4) {
+        let p_x = a.as_ptr().add(i) as *const __m256i;
+        let p_y = b.as_ptr().add(i) as *const __m256i;
+        let p_z = res.as_mut_ptr().add(i) as *mut __m256i;
+        let x = _mm256_loadu_si256(p_x);
+        let y = _mm256_loadu_si256(p_y);
+        let z = _mm256_loadu_si256(p_z);
+
+        let product = _mm256_mul_epu32(x, y);
+        let out = _mm256_add_epi64(z, product);
+
+        _mm256_storeu_si256(p_z, out);
+    }
+}
+
+/// # Safety
+/// The CPU must support AVX-512F.
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+#[target_feature(enable = "avx512f")]
+#[allow(clippy::incompatible_msrv)] // the `avx512` feature needs Rust 1.89
+pub(crate) unsafe fn multiply_add_poly_lazy_avx512(
+    params: &Params,
+    res: &mut [u64],
+    a: &[u64],
+    b: &[u64],
+) {
+    let len = params.crt_count * params.poly_len;
+    assert!(res.len() >= len && a.len() >= len && b.len() >= len);
+    for i in (0..len).step_by(8) {
+        let p_x = a.as_ptr().add(i) as *const i64;
+        let p_y = b.as_ptr().add(i) as *const i64;
+        let p_z = res.as_mut_ptr().add(i) as *mut i64;
+        let x = _mm512_loadu_epi64(p_x);
+        let y = _mm512_loadu_epi64(p_y);
+        let z = _mm512_loadu_epi64(p_z);
+
+        let out = _mm512_add_epi64(z, _mm512_mul_epu32(x, y));
+
+        _mm512_storeu_epi64(p_z, out);
     }
 }
 
@@ -434,36 +490,30 @@ pub fn modular_reduce(params: &Params, res: &mut [u64]) {
     }
 }
 
-#[cfg(not(target_feature = "avx2"))]
-pub fn multiply(res: &mut PolyMatrixNTT, a: &PolyMatrixNTT, b: &PolyMatrixNTT) {
-    assert!(res.rows == a.rows);
-    assert!(res.cols == b.cols);
-    assert!(a.cols == b.rows);
-
-    let params = res.params;
-    for i in 0..a.rows {
-        for j in 0..b.cols {
-            for z in 0..params.poly_len * params.crt_count {
-                res.get_poly_mut(i, j)[z] = 0;
-            }
-            for k in 0..a.cols {
-                let params = res.params;
-                let res_poly = res.get_poly_mut(i, j);
-                let pol1 = a.get_poly(i, k);
-                let pol2 = b.get_poly(k, j);
-                multiply_add_poly(params, res_poly, pol1, pol2);
-            }
-        }
+/// How many products of reduced NTT coefficients can be summed onto a
+/// reduced value before a u64 could overflow, or 0 if even one product can
+/// overflow the lazy kernels (some modulus is at least 2^32).
+pub fn max_lazy_products(params: &Params) -> usize {
+    let max_modulus = params.moduli[..params.crt_count]
+        .iter()
+        .copied()
+        .max()
+        .unwrap();
+    if max_modulus > u32::MAX as u64 {
+        return 0;
     }
+    let max_product = (max_modulus - 1) * (max_modulus - 1);
+    ((u64::MAX - max_modulus) / max_product.max(1)) as usize
 }
 
-#[cfg(target_feature = "avx2")]
 pub fn multiply(res: &mut PolyMatrixNTT, a: &PolyMatrixNTT, b: &PolyMatrixNTT) {
     assert_eq!(res.rows, a.rows);
     assert_eq!(res.cols, b.cols);
     assert_eq!(a.cols, b.rows);
 
     let params = res.params;
+    let max_summed = max_lazy_products(params);
+    let multiply_add_poly_lazy = kernels().multiply_add_poly_lazy;
     for i in 0..a.rows {
         for j in 0..b.cols {
             for z in 0..params.poly_len * params.crt_count {
@@ -473,7 +523,14 @@ pub fn multiply(res: &mut PolyMatrixNTT, a: &PolyMatrixNTT, b: &PolyMatrixNTT) {
             for k in 0..a.cols {
                 let pol1 = a.get_poly(i, k);
                 let pol2 = b.get_poly(k, j);
-                multiply_add_poly_avx(params, res_poly, pol1, pol2);
+                if max_summed == 0 {
+                    multiply_add_poly(params, res_poly, pol1, pol2);
+                    continue;
+                }
+                multiply_add_poly_lazy(params, res_poly, pol1, pol2);
+                if (k + 1) % max_summed == 0 {
+                    modular_reduce(params, res_poly);
+                }
             }
             modular_reduce(params, res_poly);
         }
@@ -654,8 +711,8 @@ pub fn from_ntt(a: &mut PolyMatrixRaw, b: &PolyMatrixNTT) {
                 let pol_dst = a.get_poly_mut(r, c);
                 scratch[0..pol_src.len()].copy_from_slice(pol_src);
                 ntt_inverse(params, scratch);
-                for z in 0..params.poly_len {
-                    pol_dst[z] = params.crt_compose(scratch, z);
+                for (z, dst) in pol_dst.iter_mut().enumerate().take(params.poly_len) {
+                    *dst = params.crt_compose(scratch, z);
                 }
             }
         }
@@ -701,6 +758,7 @@ impl<'a, 'b> Add for &'b PolyMatrixNTT<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::kernels::{Kernels, SimdLevel};
 
     fn get_params() -> Params {
         get_test_params()
@@ -742,6 +800,80 @@ mod test {
         assert_eq!(m3.get_poly(0, 0)[2], 700);
     }
 
+    #[test]
+    fn multiply_kernels_agree_with_scalar() {
+        let params = get_params();
+        let len = params.crt_count * params.poly_len;
+        let mut rng = get_seeded_rng();
+        let a = PolyMatrixRaw::random_rng(&params, 1, 1, &mut rng);
+        let b = PolyMatrixRaw::random_rng(&params, 1, 1, &mut rng);
+        let a_ntt = to_ntt_alloc(&a);
+        let b_ntt = to_ntt_alloc(&b);
+
+        let mut expected = vec![0u64; len];
+        for _ in 0..3 {
+            multiply_add_poly(&params, &mut expected, a_ntt.as_slice(), b_ntt.as_slice());
+        }
+
+        for level in SimdLevel::available() {
+            let kernels = Kernels::for_level(level);
+            // Offset by one word so the SIMD kernels see an unaligned slice.
+            let mut acc = vec![0u64; len + 1];
+            for _ in 0..3 {
+                (kernels.multiply_add_poly_lazy)(
+                    &params,
+                    &mut acc[1..],
+                    a_ntt.as_slice(),
+                    b_ntt.as_slice(),
+                );
+            }
+            modular_reduce(&params, &mut acc[1..]);
+            assert_eq!(&acc[1..], expected.as_slice(), "{}", level.name());
+        }
+    }
+
+    #[test]
+    fn multiply_reduces_long_sums() {
+        // With 30-bit moduli only 15 products fit in a u64 before reducing.
+        let moduli = [1073479681u64, 1073184769];
+        let base = get_params();
+        let params = Params::init(
+            2048,
+            &moduli,
+            base.noise_width,
+            base.n,
+            base.pt_modulus,
+            base.q2_bits,
+            base.t_conv,
+            base.t_exp_left,
+            base.t_exp_right,
+            base.t_gsw,
+            base.expand_queries,
+            base.db_dim_1,
+            base.db_dim_2,
+            base.instances,
+            base.db_item_size,
+            base.version,
+        );
+        assert_eq!(max_lazy_products(&params), 16);
+
+        let cols = 40;
+        let mut a = PolyMatrixNTT::zero(&params, 1, cols);
+        let mut b = PolyMatrixNTT::zero(&params, cols, 1);
+        for k in 0..cols {
+            for (c, modulus) in moduli.iter().enumerate() {
+                for i in 0..params.poly_len {
+                    a.get_poly_mut(0, k)[c * params.poly_len + i] = modulus - 1;
+                    b.get_poly_mut(k, 0)[c * params.poly_len + i] = modulus - 1;
+                }
+            }
+        }
+        let res = &a * &b;
+        for (c, _) in moduli.iter().enumerate() {
+            assert_eq!(res.get_poly(0, 0)[c * params.poly_len], cols as u64);
+        }
+    }
+
     #[test]
     fn to_vec_correctness() {
         let params = get_params();
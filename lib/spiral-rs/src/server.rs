@@ -468,18 +468,12 @@ pub fn pack<'a>(
 }
 
 pub fn encode(params: &Params, v_packed_ct: &Vec<PolyMatrixRaw>) -> Vec<u8> {
-    let q1 = 4 * params.pt_modulus;
+    let q1 = params.q1;
     let q1_bits = log2_ceil(q1) as usize;
     let q2 = Q2_VALUES[params.q2_bits as usize];
     let q2_bits = params.q2_bits as usize;
 
-    let num_bits = params.instances
-        * ((q2_bits * params.n * params.poly_len)
-            + (q1_bits * params.n * params.n * params.poly_len));
-    let round_to = 64;
-    let num_bytes_rounded_up = ((num_bits + round_to - 1) / round_to) * round_to / 8;
-
-    let mut result = vec![0u8; num_bytes_rounded_up];
+    let mut result = vec![0u8; params.response_bytes()];
     let mut bit_offs = 0;
     for instance in 0..params.instances {
         let packed_ct = &v_packed_ct[instance];
@@ -1047,6 +1041,70 @@ mod test {
         full_protocol_is_correct_for_params(&get_params());
     }
 
+    #[test]
+    fn batched_queries_and_responses_are_correct() {
+        let params = get_params();
+        let num_items = 1 << (params.db_dim_1 + params.db_dim_2);
+        let targets = [7 % num_items, 300 % num_items];
+
+        let mut client = Client::init(&params);
+        let pp_serialized = client.generate_keys().serialize();
+        let pp = PublicParameters::deserialize(&params, &pp_serialized);
+        let (corr_item, db) = generate_random_db_and_get_item(&params, targets[0]);
+
+        let responses: Vec<_> = client
+            .generate_queries(&targets)
+            .iter()
+            .map(|query| process_query(&params, &pp, query, db.as_slice()))
+            .collect();
+        let response_refs: Vec<&[u8]> = responses.iter().map(|r| r.as_slice()).collect();
+        let results = client.decode_responses(&response_refs);
+
+        let p_bits = log2_ceil(params.pt_modulus) as usize;
+        assert_eq!(
+            results[0],
+            corr_item.to_vec(p_bits, params.modp_words_per_chunk())
+        );
+        assert_eq!(results[1], client.decode_response(&responses[1]));
+        assert_ne!(results[0], results[1]);
+    }
+
+    #[test]
+    fn full_protocol_is_correct_without_expansion() {
+        full_protocol_is_correct_for_params(&get_no_expansion_testing_params());
+    }
+
+    #[test]
+    fn full_protocol_is_correct_for_other_rings() {
+        for ring in [
+            r#""poly_len": 1024, "moduli": [268460033, 268238849]"#,
+            r#""poly_len": 4096"#,
+        ] {
+            let cfg = format!(
+                r#"{{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 20, "t_gsw": 8,
+                "t_conv": 4, "t_exp_left": 8, "t_exp_right": 8, {}}}"#,
+                ring
+            );
+            full_protocol_is_correct_for_params(&params_from_json(&cfg));
+        }
+    }
+
+    #[test]
+    fn full_protocol_is_correct_for_other_q1() {
+        let mut response_bytes = Vec::new();
+        for q1 in [768, 2048, 1 << 14] {
+            let cfg = format!(
+                r#"{{"n": 2, "nu_1": 6, "nu_2": 2, "p": 256, "q2_bits": 20, "t_gsw": 8,
+                "t_conv": 4, "t_exp_left": 8, "t_exp_right": 8, "q1": {}}}"#,
+                q1
+            );
+            let params = params_from_json(&cfg);
+            response_bytes.push(params.response_bytes());
+            full_protocol_is_correct_for_params(&params);
+        }
+        assert!(response_bytes[0] < response_bytes[1] && response_bytes[1] < response_bytes[2]);
+    }
+
     #[test]
     #[ignore]
     fn larger_full_protocol_is_correct() {
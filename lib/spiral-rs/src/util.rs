@@ -1,8 +1,7 @@
-use crate::{arith::*, client::Seed, params::*, poly::*};
+use crate::{arith::*, client::Seed, error::Error, params::*, poly::*};
 use rand::{prelude::SmallRng, thread_rng, Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use serde_json::Value;
-use std::fs;
 
 pub const CFG_20_256: &'static str = r#"
         {'n': 2,
@@ -202,6 +201,7 @@ pub const fn get_empty_params() -> Params {
         noise_width: 0f64,
         n: 0,
         pt_modulus: 0,
+        q1: 0,
         q2_bits: 0,
         t_conv: 0,
         t_exp_left: 0,
@@ -221,30 +221,62 @@ pub fn params_from_json(cfg: &str) -> Params {
     params_from_json_obj(&v)
 }
 
+/// Panics if `v` is malformed; see `try_params_from_json_obj`.
 pub fn params_from_json_obj(v: &Value) -> Params {
-    let n = v["n"].as_u64().unwrap() as usize;
-    let db_dim_1 = v["nu_1"].as_u64().unwrap() as usize;
-    let db_dim_2 = v["nu_2"].as_u64().unwrap() as usize;
+    try_params_from_json_obj(v).unwrap()
+}
+
+/// Builds `Params` from a scheme. `poly_len` and `moduli` are optional, and
+/// default to `DEFAULT_POLY_LEN` and `DEFAULT_MODULI`; the ring they describe
+/// is checked with `check_ring`. `q1` is optional too, defaults to
+/// `DEFAULT_Q1_FACTOR * p`, and is checked with `check_q1`.
+pub fn try_params_from_json_obj(v: &Value) -> Result<Params, Error> {
+    let field = |name: &str| {
+        v[name]
+            .as_u64()
+            .ok_or_else(|| Error::InvalidParams(format!("missing or bad field {}", name)))
+    };
+    let n = field("n")? as usize;
+    let db_dim_1 = field("nu_1")? as usize;
+    let db_dim_2 = field("nu_2")? as usize;
     let instances = v["instances"].as_u64().unwrap_or(1) as usize;
-    let p = v["p"].as_u64().unwrap();
-    let q2_bits = u64::max(v["q2_bits"].as_u64().unwrap(), MIN_Q2_BITS);
-    let t_gsw = v["t_gsw"].as_u64().unwrap() as usize;
-    let t_conv = v["t_conv"].as_u64().unwrap() as usize;
-    let t_exp_left = v["t_exp_left"].as_u64().unwrap() as usize;
-    let t_exp_right = v["t_exp_right"].as_u64().unwrap() as usize;
+    let p = field("p")?;
+    let q2_bits = u64::max(field("q2_bits")?, MIN_Q2_BITS);
+    let t_gsw = field("t_gsw")? as usize;
+    let t_conv = field("t_conv")? as usize;
+    let t_exp_left = field("t_exp_left")? as usize;
+    let t_exp_right = field("t_exp_right")? as usize;
     let do_expansion = v.get("direct_upload").is_none();
 
+    let poly_len = match v.get("poly_len") {
+        Some(_) => field("poly_len")? as usize,
+        None => DEFAULT_POLY_LEN,
+    };
+    let moduli = match v.get("moduli") {
+        Some(m) => m
+            .as_array()
+            .and_then(|m| m.iter().map(|q| q.as_u64()).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| Error::InvalidParams("missing or bad field moduli".to_owned()))?,
+        None => DEFAULT_MODULI.to_vec(),
+    };
+    check_ring(poly_len, &moduli, q2_bits)?;
+    let q1 = match v.get("q1") {
+        Some(_) => field("q1")?,
+        None => DEFAULT_Q1_FACTOR * p,
+    };
+    check_q1(p, q1)?;
+
     let mut db_item_size = v["db_item_size"].as_u64().unwrap_or(0) as usize;
     if db_item_size == 0 {
         db_item_size = instances * n * n;
-        db_item_size = db_item_size * 2048 * log2_ceil(p) as usize / 8;
+        db_item_size = db_item_size * poly_len * log2_ceil(p) as usize / 8;
     }
 
     let version = v["version"].as_u64().unwrap_or(0) as usize;
 
-    Params::init(
-        2048,
-        &vec![268369921u64, 249561089u64],
+    let mut params = Params::init(
+        poly_len,
+        &moduli,
         6.4,
         n,
         p,
@@ -259,31 +291,58 @@ pub fn params_from_json_obj(v: &Value) -> Params {
         instances,
         db_item_size,
         version,
-    )
+    );
+    params.q1 = q1;
+    Ok(params)
 }
 
-static ALL_PARAMS_STORE_FNAME: &str = "../params_store.json";
+/// Parameter sets for powers-of-two item counts and sizes, generated with
+/// `spiral-params`. Each entry is `{"target_num": <log2 of the item count>,
+/// "item_size": <bytes>, "params": <scheme>}`.
+static PARAMS_STORE: &str = include_str!("params_store.json");
+
+/// A parameter set from the store, with the sizes clients should expect.
+pub struct StoredParams {
+    pub params: Params,
+    pub params_json: String,
+    pub num_items: usize,
+    pub item_size: usize,
+    pub setup_bytes: usize,
+    pub query_bytes: usize,
+    pub response_bytes: usize,
+}
 
-pub fn get_params_from_store(target_num_log2: usize, item_size: usize) -> Params {
-    let params_store_str = fs::read_to_string(ALL_PARAMS_STORE_FNAME).unwrap();
-    let v: Value = serde_json::from_str(&params_store_str).unwrap();
-    let nearest_target_num = target_num_log2;
-    let nearest_item_size = 1 << usize::max(log2_ceil_usize(item_size), 8);
-    println!(
-        "Starting with parameters for 2^{} x {} bytes...",
-        nearest_target_num, nearest_item_size
-    );
-    let target = v
+/// Finds the smallest stored parameter set that holds `num_items` items of
+/// `item_size` bytes.
+pub fn get_params_from_store(num_items: usize, item_size: usize) -> Result<StoredParams, Error> {
+    let v: Value = serde_json::from_str(PARAMS_STORE).unwrap();
+    let (num_items_log2, store_item_size, target) = v
         .as_array()
         .unwrap()
         .iter()
-        .map(|x| x.as_object().unwrap())
-        .filter(|x| x.get("target_num").unwrap().as_u64().unwrap() == (nearest_target_num as u64))
-        .filter(|x| x.get("item_size").unwrap().as_u64().unwrap() == (nearest_item_size as u64))
-        .map(|x| x.get("params").unwrap())
-        .next()
-        .unwrap();
-    params_from_json_obj(target)
+        .map(|x| {
+            (
+                x["target_num"].as_u64().unwrap() as usize,
+                x["item_size"].as_u64().unwrap() as usize,
+                &x["params"],
+            )
+        })
+        .filter(|&(t, size, _)| {
+            t < usize::BITS as usize && (1 << t) >= num_items && size >= item_size
+        })
+        .min_by_key(|&(t, size, _)| ((1u128 << t) * size as u128, t))
+        .ok_or(Error::NoParamsFit(num_items, item_size))?;
+
+    let params = params_from_json_obj(target);
+    Ok(StoredParams {
+        params_json: target.to_string(),
+        num_items: 1 << num_items_log2,
+        item_size: store_item_size,
+        setup_bytes: params.setup_bytes(),
+        query_bytes: params.query_bytes(),
+        response_bytes: params.response_bytes(),
+        params,
+    })
 }
 
 pub fn read_arbitrary_bits(data: &[u8], bit_offs: usize, num_bits: usize) -> u64 {
@@ -397,6 +456,65 @@ mod test {
         assert_eq!(b, c);
     }
 
+    #[test]
+    fn params_from_json_checks_ring() {
+        let scheme = serde_json::json!({
+            "n": 2, "nu_1": 9, "nu_2": 6, "p": 256, "q2_bits": 20,
+            "t_gsw": 8, "t_conv": 4, "t_exp_left": 8, "t_exp_right": 56,
+            "poly_len": 1024, "moduli": [268460033, 268238849],
+        });
+        let params = try_params_from_json_obj(&scheme).unwrap();
+        assert_eq!(params.poly_len, 1024);
+        assert_eq!(params.poly_len_log2, 10);
+        assert_eq!(&params.moduli[..2], &[268460033, 268238849]);
+        assert_eq!(params.db_item_size, 2 * 2 * 1024);
+        assert_eq!(params.q1, 4 * 256);
+
+        let mut scheme_q1 = scheme.clone();
+        scheme_q1["q1"] = serde_json::json!(768);
+        let params = try_params_from_json_obj(&scheme_q1).unwrap();
+        assert_eq!(params.q1, 768);
+
+        let mut scheme_4096 = scheme.clone();
+        scheme_4096.as_object_mut().unwrap().remove("moduli");
+        scheme_4096["poly_len"] = serde_json::json!(4096);
+        let params = try_params_from_json_obj(&scheme_4096).unwrap();
+        assert_eq!(params.poly_len, 4096);
+        assert_eq!(&params.moduli[..2], &DEFAULT_MODULI);
+
+        for (field, value) in [
+            ("poly_len", serde_json::json!(512)),
+            ("poly_len", serde_json::json!(3000)),
+            // 19 bits of q2 is 520193, which is not 1 mod 8192
+            ("q2_bits", serde_json::json!(19)),
+            ("q2_bits", serde_json::json!(31)),
+            ("moduli", serde_json::json!([268460033])),
+            ("moduli", serde_json::json!([268460033, 268460033])),
+            // 268460033 * 3, and a prime that is not 1 mod 2048
+            ("moduli", serde_json::json!([805380099, 268238849])),
+            ("moduli", serde_json::json!([268460033, 268435399])),
+            ("moduli", serde_json::json!([1073750017, 268238849])),
+            ("moduli", serde_json::json!("268460033")),
+            ("moduli", serde_json::json!([1191937, 1196033, 1253377])),
+            // q1 must be a multiple of p, above 2p and below 2^32
+            ("q1", serde_json::json!(1000)),
+            ("q1", serde_json::json!(512)),
+            ("q1", serde_json::json!(1u64 << 32)),
+            ("q1", serde_json::json!("1024")),
+        ] {
+            let mut bad_scheme = if field == "q2_bits" {
+                scheme_4096.clone()
+            } else {
+                scheme.clone()
+            };
+            bad_scheme[field] = value;
+            assert!(matches!(
+                try_params_from_json_obj(&bad_scheme),
+                Err(Error::InvalidParams(_))
+            ));
+        }
+    }
+
     #[test]
     fn test_decompose_calc_correct() {
         let lengths = [5, 4, 3];
@@ -426,4 +544,44 @@ mod test {
             bit_offs += num_bits;
         }
     }
+
+    #[test]
+    fn params_store_lookup_is_correct() {
+        let stored = get_params_from_store(1 << 14, 32768).unwrap();
+        assert_eq!((stored.num_items, stored.item_size), (1 << 14, 32768));
+        assert_eq!(stored.setup_bytes, stored.params.setup_bytes());
+        assert_eq!(stored.query_bytes, stored.params.query_bytes());
+        assert_eq!(stored.response_bytes, stored.params.response_bytes());
+        assert_eq!(params_from_json(&stored.params_json), stored.params);
+
+        // the nearest set that fits
+        let stored = get_params_from_store(1000, 300).unwrap();
+        assert_eq!((stored.num_items, stored.item_size), (1 << 10, 512));
+        let stored = get_params_from_store(0, 0).unwrap();
+        assert_eq!((stored.num_items, stored.item_size), (1 << 10, 256));
+
+        assert_eq!(
+            get_params_from_store(1 << 30, 1).err(),
+            Some(Error::NoParamsFit(1 << 30, 1))
+        );
+        assert_eq!(
+            get_params_from_store(1, 1 << 30).err(),
+            Some(Error::NoParamsFit(1, 1 << 30))
+        );
+    }
+
+    #[test]
+    fn params_store_entries_fit() {
+        let v: Value = serde_json::from_str(PARAMS_STORE).unwrap();
+        for entry in v.as_array().unwrap() {
+            let params = params_from_json_obj(&entry["params"]);
+            let item_size = entry["item_size"].as_u64().unwrap() as usize;
+            assert_eq!(
+                params.num_items(),
+                1 << entry["target_num"].as_u64().unwrap()
+            );
+            assert_eq!(params.db_item_size, item_size);
+            assert!(params.item_size() >= item_size);
+        }
+    }
 }
@@ -0,0 +1,179 @@
+//! The binary framing that Spiral clients and servers exchange setup data,
+//! queries and responses in.
+//!
+//! A frame is:
+//! - 4 bytes: `WIRE_MAGIC`
+//! - 1 byte: `WIRE_VERSION`
+//! - 1 byte: the `FrameKind`
+//! - 2 bytes: reserved, zero
+//! - 8 bytes: number of chunks (u64 LE)
+//! - for each chunk:
+//!   - 8 bytes: chunk length (u64 LE)
+//!   - (chunk data)
+//!
+//! Requests in this framing are sent with the content type
+//! `WIRE_CONTENT_TYPE`; responses are framed only for requests that accept it.
+
+use std::fmt::Display;
+
+pub const WIRE_CONTENT_TYPE: &str = "application/vnd.spiral-wire";
+pub const WIRE_MAGIC: [u8; 4] = *b"SPRW";
+pub const WIRE_VERSION: u8 = 1;
+
+const HEADER_BYTES: usize = 16;
+const LEN_BYTES: usize = 8;
+
+/// What a frame holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// One chunk: serialized public parameters.
+    Setup = 1,
+    /// One chunk per query: a session UUID followed by the serialized query.
+    Queries = 2,
+    /// One chunk per query: the serialized response.
+    Responses = 3,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedKind(u8),
+    TrailingBytes(usize),
+}
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireError::Truncated => write!(f, "frame is truncated"),
+            WireError::BadMagic => write!(f, "not a spiral frame"),
+            WireError::UnsupportedVersion(v) => write!(f, "unsupported frame version {}", v),
+            WireError::UnexpectedKind(k) => write!(f, "unexpected frame kind {}", k),
+            WireError::TrailingBytes(n) => write!(f, "{} bytes after frame", n),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+pub fn encode_frame<T: AsRef<[u8]>>(kind: FrameKind, chunks: &[T]) -> Vec<u8> {
+    let body_len: usize = chunks.iter().map(|c| LEN_BYTES + c.as_ref().len()).sum();
+    let mut out = Vec::with_capacity(HEADER_BYTES + body_len);
+    out.extend(WIRE_MAGIC);
+    out.push(WIRE_VERSION);
+    out.push(kind as u8);
+    out.extend([0u8; 2]);
+    out.extend((chunks.len() as u64).to_le_bytes());
+    for chunk in chunks {
+        out.extend((chunk.as_ref().len() as u64).to_le_bytes());
+        out.extend(chunk.as_ref());
+    }
+    out
+}
+
+fn read_u64(data: &[u8], offset: &mut usize) -> Result<u64, WireError> {
+    let bytes = data
+        .get(*offset..*offset + LEN_BYTES)
+        .ok_or(WireError::Truncated)?;
+    *offset += LEN_BYTES;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decodes a frame of the `expected` kind, returning its chunks.
+pub fn decode_frame(data: &[u8], expected: FrameKind) -> Result<Vec<&[u8]>, WireError> {
+    if data.len() < HEADER_BYTES {
+        return Err(WireError::Truncated);
+    }
+    if data[0..4] != WIRE_MAGIC {
+        return Err(WireError::BadMagic);
+    }
+    if data[4] != WIRE_VERSION {
+        return Err(WireError::UnsupportedVersion(data[4]));
+    }
+    if data[5] != expected as u8 {
+        return Err(WireError::UnexpectedKind(data[5]));
+    }
+
+    let mut offset = 8;
+    let num_chunks = read_u64(data, &mut offset)?;
+    // every chunk takes at least its length
+    if num_chunks > ((data.len() - offset) / LEN_BYTES) as u64 {
+        return Err(WireError::Truncated);
+    }
+    let mut chunks = Vec::with_capacity(num_chunks as usize);
+    for _ in 0..num_chunks {
+        let chunk_len = read_u64(data, &mut offset)?;
+        if chunk_len > (data.len() - offset) as u64 {
+            return Err(WireError::Truncated);
+        }
+        let end = offset + chunk_len as usize;
+        chunks.push(&data[offset..end]);
+        offset = end;
+    }
+    if offset != data.len() {
+        return Err(WireError::TrailingBytes(data.len() - offset));
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frame_roundtrip_is_correct() {
+        let chunks = vec![b"abc".to_vec(), Vec::new(), vec![7u8; 1000]];
+        let frame = encode_frame(FrameKind::Queries, &chunks);
+        let decoded = decode_frame(&frame, FrameKind::Queries).unwrap();
+        assert_eq!(decoded, chunks);
+
+        let empty = encode_frame::<Vec<u8>>(FrameKind::Responses, &[]);
+        assert!(decode_frame(&empty, FrameKind::Responses)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn bad_frames_are_rejected() {
+        let frame = encode_frame(FrameKind::Setup, &[b"public parameters"]);
+        assert_eq!(
+            decode_frame(&frame, FrameKind::Queries),
+            Err(WireError::UnexpectedKind(FrameKind::Setup as u8))
+        );
+        assert_eq!(
+            decode_frame(&frame[..frame.len() - 1], FrameKind::Setup),
+            Err(WireError::Truncated)
+        );
+        assert_eq!(
+            decode_frame(b"[\"AAAA\"]", FrameKind::Setup),
+            Err(WireError::Truncated)
+        );
+
+        let mut bad = frame.clone();
+        bad[0] = b'X';
+        assert_eq!(
+            decode_frame(&bad, FrameKind::Setup),
+            Err(WireError::BadMagic)
+        );
+        let mut bad = frame.clone();
+        bad[4] = WIRE_VERSION + 1;
+        assert_eq!(
+            decode_frame(&bad, FrameKind::Setup),
+            Err(WireError::UnsupportedVersion(WIRE_VERSION + 1))
+        );
+        let mut bad = frame.clone();
+        bad.push(0);
+        assert_eq!(
+            decode_frame(&bad, FrameKind::Setup),
+            Err(WireError::TrailingBytes(1))
+        );
+        let mut bad = frame;
+        bad[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            decode_frame(&bad, FrameKind::Setup),
+            Err(WireError::Truncated)
+        );
+    }
+}
@@ -1,22 +1,17 @@
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 
 use spiral_rs::client::*;
 use spiral_rs::key_value::*;
-use spiral_rs::params::Params;
 use spiral_rs::util::*;
 
 use std::convert::TryInto;
+use std::sync::Arc;
+use zeroize::Zeroize;
 
-pub struct ApiClientObj<'a> {
-    pub params: &'a Params,
-    pub c: Client<'a>,
-}
-
-// Container class for a static lifetime ApiClientObj
-// Avoids a lifetime in the return signature of bound Rust functions
 #[pyclass]
 pub struct ApiClient {
-    client: &'static mut ApiClientObj<'static>,
+    client: OwnedClient,
 }
 
 #[pyfunction]
@@ -26,42 +21,42 @@ pub fn initialize_client(json_params: Option<String>) -> ApiClient {
         cfg = json_params.unwrap();
     }
 
-    let params = Box::leak(Box::new(params_from_json(&cfg)));
-    let client = Box::leak(Box::new(ApiClientObj {
-        params,
-        c: Client::init(params),
-    }));
-
-    ApiClient { client }
+    let params = Arc::new(params_from_json(&cfg));
+    ApiClient {
+        client: OwnedClient::init(params),
+    }
 }
 
 #[pyfunction]
 pub fn generate_keys(
     c: &mut ApiClient,
-    seed: Vec<u8>,
+    mut seed: Vec<u8>,
     generate_pub_params: bool,
 ) -> Option<Vec<u8>> {
-    let seed_val = (*seed).try_into().unwrap();
-    Some(
-        c.client
-            .c
-            .generate_keys_optional(seed_val, generate_pub_params)?,
-    )
+    let mut seed_val: Seed = (*seed).try_into().unwrap();
+    seed.zeroize();
+    let result = c
+        .client
+        .generate_keys_optional(seed_val, generate_pub_params);
+    seed_val.zeroize();
+    result
 }
 
 #[pyfunction]
 pub fn generate_query(c: &mut ApiClient, id: &str, idx_target: usize) -> Vec<u8> {
-    c.client.c.generate_full_query(id, idx_target)
+    c.client.generate_full_query(id, idx_target)
 }
 
 #[pyfunction]
-pub fn decode_response(c: &mut ApiClient, data: Vec<u8>) -> Vec<u8> {
-    c.client.c.decode_response(&*data)
+pub fn decode_response(c: &mut ApiClient, data: Vec<u8>) -> PyResult<Vec<u8>> {
+    c.client
+        .try_decode_response(&*data)
+        .map_err(|e| PyValueError::new_err(e.to_string()))
 }
 
 #[pyfunction]
 pub fn get_row(c: &mut ApiClient, key: &str) -> u32 {
-    row_from_key(c.client.params, key) as u32
+    row_from_key(c.client.params(), key) as u32
 }
 
 #[pyfunction]